@@ -0,0 +1,390 @@
+use std::collections::VecDeque;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{api::grid_api::GridState, types::HexTile};
+
+// how many tile changes we remember for undo/revert
+pub const ACTION_LOG_LEN: usize = 10_000;
+
+// who (or what) changed a tile
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub enum Actor {
+    Player(String),
+    Admin,
+    Slime,
+    // a mine producing gold
+    Mine,
+    // a player's script, running on their behalf
+    Script(String),
+    // a turret shooting slime
+    Turret,
+}
+
+impl Actor {
+    // changes the simulation makes by itself; replays re-run those instead of
+    // recording them
+    pub fn is_simulation(&self) -> bool {
+        matches!(
+            self,
+            Self::Slime | Self::Mine | Self::Script(_) | Self::Turret
+        )
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct TileAction {
+    pub id: u64,
+    pub tick: u64,
+    pub actor: Actor,
+    pub col: u32,
+    pub row: u32,
+    pub before: HexTile,
+    pub after: HexTile,
+    // undone by an admin (and not redone since)
+    pub undone: bool,
+}
+
+// most recent player and admin edits, oldest first. whatever the simulation
+// changes isn't in here (it's in the journal though)
+pub struct ActionLog {
+    actions: VecDeque<TileAction>,
+    capacity: usize,
+    next_id: u64,
+    // ids of undone actions, most recently undone last
+    redo: Vec<u64>,
+}
+
+impl ActionLog {
+    pub fn new(capacity: usize) -> Self {
+        ActionLog {
+            actions: VecDeque::with_capacity(capacity.min(ACTION_LOG_LEN)),
+            capacity,
+            next_id: 0,
+            redo: Vec::new(),
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        tick: u64,
+        actor: Actor,
+        col: u32,
+        row: u32,
+        before: HexTile,
+        after: HexTile,
+    ) {
+        // a fresh edit makes the player's undone ones impossible to redo, and
+        // anyone's on the same tile, those would overwrite it
+        if let Actor::Player(_) = actor {
+            let actions = &self.actions;
+
+            self.redo.retain(|id| {
+                Self::find(actions, *id)
+                    .is_some_and(|a| a.actor != actor && (a.col, a.row) != (col, row))
+            });
+        }
+
+        if self.actions.len() == self.capacity {
+            self.actions.pop_front();
+        }
+
+        self.next_id += 1;
+
+        self.actions.push_back(TileAction {
+            id: self.next_id,
+            tick,
+            actor,
+            col,
+            row,
+            before,
+            after,
+            undone: false,
+        });
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &TileAction> {
+        self.actions.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    // false once actions from after `tick` may have been dropped
+    pub fn reaches_back_to(&self, tick: u64) -> bool {
+        self.actions.len() < self.capacity || self.actions.front().is_none_or(|a| a.tick <= tick)
+    }
+
+    // undone actions on this tile can't be redone anymore
+    fn forget_redo(&mut self, col: u32, row: u32) {
+        let actions = &self.actions;
+
+        self.redo
+            .retain(|id| Self::find(actions, *id).is_some_and(|a| (a.col, a.row) != (col, row)));
+    }
+
+    fn find(actions: &VecDeque<TileAction>, id: u64) -> Option<&TileAction> {
+        // ids are increasing, so the position is known up front
+        let first = actions.front()?.id;
+
+        actions.get(id.checked_sub(first)? as usize)
+    }
+
+    fn find_mut(&mut self, id: u64) -> Option<&mut TileAction> {
+        let first = self.actions.front()?.id;
+
+        self.actions.get_mut(id.checked_sub(first)? as usize)
+    }
+}
+
+impl Default for ActionLog {
+    fn default() -> Self {
+        ActionLog::new(ACTION_LOG_LEN)
+    }
+}
+
+impl GridState {
+    // puts the last `count` tiles `player` changed back the way they were,
+    // returning the indices of every tile that changed
+    pub fn undo_actions(&mut self, player: &str, count: usize) -> Vec<usize> {
+        let actor = Actor::Player(player.to_string());
+
+        let undo = self
+            .actions
+            .iter()
+            .rev()
+            .filter(|a| a.actor == actor && !a.undone)
+            .take(count)
+            .map(|a| (a.id, a.col, a.row, a.before.clone()))
+            .collect::<Vec<_>>();
+
+        let mut changed = Vec::new();
+
+        for (id, col, row, before) in undo {
+            if let Some(action) = self.actions.find_mut(id) {
+                action.undone = true;
+            }
+            self.actions.redo.push(id);
+
+            self.edit_tile(col, row, before, Actor::Admin);
+            changed.push(self.get_index(col, row));
+        }
+
+        changed
+    }
+
+    // re-applies the last `count` actions of `player` that were undone
+    pub fn redo_actions(&mut self, player: &str, count: usize) -> Vec<usize> {
+        let actor = Actor::Player(player.to_string());
+        let mut changed = Vec::new();
+
+        for _ in 0..count {
+            let Some(position) = self.actions.redo.iter().rposition(|id| {
+                ActionLog::find(&self.actions.actions, *id).is_some_and(|a| a.actor == actor)
+            }) else {
+                break;
+            };
+
+            let id = self.actions.redo.remove(position);
+
+            let Some(action) = self.actions.find_mut(id) else {
+                continue;
+            };
+
+            action.undone = false;
+            let (col, row, after) = (action.col, action.row, action.after.clone());
+
+            self.edit_tile(col, row, after, Actor::Admin);
+            changed.push(self.get_index(col, row));
+        }
+
+        changed
+    }
+
+    // undoes every player edit inside the (inclusive) region made after
+    // `tick`, so those tiles look like they did back then. what the
+    // simulation changed isn't logged and stays as it is
+    pub fn revert_region(
+        &mut self,
+        (min_col, min_row): (u32, u32),
+        (max_col, max_row): (u32, u32),
+        tick: u64,
+    ) -> Vec<usize> {
+        if !self.actions.reaches_back_to(tick) {
+            warn!("action log doesn't reach back to tick {tick}, reverting what's left");
+        }
+
+        let revert = self
+            .actions
+            .iter()
+            .rev()
+            .take_while(|a| a.tick > tick)
+            // earlier undos/reverts included, those would only bring
+            // back what they removed
+            .filter(|a| {
+                !a.undone
+                    && a.actor != Actor::Admin
+                    && (min_col..=max_col).contains(&a.col)
+                    && (min_row..=max_row).contains(&a.row)
+            })
+            .map(|a| (a.id, a.col, a.row, a.before.clone()))
+            .collect::<Vec<_>>();
+
+        let mut changed = Vec::new();
+
+        // newest first, so each tile ends up with the oldest `before`
+        for (id, col, row, before) in revert {
+            if let Some(action) = self.actions.find_mut(id) {
+                action.undone = true;
+            }
+            self.actions.forget_redo(col, row);
+
+            self.edit_tile(col, row, before, Actor::Admin);
+            changed.push(self.get_index(col, row));
+        }
+
+        changed.sort_unstable();
+        changed.dedup();
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api::simulation, types::tests::turret};
+
+    use super::*;
+
+    fn player(name: &str) -> Actor {
+        Actor::Player(name.to_string())
+    }
+
+    #[test]
+    fn it_logs_edits_with_actor_and_tick() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+        grid.tick = 3;
+
+        grid.edit_tile(1, 1, HexTile::Slime, player("ada"));
+
+        let action = grid.actions.iter().next().unwrap();
+
+        assert_eq!(action.tick, 3);
+        assert_eq!(action.actor, player("ada"));
+        assert_eq!((action.col, action.row), (1, 1));
+        assert_eq!(action.before, HexTile::Wild);
+        assert_eq!(action.after, HexTile::Slime);
+    }
+
+    #[test]
+    fn it_forgets_actions_past_capacity() {
+        let mut log = ActionLog::new(3);
+
+        for col in 0..5 {
+            log.push(0, Actor::Admin, col, 0, HexTile::Wild, HexTile::Slime);
+        }
+
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.iter().map(|a| a.col).collect::<Vec<_>>(), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn it_undoes_and_redoes_one_players_actions() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+
+        grid.edit_tile(1, 1, HexTile::Slime, player("ada"));
+        grid.edit_tile(2, 2, HexTile::Slime, player("bob"));
+        grid.edit_tile(3, 3, HexTile::Slime, player("ada"));
+        grid.edit_tile(4, 4, HexTile::Slime, player("ada"));
+
+        let changed = grid.undo_actions("ada", 2);
+
+        assert_eq!(changed, vec![grid.get_index(4, 4), grid.get_index(3, 3)]);
+        assert_eq!(grid.get_tile(4, 4), Some(&HexTile::Wild));
+        assert_eq!(grid.get_tile(3, 3), Some(&HexTile::Wild));
+        assert_eq!(grid.get_tile(1, 1), Some(&HexTile::Slime));
+        assert_eq!(grid.get_tile(2, 2), Some(&HexTile::Slime));
+
+        // undone actions aren't undone twice
+        grid.undo_actions("ada", 1);
+        assert_eq!(grid.get_tile(1, 1), Some(&HexTile::Wild));
+
+        let changed = grid.redo_actions("ada", 2);
+
+        assert_eq!(changed, vec![grid.get_index(1, 1), grid.get_index(3, 3)]);
+        assert_eq!(grid.get_tile(1, 1), Some(&HexTile::Slime));
+        assert_eq!(grid.get_tile(3, 3), Some(&HexTile::Slime));
+        assert_eq!(grid.get_tile(4, 4), Some(&HexTile::Wild));
+
+        // a new edit throws away what's left to redo
+        grid.edit_tile(5, 5, HexTile::Slime, player("ada"));
+        assert!(grid.redo_actions("ada", 1).is_empty());
+    }
+
+    #[test]
+    fn it_forgets_redos_that_would_overwrite_later_changes() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+
+        // someone else building over an undone edit
+        grid.edit_tile(1, 1, HexTile::Slime, player("ada"));
+        grid.undo_actions("ada", 1);
+        grid.edit_tile(1, 1, turret(), player("bob"));
+
+        assert!(grid.redo_actions("ada", 1).is_empty());
+        assert_eq!(grid.get_tile(1, 1), Some(&turret()));
+
+        // or a revert taking the tile back past it
+        grid.tick = 1;
+        grid.edit_tile(2, 2, turret(), player("bob"));
+        grid.tick = 2;
+        grid.edit_tile(2, 2, HexTile::Slime, player("ada"));
+        grid.undo_actions("ada", 1);
+        grid.revert_region((2, 2), (2, 2), 0);
+
+        assert!(grid.redo_actions("ada", 1).is_empty());
+        assert_eq!(grid.get_tile(2, 2), Some(&HexTile::Wild));
+    }
+
+    #[test]
+    fn it_reverts_a_region_to_an_earlier_tick() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+
+        grid.edit_tile(1, 1, HexTile::Slime, player("ada"));
+        simulation::tick(&mut grid);
+        simulation::tick(&mut grid);
+
+        // the griefing, then a few ticks of the slime spreading
+        grid.edit_tile(5, 5, HexTile::Slime, player("bob"));
+        grid.edit_tile(1, 1, HexTile::Wild, player("bob"));
+        simulation::tick(&mut grid);
+        simulation::tick(&mut grid);
+
+        let spread = grid.get_tile(5, 6).cloned();
+        assert!(matches!(spread, Some(HexTile::Mine(_))));
+
+        let changed = grid.revert_region((0, 0), (9, 9), 1);
+
+        assert_eq!(changed, vec![grid.get_index(1, 1), grid.get_index(5, 5)]);
+        assert_eq!(grid.get_tile(1, 1), Some(&HexTile::Slime));
+        assert_eq!(grid.get_tile(5, 5), Some(&HexTile::Wild));
+
+        // what the simulation did meanwhile isn't an edit, so it stays
+        assert_eq!(grid.get_tile(5, 6).cloned(), spread);
+
+        // nothing left to revert the second time around
+        assert!(grid.revert_region((0, 0), (9, 9), 1).is_empty());
+
+        // and only inside the region
+        grid.edit_tile(8, 8, HexTile::Slime, player("bob"));
+        grid.edit_tile(3, 3, HexTile::Slime, player("bob"));
+        grid.revert_region((0, 0), (4, 4), 1);
+
+        assert_eq!(grid.get_tile(3, 3), Some(&HexTile::Wild));
+        assert_eq!(grid.get_tile(8, 8), Some(&HexTile::Slime));
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+// chunks are CHUNK_SIZE x CHUNK_SIZE tiles in offset (col, row) coordinates
+pub const CHUNK_SIZE: u32 = 16;
+const CHUNK_AREA: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+// sparse 2d storage for a map layer. a chunk is only allocated once one of its
+// tiles differs from the default value, and it's dropped again when the whole
+// chunk is back to default, so mostly untouched maps stay cheap no matter how
+// big they are
+pub struct ChunkMap<T> {
+    default: T,
+    chunks: HashMap<(u32, u32), Box<[T]>>,
+}
+
+impl<T: Clone + PartialEq> ChunkMap<T> {
+    pub fn new(default: T) -> Self {
+        ChunkMap {
+            default,
+            chunks: HashMap::new(),
+        }
+    }
+
+    pub fn default_value(&self) -> &T {
+        &self.default
+    }
+
+    // (chunk_col, chunk_row) of the chunk containing the given tile
+    pub fn chunk_key(x: u32, y: u32) -> (u32, u32) {
+        (x / CHUNK_SIZE, y / CHUNK_SIZE)
+    }
+
+    fn offset(x: u32, y: u32) -> usize {
+        ((y % CHUNK_SIZE) * CHUNK_SIZE + (x % CHUNK_SIZE)) as usize
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> &T {
+        match self.chunks.get(&Self::chunk_key(x, y)) {
+            Some(chunk) => &chunk[Self::offset(x, y)],
+            None => &self.default,
+        }
+    }
+
+    // allocates the chunk if it isn't yet; prefer `set` when writing a value
+    // that might be the default
+    pub fn get_mut(&mut self, x: u32, y: u32) -> &mut T {
+        let default = &self.default;
+
+        let chunk = self
+            .chunks
+            .entry(Self::chunk_key(x, y))
+            .or_insert_with(|| vec![default.clone(); CHUNK_AREA].into_boxed_slice());
+
+        &mut chunk[Self::offset(x, y)]
+    }
+
+    // stores a value, returning the one it replaced
+    pub fn set(&mut self, x: u32, y: u32, value: T) -> T {
+        let key = Self::chunk_key(x, y);
+
+        if value == self.default {
+            let Some(chunk) = self.chunks.get_mut(&key) else {
+                // unallocated chunks are already all default
+                return value;
+            };
+
+            let old = std::mem::replace(&mut chunk[Self::offset(x, y)], value);
+
+            if chunk.iter().all(|t| *t == self.default) {
+                self.chunks.remove(&key);
+            }
+
+            return old;
+        }
+
+        std::mem::replace(self.get_mut(x, y), value)
+    }
+
+    // raw contents of a chunk in row-major order, None if it's all default
+    pub fn chunk(&self, chunk_col: u32, chunk_row: u32) -> Option<&[T]> {
+        self.chunks.get(&(chunk_col, chunk_row)).map(|c| &c[..])
+    }
+
+    // copy of a chunk's contents in row-major order, filled in with the
+    // default value if it was never allocated
+    pub fn chunk_values(&self, chunk_col: u32, chunk_row: u32) -> Vec<T> {
+        match self.chunk(chunk_col, chunk_row) {
+            Some(chunk) => chunk.to_vec(),
+            None => vec![self.default.clone(); CHUNK_AREA],
+        }
+    }
+
+    // every stored value that differs from the default, as (x, y, value)
+    pub fn iter_non_default(&self) -> impl Iterator<Item = (u32, u32, &T)> {
+        self.chunks.iter().flat_map(move |(&(cx, cy), chunk)| {
+            chunk
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| **t != self.default)
+                .map(move |(i, t)| {
+                    let x = cx * CHUNK_SIZE + i as u32 % CHUNK_SIZE;
+                    let y = cy * CHUNK_SIZE + i as u32 / CHUNK_SIZE;
+
+                    (x, y, t)
+                })
+        })
+    }
+
+    pub fn allocated_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_returns_default_without_allocating() {
+        let map = ChunkMap::new(0u32);
+
+        assert_eq!(*map.get(3, 7), 0);
+        assert_eq!(*map.get(1_000_000, 1_000_000), 0);
+        assert_eq!(map.allocated_chunks(), 0);
+    }
+
+    #[test]
+    fn it_allocates_one_chunk_per_region() {
+        let mut map = ChunkMap::new(0u32);
+
+        map.set(0, 0, 1);
+        map.set(CHUNK_SIZE - 1, CHUNK_SIZE - 1, 2);
+
+        assert_eq!(map.allocated_chunks(), 1);
+
+        map.set(CHUNK_SIZE, 0, 3);
+
+        assert_eq!(map.allocated_chunks(), 2);
+        assert_eq!(*map.get(0, 0), 1);
+        assert_eq!(*map.get(CHUNK_SIZE - 1, CHUNK_SIZE - 1), 2);
+        assert_eq!(*map.get(CHUNK_SIZE, 0), 3);
+        assert_eq!(*map.get(1, 0), 0);
+    }
+
+    #[test]
+    fn it_returns_replaced_value() {
+        let mut map = ChunkMap::new(0u32);
+
+        assert_eq!(map.set(4, 4, 9), 0);
+        assert_eq!(map.set(4, 4, 5), 9);
+    }
+
+    #[test]
+    fn it_drops_chunks_that_are_back_to_default() {
+        let mut map = ChunkMap::new(0u32);
+
+        map.set(2, 2, 1);
+        map.set(3, 2, 1);
+
+        map.set(2, 2, 0);
+        assert_eq!(map.allocated_chunks(), 1);
+
+        map.set(3, 2, 0);
+        assert_eq!(map.allocated_chunks(), 0);
+        assert!(map.chunk(0, 0).is_none());
+    }
+
+    #[test]
+    fn it_iterates_only_non_default_values() {
+        let mut map = ChunkMap::new(0u32);
+
+        map.set(1, 2, 5);
+        map.set(CHUNK_SIZE * 3 + 4, CHUNK_SIZE + 7, 6);
+
+        let mut values = map.iter_non_default().collect::<Vec<_>>();
+        values.sort();
+
+        assert_eq!(
+            values,
+            vec![(1, 2, &5), (CHUNK_SIZE * 3 + 4, CHUNK_SIZE + 7, &6)]
+        );
+    }
+
+    #[test]
+    fn it_exposes_chunk_contents_row_major() {
+        let mut map = ChunkMap::new(0u32);
+
+        map.set(CHUNK_SIZE + 2, 1, 7);
+
+        let chunk = map.chunk(1, 0).unwrap();
+
+        assert_eq!(chunk.len(), CHUNK_AREA);
+        assert_eq!(chunk[CHUNK_SIZE as usize + 2], 7);
+        assert_eq!(map.chunk_values(1, 0), chunk.to_vec());
+
+        // unallocated chunks still come back full size
+        assert_eq!(map.chunk_values(5, 5), vec![0; CHUNK_AREA]);
+    }
+}
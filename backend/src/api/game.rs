@@ -1,13 +1,1150 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    ops::{Deref, DerefMut},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        action_log::Actor,
+        grid_api::GridState,
+        profiler::ScriptCost,
+        script_host::{LogLevel, RunError},
+        scripts::ScriptRole,
+        tile_store::TileStore,
+    },
+    types::{HexTile, MineData, Terrain, TurretData},
+};
+
+// how far gold can be shipped and turrets can shoot, counted in steps between
+// neighbouring tiles
+pub const LOGISTICS_RANGE: u32 = 6;
+pub const TURRET_RANGE: u32 = 3;
+
+// how many rows/cols around its own tile a conductor gets to see; a step
+// never moves more than one of each, so this covers both ranges above
+pub const VIEW_RANGE: u32 = 6;
+
+// intents one tile may queue per phase, anything past that is refused
+pub const MAX_INTENTS: usize = 16;
+
+// messages: how big one may be, how many one tile may send per tick and how
+// many it gets at most, anything past that gets turned down. within
+// VIEW_RANGE steps anything with a conductor can be messaged, further out
+// only tiles running a script of the same owner
+pub const MAX_MESSAGE_BYTES: usize = 128;
+pub const MAX_MESSAGES: u32 = 8;
+pub const MAX_INBOX: usize = 32;
+
+// upgrading a mine costs this much gold per level it already has, and makes
+// room for this much more
+pub const UPGRADE_COST: u32 = 5;
+pub const UPGRADE_CAPACITY: u32 = 10;
+
+// something a conductor wants done. conductors only get to look at the world,
+// these are resolved once every tile of the phase has run, see
+// GridState::resolve_intents
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Intent {
+    // > 0 offers that much gold to logistics, < 0 asks for it
+    SetTradeValue(i32),
+    // spends UPGRADE_COST * level of this mine's gold on the next level
+    Upgrade,
+    // gold from this mine to another one within LOGISTICS_RANGE, as much of
+    // `amount` as this one has and the other has room for
+    Transfer {
+        col: u32,
+        row: u32,
+        amount: u32,
+    },
+    // gold from another mine within LOGISTICS_RANGE to this one, as much of
+    // `amount` as the other one still offers
+    Request {
+        col: u32,
+        row: u32,
+        amount: u32,
+    },
+    // at slime within TURRET_RANGE, once per tick
+    Fire {
+        col: u32,
+        row: u32,
+    },
+    // a message to the mine/turret at (col, row), see MAX_MESSAGES
+    Send {
+        col: u32,
+        row: u32,
+        payload: Vec<u8>,
+    },
+}
+
+impl Intent {
+    // intents of a phase are carried out kind by kind in this order: trade
+    // values first so requests see the new offers, spending before shipping
+    fn rank(&self) -> u8 {
+        match self {
+            Self::SetTradeValue(_) => 0,
+            Self::Upgrade => 1,
+            Self::Transfer { .. } => 2,
+            Self::Request { .. } => 3,
+            Self::Fire { .. } => 4,
+            Self::Send { .. } => 5,
+        }
+    }
+
+    // the phase whose conductors may ask for this; messages go in any
+    fn phase(&self) -> Option<ScriptRole> {
+        match self {
+            Self::SetTradeValue(_) | Self::Upgrade => Some(ScriptRole::Mine),
+            Self::Transfer { .. } | Self::Request { .. } => Some(ScriptRole::Logistics),
+            Self::Fire { .. } => Some(ScriptRole::Defender),
+            Self::Send { .. } => None,
+        }
+    }
+}
+
+// why an intent wasn't carried out
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    // too far away, or off the map
+    OutOfRange = 0,
+    // not something this kind of tile can do, or to that kind of tile
+    WrongTile = 1,
+    // not enough gold left
+    NoGold = 2,
+    // the receiving mine is full
+    NoRoom = 3,
+    // the mine asked isn't offering anything (anymore)
+    NotOffered = 4,
+    // what it fired at isn't slime (anymore)
+    TargetGone = 5,
+    // the turret already fired this tick
+    AlreadyFired = 6,
+    // the tile already sent MAX_MESSAGES this tick
+    Quota = 7,
+    // the receiver already has MAX_INBOX messages waiting
+    InboxFull = 8,
+    // asked for during another phase, like a mine firing
+    OutOfPhase = 9,
+}
+
+// an intent that couldn't be carried out, handed back to the tile's
+// conductor on the next tick
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Rejection {
+    pub intent: Intent,
+    pub reason: RejectReason,
+}
+
+// a message waiting for a tile's conductor, from the tile at (col, row)
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub col: u32,
+    pub row: u32,
+    pub payload: Vec<u8>,
+}
+
+// what resolving a tick's intents leaves for the next one
+#[derive(Default)]
+pub(crate) struct NextTick {
+    pub rejected: BTreeMap<usize, Vec<Rejection>>,
+    pub inboxes: BTreeMap<usize, Vec<Message>>,
+    // messages each tile sent so far
+    sent: HashMap<usize, u32>,
+}
+
 // main api, everyone gets this
-trait GlobalApi {}
+pub trait GlobalApi {
+    // (col, row) of the tile this runs on
+    fn position(&self) -> (u32, u32);
+    fn tick(&self) -> u64;
+    // tiles within VIEW_RANGE as they were when the phase started, None
+    // further out or out of bounds
+    fn tile(&self, col: u32, row: u32) -> Option<&HexTile>;
+    fn terrain(&self, col: u32, row: u32) -> Option<&Terrain>;
+    // the closest visible tile within `range` steps that `matches`, ties going
+    // to the lowest (row, col)
+    fn nearest(&self, range: u32, matches: &dyn Fn(&HexTile) -> bool) -> Option<(u32, u32)>;
+    // what this tile wanted done last tick but didn't get, in the order the
+    // resolver got to it
+    fn rejected(&self) -> &[Rejection];
+    // messages sent to this tile last tick: by phase, then by sending tile,
+    // then in the order each one sent them
+    fn inbox(&self) -> &[Message];
+    // false if the payload is longer than MAX_MESSAGE_BYTES or too much is
+    // queued already; whether it arrives is only known next tick
+    fn message(&mut self, col: u32, row: u32, payload: &[u8]) -> bool;
+    // the tile's own state, kept between ticks; writes past the quotas fail
+    fn state(&self) -> &TileStore;
+    fn state_mut(&mut self) -> &mut TileStore;
+}
+
+// what every conductor is handed: a read-only view of the neighbourhood of
+// its own tile, a copy of its state that's written back once the phase is
+// over, and the intents it came up with so far
+pub struct TileContext<'a> {
+    grid: &'a GridState,
+    col: u32,
+    row: u32,
+    rejected: &'a [Rejection],
+    inbox: &'a [Message],
+    state: TileStore,
+    intents: Vec<Intent>,
+}
+
+impl<'a> TileContext<'a> {
+    fn new(grid: &'a GridState, index: usize, state: TileStore) -> Self {
+        let (col, row) = grid.get_coords(index);
+
+        TileContext {
+            grid,
+            col,
+            row,
+            rejected: grid.rejections.get(&index).map_or(&[], Vec::as_slice),
+            inbox: grid.inboxes.get(&index).map_or(&[], Vec::as_slice),
+            state,
+            intents: Vec::new(),
+        }
+    }
+
+    // false once MAX_INTENTS are queued
+    fn push(&mut self, intent: Intent) -> bool {
+        if self.intents.len() >= MAX_INTENTS {
+            return false;
+        }
+
+        self.intents.push(intent);
+        true
+    }
+
+    fn visible(&self, col: u32, row: u32) -> bool {
+        self.col.abs_diff(col) <= VIEW_RANGE && self.row.abs_diff(row) <= VIEW_RANGE
+    }
+}
+
+impl GlobalApi for TileContext<'_> {
+    fn position(&self) -> (u32, u32) {
+        (self.col, self.row)
+    }
+
+    fn tick(&self) -> u64 {
+        self.grid.tick
+    }
+
+    fn tile(&self, col: u32, row: u32) -> Option<&HexTile> {
+        self.grid
+            .get_tile(col, row)
+            .filter(|_| self.visible(col, row))
+    }
+
+    fn terrain(&self, col: u32, row: u32) -> Option<&Terrain> {
+        self.grid
+            .get_terrain(col, row)
+            .filter(|_| self.visible(col, row))
+    }
+
+    fn nearest(&self, range: u32, matches: &dyn Fn(&HexTile) -> bool) -> Option<(u32, u32)> {
+        self.grid
+            .steps_from(self.col, self.row, range.min(VIEW_RANGE))
+            .filter(|&(index, steps)| {
+                steps > 0 && {
+                    let (x, y) = self.grid.get_coords(index);
+                    matches(self.grid.tiles.get(x, y))
+                }
+            })
+            .min_by_key(|&(index, steps)| (steps, index))
+            .map(|(index, _)| self.grid.get_coords(index))
+    }
+
+    fn rejected(&self) -> &[Rejection] {
+        self.rejected
+    }
+
+    fn inbox(&self) -> &[Message] {
+        self.inbox
+    }
+
+    fn message(&mut self, col: u32, row: u32, payload: &[u8]) -> bool {
+        payload.len() <= MAX_MESSAGE_BYTES
+            && self.push(Intent::Send {
+                col,
+                row,
+                payload: payload.to_vec(),
+            })
+    }
+
+    fn state(&self) -> &TileStore {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut TileStore {
+        &mut self.state
+    }
+}
 
 // miner api
-trait MineConductor {
-    fn tick();
+pub struct MineContext<'a> {
+    tile: TileContext<'a>,
+    mine: &'a MineData,
+}
+
+impl MineContext<'_> {
+    pub fn mine(&self) -> &MineData {
+        self.mine
+    }
+
+    // gold the ground underneath adds every tick
+    pub fn yield_per_tick(&self) -> u32 {
+        self.tile
+            .terrain(self.tile.col, self.tile.row)
+            .map_or(0, Terrain::mine_yield)
+    }
+
+    pub fn set_trade_value(&mut self, value: i32) -> bool {
+        self.push(Intent::SetTradeValue(value))
+    }
+
+    pub fn upgrade(&mut self) -> bool {
+        self.push(Intent::Upgrade)
+    }
 }
 
 // logistics api
-trait LogisticsConductor {}
+pub struct LogisticsContext<'a> {
+    tile: TileContext<'a>,
+    mine: &'a MineData,
+}
+
+impl LogisticsContext<'_> {
+    pub fn mine(&self) -> &MineData {
+        self.mine
+    }
+
+    pub fn send(&mut self, col: u32, row: u32, amount: u32) -> bool {
+        self.push(Intent::Transfer { col, row, amount })
+    }
+
+    pub fn request(&mut self, col: u32, row: u32, amount: u32) -> bool {
+        self.push(Intent::Request { col, row, amount })
+    }
+}
 
 // defender api
-trait DefenderConductor {}
+pub struct DefenderContext<'a> {
+    tile: TileContext<'a>,
+    turret: &'a TurretData,
+}
+
+impl DefenderContext<'_> {
+    pub fn turret(&self) -> &TurretData {
+        self.turret
+    }
+
+    // one shot per tick, any further ones get rejected
+    pub fn fire_at(&mut self, col: u32, row: u32) -> bool {
+        self.push(Intent::Fire { col, row })
+    }
+}
+
+macro_rules! tile_context {
+    ($($context:ident),*) => {$(
+        impl<'a> Deref for $context<'a> {
+            type Target = TileContext<'a>;
+
+            fn deref(&self) -> &Self::Target {
+                &self.tile
+            }
+        }
+
+        impl DerefMut for $context<'_> {
+            fn deref_mut(&mut self) -> &mut Self::Target {
+                &mut self.tile
+            }
+        }
+    )*};
+}
+
+tile_context!(MineContext, LogisticsContext, DefenderContext);
+
+// what runs on a tile every tick, one per phase. tiles without a player
+// script get the ones in GridState::conductors. every tile of a phase may run
+// at the same time
+pub trait MineConductor: Send + Sync {
+    fn tick(&self, ctx: &mut MineContext);
+}
+
+pub trait LogisticsConductor: Send + Sync {
+    fn tick(&self, ctx: &mut LogisticsContext);
+}
+
+pub trait DefenderConductor: Send + Sync {
+    fn tick(&self, ctx: &mut DefenderContext);
+}
+
+// keeps all the gold it can hold and only offers what would overflow; mines
+// that can't produce anything ask for enough to fill up instead
+pub struct GreedyMine;
+
+impl MineConductor for GreedyMine {
+    fn tick(&self, ctx: &mut MineContext) {
+        let mine = ctx.mine();
+        let produced = ctx.yield_per_tick();
+
+        let value = if produced == 0 {
+            -(mine.capacity.saturating_sub(mine.count) as i32)
+        } else {
+            (mine.count + produced).saturating_sub(mine.capacity) as i32
+        };
+
+        if value != mine.trade_value {
+            ctx.set_trade_value(value);
+        }
+    }
+}
+
+// sends whatever its mine offers to the closest mine asking for gold
+pub struct NearestNeedLogistics;
+
+impl LogisticsConductor for NearestNeedLogistics {
+    fn tick(&self, ctx: &mut LogisticsContext) {
+        let offer = ctx.mine().trade_value.min(ctx.mine().count as i32);
+
+        if offer <= 0 {
+            return;
+        }
+
+        let needy = |tile: &HexTile| matches!(tile, HexTile::Mine(mine) if mine.trade_value < 0);
+
+        if let Some((col, row)) = ctx.nearest(LOGISTICS_RANGE, &needy)
+            && let Some(HexTile::Mine(target)) = ctx.tile(col, row)
+        {
+            let amount = offer.min(-target.trade_value) as u32;
+            ctx.send(col, row, amount);
+        }
+    }
+}
+
+// shoots the closest slime in range
+pub struct ClosestSlimeTurret;
+
+impl DefenderConductor for ClosestSlimeTurret {
+    fn tick(&self, ctx: &mut DefenderContext) {
+        if let Some((col, row)) = ctx.nearest(TURRET_RANGE, &|tile| *tile == HexTile::Slime) {
+            ctx.fire_at(col, row);
+        }
+    }
+}
+
+pub struct Conductors {
+    pub mine: Box<dyn MineConductor>,
+    pub logistics: Box<dyn LogisticsConductor>,
+    pub defender: Box<dyn DefenderConductor>,
+}
+
+impl Default for Conductors {
+    fn default() -> Self {
+        Conductors {
+            mine: Box::new(GreedyMine),
+            logistics: Box::new(NearestNeedLogistics),
+            defender: Box::new(ClosestSlimeTurret),
+        }
+    }
+}
+
+// what running one tile's conductor (built-in or script) came up with, to be
+// applied after the phase
+pub(crate) struct Outcome {
+    pub index: usize,
+    // who its changes are attributed to
+    pub actor: Actor,
+    // scripts only; kept even if it failed
+    pub logs: Vec<(LogLevel, String)>,
+    // scripts only, for the profiler
+    pub cost: Option<ScriptCost>,
+    // the tile's state afterwards and what it wants done
+    pub result: Result<(TileStore, Vec<Intent>), RunError>,
+}
+
+impl GridState {
+    // every tile reachable from (x, y) within `range` steps, with how many
+    // steps it took, closest first (the start included, at 0)
+    pub fn steps_from(&self, x: u32, y: u32, range: u32) -> impl Iterator<Item = (usize, u32)> {
+        let start = self.get_index(x, y);
+
+        let mut seen = vec![start];
+        let mut queue = VecDeque::from([(start, 0)]);
+
+        std::iter::from_fn(move || {
+            let (index, steps) = queue.pop_front()?;
+
+            if steps < range {
+                let (x, y) = self.get_coords(index);
+
+                for next in self.get_neighbors(x, y) {
+                    if !seen.contains(&next) {
+                        seen.push(next);
+                        queue.push_back((next, steps + 1));
+                    }
+                }
+            }
+
+            Some((index, steps))
+        })
+    }
+
+    fn within_steps(&self, from: usize, to: usize, range: u32) -> bool {
+        let (x, y) = self.get_coords(from);
+
+        self.steps_from(x, y, range).any(|(index, _)| index == to)
+    }
+
+    // runs the built-in conductor for `phase` on a tile, without changing
+    // anything yet
+    pub(crate) fn plan_conductor(&self, index: usize, phase: ScriptRole) -> Outcome {
+        let (x, y) = self.get_coords(index);
+        let tile = self.tiles.get(x, y);
+        let context = TileContext::new(self, index, tile.store().cloned().unwrap_or_default());
+
+        let (actor, tile) = match (phase, tile) {
+            (ScriptRole::Mine, HexTile::Mine(mine)) => {
+                let mut ctx = MineContext {
+                    tile: context,
+                    mine,
+                };
+                self.conductors.mine.tick(&mut ctx);
+
+                (Actor::Mine, ctx.tile)
+            }
+            (ScriptRole::Logistics, HexTile::Mine(mine)) => {
+                let mut ctx = LogisticsContext {
+                    tile: context,
+                    mine,
+                };
+                self.conductors.logistics.tick(&mut ctx);
+
+                (Actor::Mine, ctx.tile)
+            }
+            (ScriptRole::Defender, HexTile::Turret(turret)) => {
+                let mut ctx = DefenderContext {
+                    tile: context,
+                    turret,
+                };
+                self.conductors.defender.tick(&mut ctx);
+
+                (Actor::Turret, ctx.tile)
+            }
+            _ => (Actor::Mine, context),
+        };
+
+        Outcome {
+            index,
+            actor,
+            logs: Vec::new(),
+            cost: None,
+            result: Ok((tile.state, tile.intents)),
+        }
+    }
+
+    // carries out the intents of a whole phase, as (tile, who, intent): kind
+    // by kind (see Intent::rank), then in tile-index order, then in the order
+    // each tile queued them. whoever comes first wins a conflict, like two
+    // turrets firing at the same slime or two requests for the last of a
+    // mine's gold; the losers end up in `next`, along with the messages.
+    // intents that belong to another phase than `phase` are turned down,
+    // without one (migrations) anything goes. returns every tile that changed
+    pub(crate) fn resolve_intents(
+        &mut self,
+        phase: Option<ScriptRole>,
+        mut intents: Vec<(usize, Actor, Intent)>,
+        next: &mut NextTick,
+    ) -> Vec<usize> {
+        // stable, so each tile's own order is kept
+        intents.sort_by_key(|(index, _, intent)| (intent.rank(), *index));
+
+        let mut changed = Vec::new();
+        let mut fired = HashSet::new();
+
+        for (index, actor, intent) in intents {
+            let result = match (phase, intent.phase()) {
+                (Some(phase), Some(own)) if phase != own => Err(RejectReason::OutOfPhase),
+                _ => self.apply_intent(index, &intent, &actor, &mut fired, next),
+            };
+
+            match result {
+                Ok(tiles) => changed.extend(tiles),
+                Err(reason) => next
+                    .rejected
+                    .entry(index)
+                    .or_default()
+                    .push(Rejection { intent, reason }),
+            }
+        }
+
+        changed
+    }
+
+    fn apply_intent(
+        &mut self,
+        index: usize,
+        intent: &Intent,
+        actor: &Actor,
+        fired: &mut HashSet<usize>,
+        next: &mut NextTick,
+    ) -> Result<Vec<usize>, RejectReason> {
+        let (x, y) = self.get_coords(index);
+
+        match *intent {
+            Intent::SetTradeValue(value) => {
+                if !matches!(self.tiles.get(x, y), HexTile::Mine(_)) {
+                    return Err(RejectReason::WrongTile);
+                }
+
+                Ok(self.update_mine(index, actor.clone(), |mine| {
+                    mine.trade_value = value;
+                }))
+            }
+            Intent::Upgrade => {
+                let HexTile::Mine(mine) = self.tiles.get(x, y) else {
+                    return Err(RejectReason::WrongTile);
+                };
+
+                let cost = UPGRADE_COST * mine.level;
+
+                if mine.count < cost {
+                    return Err(RejectReason::NoGold);
+                }
+
+                Ok(self.update_mine(index, actor.clone(), |mine| {
+                    mine.count -= cost;
+                    mine.level += 1;
+                    mine.capacity += UPGRADE_CAPACITY;
+                }))
+            }
+            Intent::Transfer { col, row, amount } => {
+                let to = self.in_reach(index, col, row, LOGISTICS_RANGE)?;
+
+                self.transfer_gold(index, to, amount, actor)
+            }
+            Intent::Request { col, row, amount } => {
+                let from = self.in_reach(index, col, row, LOGISTICS_RANGE)?;
+
+                let offer = match self.tiles.get(col, row) {
+                    HexTile::Mine(mine) => mine.trade_value.clamp(0, mine.count as i32) as u32,
+                    _ => return Err(RejectReason::WrongTile),
+                };
+
+                if offer == 0 {
+                    return Err(RejectReason::NotOffered);
+                }
+
+                self.transfer_gold(from, index, amount.min(offer), actor)
+            }
+            Intent::Fire { col, row } => {
+                if !matches!(self.tiles.get(x, y), HexTile::Turret(_)) {
+                    return Err(RejectReason::WrongTile);
+                }
+
+                if fired.contains(&index) {
+                    return Err(RejectReason::AlreadyFired);
+                }
+
+                let target = self.in_reach(index, col, row, TURRET_RANGE)?;
+
+                if *self.tiles.get(col, row) != HexTile::Slime {
+                    return Err(RejectReason::TargetGone);
+                }
+
+                fired.insert(index);
+                self.replace_tile(target, HexTile::Wild, actor.clone());
+                self.turret_kills += 1;
+
+                Ok(vec![target])
+            }
+            Intent::Send {
+                col,
+                row,
+                ref payload,
+            } => {
+                let to = self.get_index(col, row);
+
+                let reachable = self.in_reach(index, col, row, VIEW_RANGE).is_ok()
+                    || matches!(
+                        (actor, self.assignments.get(&to)),
+                        (Actor::Script(owner), Some(assignment)) if assignment.owner == *owner
+                    );
+
+                if !self.in_bounds(col, row) || !reachable {
+                    return Err(RejectReason::OutOfRange);
+                }
+
+                if !matches!(
+                    self.tiles.get(col, row),
+                    HexTile::Mine(_) | HexTile::Turret(_)
+                ) {
+                    return Err(RejectReason::WrongTile);
+                }
+
+                let sent = next.sent.entry(index).or_default();
+                if *sent >= MAX_MESSAGES {
+                    return Err(RejectReason::Quota);
+                }
+
+                let inbox = next.inboxes.entry(to).or_default();
+                if inbox.len() >= MAX_INBOX {
+                    return Err(RejectReason::InboxFull);
+                }
+
+                *sent += 1;
+                inbox.push(Message {
+                    col: x,
+                    row: y,
+                    payload: payload.clone(),
+                });
+
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    // index of (col, row) if it's on the map and within `range` steps
+    fn in_reach(&self, from: usize, col: u32, row: u32, range: u32) -> Result<usize, RejectReason> {
+        let to = self.get_index(col, row);
+
+        if self.in_bounds(col, row) && self.within_steps(from, to, range) {
+            Ok(to)
+        } else {
+            Err(RejectReason::OutOfRange)
+        }
+    }
+
+    // applies `update` to the mine at `index` through replace_tile
+    fn update_mine(
+        &mut self,
+        index: usize,
+        cause: Actor,
+        update: impl FnOnce(&mut MineData),
+    ) -> Vec<usize> {
+        let (x, y) = self.get_coords(index);
+
+        let HexTile::Mine(mine) = self.tiles.get(x, y) else {
+            return Vec::new();
+        };
+
+        let mut after = mine.clone();
+        update(&mut after);
+
+        if self.replace_tile(index, HexTile::Mine(after), cause) {
+            vec![index]
+        } else {
+            Vec::new()
+        }
+    }
+
+    // moves as much of `amount` as there is (and there's room for) from one
+    // mine to another, settling their trade values by what was moved
+    fn transfer_gold(
+        &mut self,
+        from: usize,
+        to: usize,
+        amount: u32,
+        actor: &Actor,
+    ) -> Result<Vec<usize>, RejectReason> {
+        let (fx, fy) = self.get_coords(from);
+        let (tx, ty) = self.get_coords(to);
+
+        let (HexTile::Mine(source), HexTile::Mine(target)) =
+            (self.tiles.get(fx, fy), self.tiles.get(tx, ty))
+        else {
+            return Err(RejectReason::WrongTile);
+        };
+
+        if from == to {
+            return Err(RejectReason::WrongTile);
+        }
+
+        if amount == 0 {
+            return Ok(Vec::new());
+        }
+
+        if source.count == 0 {
+            return Err(RejectReason::NoGold);
+        }
+
+        let moved = amount
+            .min(source.count)
+            .min(target.capacity.saturating_sub(target.count));
+
+        if moved == 0 {
+            return Err(RejectReason::NoRoom);
+        }
+
+        let mut changed = self.update_mine(from, actor.clone(), |mine| {
+            mine.count -= moved;
+            mine.trade_value = (mine.trade_value - moved as i32).max(0);
+        });
+
+        changed.extend(self.update_mine(to, actor.clone(), |mine| {
+            mine.count += moved;
+            mine.trade_value = (mine.trade_value + moved as i32).min(0);
+        }));
+
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::tests::{mine, turret};
+
+    use super::*;
+
+    // plans and resolves one tile's built-in conductor straight away
+    fn run(grid: &mut GridState, x: u32, y: u32, phase: ScriptRole) -> Vec<usize> {
+        let outcome = grid.plan_conductor(grid.get_index(x, y), phase);
+        let (_, intents) = outcome.result.unwrap();
+
+        let intents = intents
+            .into_iter()
+            .map(|intent| (outcome.index, outcome.actor.clone(), intent))
+            .collect();
+
+        grid.resolve_intents(Some(phase), intents, &mut NextTick::default())
+    }
+
+    fn mine_at(grid: &GridState, x: u32, y: u32) -> MineData {
+        match grid.get_tile(x, y) {
+            Some(HexTile::Mine(mine)) => mine.clone(),
+            tile => panic!("expected a mine at <{x}, {y}>, found {tile:?}"),
+        }
+    }
+
+    #[test]
+    fn it_walks_outwards_step_by_step() {
+        let grid = GridState::new(9, 9, HexTile::Wild);
+
+        let steps = grid.steps_from(4, 4, 2).collect::<Vec<_>>();
+
+        assert_eq!(steps[0], (grid.get_index(4, 4), 0));
+        assert!(steps.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert_eq!(steps.iter().filter(|(_, s)| *s == 1).count(), 6);
+        assert!(steps.iter().all(|(_, s)| *s <= 2));
+    }
+
+    #[test]
+    fn it_lets_greedy_mines_offer_overflow_and_ask_when_barren() {
+        let mut grid = GridState::new(6, 6, HexTile::Wild);
+        grid.set_terrain(1, 1, Terrain::Ore { richness: 2 });
+        grid.set_tile(1, 1, mine(9, 10, 0));
+        grid.set_tile(4, 4, mine(3, 10, 0));
+
+        run(&mut grid, 1, 1, ScriptRole::Mine);
+        run(&mut grid, 4, 4, ScriptRole::Mine);
+
+        assert_eq!(mine_at(&grid, 1, 1).trade_value, 9 + 2 - 10);
+        assert_eq!(mine_at(&grid, 4, 4).trade_value, -7);
+    }
+
+    #[test]
+    fn it_ships_gold_to_the_nearest_mine_in_need() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.set_tile(2, 2, mine(5, 5, 4));
+        grid.set_tile(3, 2, mine(0, 10, -3));
+        grid.set_tile(5, 2, mine(0, 10, -10));
+
+        let changed = run(&mut grid, 2, 2, ScriptRole::Logistics);
+
+        assert_eq!(changed, vec![grid.get_index(2, 2), grid.get_index(3, 2)]);
+
+        let (source, target, other) = (
+            mine_at(&grid, 2, 2),
+            mine_at(&grid, 3, 2),
+            mine_at(&grid, 5, 2),
+        );
+
+        assert_eq!((source.count, source.trade_value), (2, 1));
+        assert_eq!((target.count, target.trade_value), (3, 0));
+        assert_eq!(other.count, 0);
+    }
+
+    #[test]
+    fn it_shoots_the_closest_slime_in_range() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+        grid.set_tile(5, 5, turret());
+        grid.set_tile(7, 5, HexTile::Slime);
+        grid.set_tile(6, 5, HexTile::Slime);
+        grid.set_tile(9, 5, HexTile::Slime);
+
+        assert_eq!(grid.turret_tiles, vec![grid.get_index(5, 5)]);
+
+        let changed = run(&mut grid, 5, 5, ScriptRole::Defender);
+
+        assert_eq!(changed, vec![grid.get_index(6, 5)]);
+        assert_eq!(grid.get_tile(6, 5), Some(&HexTile::Wild));
+
+        run(&mut grid, 5, 5, ScriptRole::Defender);
+        run(&mut grid, 5, 5, ScriptRole::Defender);
+
+        // out of range
+        assert_eq!(grid.get_tile(7, 5), Some(&HexTile::Wild));
+        assert_eq!(grid.get_tile(9, 5), Some(&HexTile::Slime));
+    }
+
+    #[test]
+    fn it_only_shows_the_neighbourhood() {
+        let mut grid = GridState::new(20, 20, HexTile::Wild);
+        grid.set_tile(1, 1, turret());
+        grid.set_tile(1 + VIEW_RANGE + 1, 1, HexTile::Slime);
+
+        let ctx = TileContext::new(&grid, grid.get_index(1, 1), TileStore::default());
+
+        assert_eq!(
+            ctx.tile(1 + VIEW_RANGE, 1 + VIEW_RANGE),
+            Some(&HexTile::Wild)
+        );
+        assert_eq!(ctx.tile(1 + VIEW_RANGE + 1, 1), None);
+        assert_eq!(ctx.nearest(100, &|tile| *tile == HexTile::Slime), None);
+    }
+
+    #[test]
+    fn it_runs_a_phase_against_the_state_it_started_with() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+        grid.set_tile(2, 2, turret());
+        grid.set_tile(4, 2, turret());
+        grid.set_tile(3, 2, HexTile::Slime);
+        grid.set_tile(5, 2, HexTile::Slime);
+
+        // (3, 2) is the closest slime for both turrets; the second one can't
+        // know the first already took care of it, so (5, 2) survives
+        let changed = grid.run_scripts();
+
+        assert!(changed.contains(&grid.get_index(3, 2)));
+        assert_eq!(grid.get_tile(3, 2), Some(&HexTile::Wild));
+        assert_eq!(grid.get_tile(5, 2), Some(&HexTile::Slime));
+
+        // the lower index got there first, the other one hears about it
+        assert_eq!(
+            grid.rejections,
+            BTreeMap::from([(
+                grid.get_index(4, 2),
+                vec![Rejection {
+                    intent: Intent::Fire { col: 3, row: 2 },
+                    reason: RejectReason::TargetGone,
+                }]
+            )])
+        );
+
+        grid.run_scripts();
+
+        assert_eq!(grid.get_tile(5, 2), Some(&HexTile::Wild));
+
+        // both went for the last one, only last tick's are kept
+        assert_eq!(grid.rejections.len(), 1);
+        assert_eq!(
+            grid.rejections[&grid.get_index(4, 2)][0].intent,
+            Intent::Fire { col: 5, row: 2 }
+        );
+    }
+
+    #[test]
+    fn it_delivers_messages_in_order_within_quotas() {
+        let mut grid = GridState::new(20, 20, HexTile::Wild);
+        for (x, y) in [(1, 1), (2, 1), (3, 1), (15, 15)] {
+            grid.set_tile(x, y, mine(0, 10, 0));
+        }
+        grid.assignments.insert(
+            grid.get_index(15, 15),
+            crate::api::scripts::ScriptAssignment {
+                script_id: "bot".to_string(),
+                owner: "ada".to_string(),
+            },
+        );
+
+        let (a, b, c) = (
+            grid.get_index(1, 1),
+            grid.get_index(2, 1),
+            grid.get_index(3, 1),
+        );
+        let ada = Actor::Script("ada".to_string());
+        let send = |col, row, payload: &[u8]| Intent::Send {
+            col,
+            row,
+            payload: payload.to_vec(),
+        };
+
+        let mut intents = vec![
+            (b, Actor::Mine, send(3, 1, b"b")),
+            (a, ada.clone(), send(3, 1, b"a1")),
+            (a, ada.clone(), send(3, 1, b"a2")),
+            // far away, but ada's
+            (a, ada.clone(), send(15, 15, b"far")),
+            (b, Actor::Mine, send(15, 15, b"far")),
+            (b, Actor::Mine, send(4, 1, b"nobody")),
+        ];
+        intents.extend((0..MAX_MESSAGES).map(|_| (c, Actor::Mine, send(1, 1, b"spam"))));
+
+        let mut next = NextTick::default();
+        grid.resolve_intents(None, intents, &mut next);
+
+        let payloads = |to: usize| {
+            next.inboxes[&to]
+                .iter()
+                .map(|m| ((m.col, m.row), m.payload.as_slice()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            payloads(c),
+            [((1, 1), &b"a1"[..]), ((1, 1), b"a2"), ((2, 1), b"b")]
+        );
+        assert_eq!(payloads(grid.get_index(15, 15)), [((1, 1), &b"far"[..])]);
+        assert_eq!(next.inboxes[&a].len(), MAX_MESSAGES as usize);
+
+        let reasons = next
+            .rejected
+            .iter()
+            .map(|(&index, r)| (index, r.iter().map(|r| r.reason).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            reasons,
+            vec![(b, vec![RejectReason::OutOfRange, RejectReason::WrongTile])]
+        );
+
+        // one more is over the quota
+        let mut intents = (0..=MAX_MESSAGES)
+            .map(|_| (c, Actor::Mine, send(1, 1, b"spam")))
+            .collect::<Vec<_>>();
+        intents.push((c, Actor::Mine, send(2, 1, b"spam")));
+
+        let mut next = NextTick::default();
+        grid.resolve_intents(None, intents, &mut next);
+
+        assert_eq!(
+            next.rejected[&c]
+                .iter()
+                .map(|r| r.reason)
+                .collect::<Vec<_>>(),
+            [RejectReason::Quota, RejectReason::Quota]
+        );
+    }
+
+    #[test]
+    fn it_resolves_conflicting_intents_in_a_fixed_order() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+        grid.set_tile(2, 2, mine(5, 10, 4));
+        grid.set_tile(1, 2, mine(0, 10, -3));
+        grid.set_tile(3, 2, mine(0, 10, -10));
+        grid.set_tile(4, 2, mine(0, 10, 0));
+        grid.set_tile(5, 5, mine(7, 10, 0));
+
+        let (a, b, c, d, e) = (
+            grid.get_index(2, 2),
+            grid.get_index(1, 2),
+            grid.get_index(3, 2),
+            grid.get_index(4, 2),
+            grid.get_index(5, 5),
+        );
+        let request = Intent::Request {
+            col: 2,
+            row: 2,
+            amount: 3,
+        };
+
+        let mut next = NextTick::default();
+        grid.resolve_intents(
+            None,
+            vec![
+                (c, Actor::Mine, request.clone()),
+                (d, Actor::Mine, request.clone()),
+                (b, Actor::Mine, request.clone()),
+                // upgrades come before any gold moves
+                (b, Actor::Mine, Intent::Upgrade),
+                (e, Actor::Mine, Intent::Upgrade),
+                (a, Actor::Mine, Intent::Fire { col: 0, row: 0 }),
+            ],
+            &mut next,
+        );
+
+        // (2, 2) offered 4: (1, 2) takes 3, (3, 2) the last one
+        let counts = [(2, 2), (1, 2), (3, 2), (4, 2)].map(|(x, y)| mine_at(&grid, x, y).count);
+        assert_eq!(counts, [1, 3, 1, 0]);
+        assert_eq!(mine_at(&grid, 2, 2).trade_value, 0);
+        assert_eq!(mine_at(&grid, 3, 2).trade_value, -9);
+
+        let upgraded = mine_at(&grid, 5, 5);
+        assert_eq!(
+            (upgraded.level, upgraded.count, upgraded.capacity),
+            (2, 7 - UPGRADE_COST, 10 + UPGRADE_CAPACITY)
+        );
+
+        let reasons = next
+            .rejected
+            .iter()
+            .map(|(&index, r)| (index, r.iter().map(|r| r.reason).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            reasons,
+            vec![
+                (b, vec![RejectReason::NoGold]),
+                (a, vec![RejectReason::WrongTile]),
+                (d, vec![RejectReason::NotOffered]),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_turns_down_intents_from_another_phase() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+        grid.set_tile(2, 2, mine(5, 10, 0));
+        grid.set_tile(3, 2, mine(0, 10, 0));
+        grid.set_tile(4, 2, HexTile::Slime);
+
+        let (a, b) = (grid.get_index(2, 2), grid.get_index(3, 2));
+
+        let mut next = NextTick::default();
+        grid.resolve_intents(
+            Some(ScriptRole::Mine),
+            vec![
+                (a, Actor::Mine, Intent::Fire { col: 4, row: 2 }),
+                (
+                    a,
+                    Actor::Mine,
+                    Intent::Transfer {
+                        col: 3,
+                        row: 2,
+                        amount: 1,
+                    },
+                ),
+                (a, Actor::Mine, Intent::SetTradeValue(2)),
+                (
+                    a,
+                    Actor::Mine,
+                    Intent::Send {
+                        col: 3,
+                        row: 2,
+                        payload: b"hi".to_vec(),
+                    },
+                ),
+            ],
+            &mut next,
+        );
+
+        assert_eq!(grid.get_tile(4, 2), Some(&HexTile::Slime));
+        assert_eq!(
+            (mine_at(&grid, 2, 2).count, mine_at(&grid, 3, 2).count),
+            (5, 0)
+        );
+        assert_eq!(mine_at(&grid, 2, 2).trade_value, 2);
+        assert_eq!(next.inboxes[&b].len(), 1);
+
+        let reasons = next.rejected[&a]
+            .iter()
+            .map(|r| r.reason)
+            .collect::<Vec<_>>();
+
+        assert_eq!(reasons, [RejectReason::OutOfPhase; 2]);
+    }
+}
@@ -1,11 +1,64 @@
-use log::debug;
+use std::collections::BTreeMap;
+
+use log::{debug, warn};
+use rand::Rng;
+
+use crate::{
+    api::{
+        action_log::{ActionLog, Actor},
+        chunk_map::ChunkMap,
+        game::{Conductors, Message, Rejection},
+        journal::{Journal, JournalRecord},
+        profiler::Profiler,
+        replay::{Replay, ReplayAction},
+        script_host::ScriptLog,
+        scripts::{ScriptAssignment, ScriptStore},
+    },
+    types::{HexTile, Terrain, TileState},
+};
+
+// chances used when scattering terrain over a fresh map
+const ROCK_CHANCE: f64 = 0.15;
+const WATER_CHANCE: f64 = 0.05;
+const ORE_CHANCE: f64 = 0.08;
+const MAX_ORE_RICHNESS: u32 = 3;
+
+// 64-bit FNV-1a
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+// hash of one tile's position and contents. kept simple on purpose (FNV-1a of
+// "col,row:<tile as json>") so it's easy to reproduce outside of rust
+pub fn tile_hash(x: u32, y: u32, tile: &HexTile) -> u64 {
+    let json = serde_json::to_string(tile).unwrap_or_default();
+
+    fnv1a(format!("{x},{y}:{json}").as_bytes())
+}
 
-use crate::types::HexTile;
+// Wild tiles don't count towards the state hash
+fn hash_contribution(x: u32, y: u32, tile: &HexTile) -> u64 {
+    match tile {
+        HexTile::Wild => 0,
+        tile => tile_hash(x, y, tile),
+    }
+}
 
 pub struct GridState {
     pub width: usize,
     pub height: usize,
-    pub tiles: Vec<HexTile>,
+    // number of simulation ticks run so far
+    pub tick: u64,
+    // both layers are chunked, untouched regions fall back to the starter
+    // tile/plains without being allocated
+    pub tiles: ChunkMap<HexTile>,
+    // separate layer underneath tiles
+    pub terrain: ChunkMap<Terrain>,
 
     // TODO: for performance reasons these lists are more or less hardcoded
     // to ensure priority (e.g. mines running before slime); ideally I'd like
@@ -13,28 +66,77 @@ pub struct GridState {
     pub slime_tiles: Vec<usize>,
     pub mine_tiles: Vec<usize>,
     pub turret_tiles: Vec<usize>,
+
+    // recent tile changes and who made them, for undo/revert
+    pub actions: ActionLog,
+    // player edits and scripts get recorded into this while a replay is being
+    // captured
+    pub replay: Option<Replay>,
+    // every mutation gets appended to this, if there is one
+    pub journal: Option<Journal>,
+
+    // uploaded player scripts, and which tile (by index) runs which
+    pub scripts: ScriptStore,
+    pub assignments: BTreeMap<usize, ScriptAssignment>,
+    // what runs on tiles without a script
+    pub conductors: Conductors,
+    // what scripts logged during the last tick, for the owners to see
+    pub script_logs: Vec<ScriptLog>,
+    // intents of the last tick that were turned down, per tile
+    pub rejections: BTreeMap<usize, Vec<Rejection>>,
+    // messages sent last tick, per receiving tile
+    pub inboxes: BTreeMap<usize, Vec<Message>>,
+    // what each tile's script has cost so far
+    pub profiler: Profiler,
+    // slime the turrets shot down during the current tick
+    pub turret_kills: usize,
+
+    // kept up to date by every tile change, see state_hash
+    hash: u64,
 }
 
 impl GridState {
     pub fn new(width: usize, height: usize, starter_tile: HexTile) -> Self {
-        let tiles = vec![starter_tile; width * height];
+        let tiles = ChunkMap::new(starter_tile);
+        let terrain = ChunkMap::new(Terrain::Plains);
 
         debug!("Creating tile map of size {width}x{height}");
 
-        GridState {
+        let mut grid = GridState {
             width,
             height,
+            tick: 0,
             tiles,
+            terrain,
             slime_tiles: Vec::new(),
             mine_tiles: Vec::new(),
             turret_tiles: Vec::new(),
-        }
+            actions: ActionLog::default(),
+            replay: None,
+            journal: None,
+            scripts: ScriptStore::default(),
+            assignments: BTreeMap::new(),
+            conductors: Conductors::default(),
+            script_logs: Vec::new(),
+            rejections: BTreeMap::new(),
+            inboxes: BTreeMap::new(),
+            profiler: Profiler::default(),
+            turret_kills: 0,
+            hash: 0,
+        };
+
+        grid.hash = grid.compute_state_hash();
+        grid
     }
 
     pub fn get_index(&self, x: u32, y: u32) -> usize {
         (y as usize * self.width) + x as usize
     }
 
+    pub fn in_bounds(&self, x: u32, y: u32) -> bool {
+        (x as usize) < self.width && (y as usize) < self.height
+    }
+
     pub fn get_coords(&self, index: usize) -> (u32, u32) {
         let x = index % self.width;
         let y = index / self.width;
@@ -42,6 +144,24 @@ impl GridState {
         (x as u32, y as u32)
     }
 
+    // a per-tile map as (col, row, value), in index order, the way saves and
+    // the journal keep them
+    pub(crate) fn by_coords<T: Clone>(&self, map: &BTreeMap<usize, T>) -> Vec<(u32, u32, T)> {
+        map.iter()
+            .map(|(&index, value)| {
+                let (x, y) = self.get_coords(index);
+                (x, y, value.clone())
+            })
+            .collect()
+    }
+
+    // the other way around
+    pub(crate) fn by_index<T>(&self, list: Vec<(u32, u32, T)>) -> BTreeMap<usize, T> {
+        list.into_iter()
+            .map(|(x, y, value)| (self.get_index(x, y), value))
+            .collect()
+    }
+
     pub fn get_neighbors(&self, x: u32, y: u32) -> impl Iterator<Item = usize> {
         // neighbors:
         // (-1, 0), (1, 0) left and right
@@ -49,12 +169,11 @@ impl GridState {
         // (1, 1), (0, 1) bottom left, bottom right
         const NEIGHBORS: [(i8, i8); 6] = [(-1, 0), (1, 0), (1, -1), (0, -1), (1, 1), (0, 1)];
 
-        // TODO: bounds checking
         NEIGHBORS.iter().filter_map(move |&(dx, dy)| {
             let nx = x as i32 + dx as i32;
             let ny = y as i32 + dy as i32;
 
-            if nx < 0 || ny < 0 {
+            if nx < 0 || ny < 0 || !self.in_bounds(nx as u32, ny as u32) {
                 return None;
             }
 
@@ -65,21 +184,27 @@ impl GridState {
     }
 
     pub fn get_tile(&self, x: u32, y: u32) -> Option<&HexTile> {
-        self.tiles.get(self.get_index(x, y))
+        self.in_bounds(x, y).then(|| self.tiles.get(x, y))
     }
 
     pub fn set_tile(&mut self, x: u32, y: u32, new_tile: HexTile) {
+        if !self.in_bounds(x, y) {
+            warn!("ignoring update to out of bounds tile <{x}, {y}>");
+            return;
+        }
+
         let index = self.get_index(x, y);
         debug!("updating <{x}, {y}> to {new_tile}, giving it an index of <{index}>");
 
-        let tile = self.tiles[index].clone();
-
         // for priority, we keep a list for each "active" tile type
         // this requires unregistering/registering to keep it synchronized
         // with the main tile array
+        let tile = self.tiles.set(x, y, new_tile.clone());
         self.unregister_tile(index, &tile);
-        self.tiles[index] = new_tile.clone();
         self.register_tile(index, &new_tile);
+        self.drop_stale_assignment(index, &tile, &new_tile);
+
+        self.hash ^= hash_contribution(x, y, &tile) ^ hash_contribution(x, y, &new_tile);
 
         debug!(
             "mines: {:?}, slimes: {:?}",
@@ -87,6 +212,211 @@ impl GridState {
         );
     }
 
+    // set_tile on behalf of a player (or an admin), keeping track of it in
+    // the action log and the replay
+    pub fn edit_tile(&mut self, x: u32, y: u32, new_tile: HexTile, actor: Actor) {
+        let Some(before) = self.get_tile(x, y).cloned() else {
+            warn!("ignoring edit by {actor:?} to out of bounds tile <{x}, {y}>");
+            return;
+        };
+
+        if let Some(replay) = &mut self.replay
+            && !actor.is_simulation()
+        {
+            replay.record(ReplayAction::Tile {
+                tick: self.tick,
+                col: x,
+                row: y,
+                tile: new_tile.clone(),
+            });
+        }
+
+        let tick = self.tick;
+        self.journal_record(|| JournalRecord::Tile {
+            tick,
+            cause: actor.clone(),
+            col: x,
+            row: y,
+            before: before.clone(),
+            after: new_tile.clone(),
+        });
+
+        self.actions
+            .push(self.tick, actor, x, y, before, new_tile.clone());
+
+        self.set_tile(x, y, new_tile);
+    }
+
+    // set_tile by the simulation itself (slime, turrets, conductors),
+    // journaled but kept out of the action log, which is only for undoing
+    // what players did. false (and nothing recorded) if it's no change
+    pub(crate) fn replace_tile(&mut self, index: usize, after: HexTile, cause: Actor) -> bool {
+        let (x, y) = self.get_coords(index);
+        let before = self.tiles.get(x, y);
+
+        if *before == after {
+            return false;
+        }
+
+        let before = before.clone();
+        let tick = self.tick;
+
+        self.journal_record(|| JournalRecord::Tile {
+            tick,
+            cause,
+            col: x,
+            row: y,
+            before,
+            after: after.clone(),
+        });
+
+        self.set_tile(x, y, after);
+
+        true
+    }
+
+    pub fn advance_tick(&mut self) {
+        self.tick += 1;
+        self.turret_kills = 0;
+
+        let tick = self.tick;
+        self.journal_record(|| JournalRecord::Tick { tick });
+    }
+
+    // every non-Wild tile's tile_hash XORed together; equal grids give equal
+    // hashes no matter what order the tiles were set in. maintained
+    // incrementally, so this is free to call every tick
+    pub fn state_hash(&self) -> u64 {
+        self.hash
+    }
+
+    // state_hash from scratch
+    pub fn compute_state_hash(&self) -> u64 {
+        if *self.tiles.default_value() == HexTile::Wild {
+            return self
+                .tiles
+                .iter_non_default()
+                .fold(0, |hash, (x, y, tile)| hash ^ hash_contribution(x, y, tile));
+        }
+
+        (0..self.width * self.height)
+            .map(|i| self.get_coords(i))
+            .fold(0, |hash, (x, y)| {
+                hash ^ hash_contribution(x, y, self.tiles.get(x, y))
+            })
+    }
+
+    // wire representation of the tile at an index
+    pub fn tile_state(&self, index: usize) -> TileState {
+        let (x, y) = self.get_coords(index);
+
+        TileState {
+            col: x as i32,
+            row: y as i32,
+            data: self.tiles.get(x, y).clone(),
+        }
+    }
+
+    pub fn get_terrain(&self, x: u32, y: u32) -> Option<&Terrain> {
+        self.in_bounds(x, y).then(|| self.terrain.get(x, y))
+    }
+
+    pub fn set_terrain(&mut self, x: u32, y: u32, terrain: Terrain) {
+        if !self.in_bounds(x, y) {
+            warn!("ignoring terrain update to out of bounds tile <{x}, {y}>");
+            return;
+        }
+
+        debug!("setting terrain of <{x}, {y}> to {terrain}");
+
+        let before = *self.terrain.get(x, y);
+        let tick = self.tick;
+
+        self.journal_record(|| JournalRecord::Terrain {
+            tick,
+            col: x,
+            row: y,
+            before,
+            after: terrain,
+        });
+
+        self.terrain.set(x, y, terrain);
+    }
+
+    // tears down whatever is built on a tile; the terrain layer is untouched,
+    // so the deposit underneath is available again for the next building
+    pub fn demolish(&mut self, x: u32, y: u32) {
+        self.set_tile(x, y, HexTile::Wild);
+    }
+
+    // randomly scatters rock, water and ore deposits over the whole map
+    pub fn generate_terrain<R: Rng>(&mut self, rng: &mut R) {
+        for i in 0..self.width * self.height {
+            let (x, y) = self.get_coords(i);
+            let roll: f64 = rng.random();
+
+            let terrain = if roll < ORE_CHANCE {
+                Terrain::Ore {
+                    richness: rng.random_range(1..=MAX_ORE_RICHNESS),
+                }
+            } else if roll < ORE_CHANCE + WATER_CHANCE {
+                Terrain::Water
+            } else if roll < ORE_CHANCE + WATER_CHANCE + ROCK_CHANCE {
+                Terrain::Rock
+            } else {
+                Terrain::Plains
+            };
+
+            self.terrain.set(x, y, terrain);
+        }
+    }
+
+    // adds each mine's terrain yield to its stock (up to its capacity),
+    // returning the indices of mines whose count actually changed
+    pub fn produce_mines(&mut self) -> Vec<usize> {
+        let mut changed = Vec::new();
+        let mut journaled = Vec::new();
+
+        for &i in &self.mine_tiles {
+            let (x, y) = (i % self.width, i / self.width);
+            let produced = self.terrain.get(x as u32, y as u32).mine_yield();
+
+            let tile = self.tiles.get_mut(x as u32, y as u32);
+            let old_hash = hash_contribution(x as u32, y as u32, tile);
+            let before = self.journal.is_some().then(|| tile.clone());
+
+            if let HexTile::Mine(mine) = tile {
+                let count = (mine.count + produced).min(mine.capacity);
+
+                if count != mine.count {
+                    mine.count = count;
+                    changed.push(i);
+
+                    self.hash ^= old_hash ^ hash_contribution(x as u32, y as u32, tile);
+
+                    if let Some(before) = before {
+                        journaled.push((x as u32, y as u32, before, tile.clone()));
+                    }
+                }
+            }
+        }
+
+        let tick = self.tick;
+
+        for (col, row, before, after) in journaled {
+            self.journal_record(|| JournalRecord::Tile {
+                tick,
+                cause: Actor::Mine,
+                col,
+                row,
+                before,
+                after,
+            });
+        }
+
+        changed
+    }
+
     fn unregister_tile(&mut self, i: usize, t: &HexTile) {
         match t {
             HexTile::Mine(_) => {
@@ -95,6 +425,9 @@ impl GridState {
             HexTile::Slime => {
                 self.slime_tiles.retain(|j| *j != i);
             }
+            HexTile::Turret(_) => {
+                self.turret_tiles.retain(|j| *j != i);
+            }
             _ => {}
         }
     }
@@ -107,6 +440,9 @@ impl GridState {
             HexTile::Slime => {
                 self.slime_tiles.push(i);
             }
+            HexTile::Turret(_) => {
+                self.turret_tiles.push(i);
+            }
             _ => {}
         }
     }
@@ -116,7 +452,7 @@ impl GridState {
 mod tests {
     use std::collections::HashSet;
 
-    use crate::types::MineData;
+    use crate::{api::tile_store::TileStore, types::MineData};
 
     use super::*;
 
@@ -221,11 +557,14 @@ mod tests {
         assert!(tile.is_some());
         assert_eq!(tile.unwrap().clone(), start_tile);
 
+        let mut state = TileStore::default();
+        state.set("state", "1").unwrap();
+
         let new_tile = HexTile::Mine(MineData {
             level: 1,
             count: 1,
             capacity: 3,
-            state: "state".to_string(),
+            state,
             trade_value: 0,
         });
 
@@ -251,7 +590,7 @@ mod tests {
         let width = 72;
         let height = 30;
 
-        let mut grid_state = GridState::new(width, height, start_tile.clone());
+        let grid_state = GridState::new(width, height, start_tile.clone());
 
         let target_center = grid_state.get_index(5, 5);
 
@@ -267,5 +606,231 @@ mod tests {
         assert!(set.contains(&(target_center + width))); // bottom left
     }
 
+    #[test]
+    fn it_defaults_terrain_to_plains() {
+        let grid_state = GridState::new(10, 8, HexTile::Wild);
+
+        assert!((0..10).all(|x| {
+            (0..8).all(|y| *grid_state.get_terrain(x, y).unwrap() == Terrain::Plains)
+        }));
+        assert_eq!(grid_state.terrain.allocated_chunks(), 0);
+    }
+
+    #[test]
+    fn it_keeps_terrain_when_building_and_demolishing() {
+        let mut grid_state = GridState::new(10, 8, HexTile::Wild);
+
+        let deposit = Terrain::Ore { richness: 2 };
+        grid_state.set_terrain(3, 4, deposit);
+
+        grid_state.set_tile(
+            3,
+            4,
+            HexTile::Mine(MineData {
+                level: 1,
+                count: 0,
+                capacity: 5,
+                state: TileStore::default(),
+                trade_value: 0,
+            }),
+        );
+
+        // building doesn't touch the terrain
+        assert_eq!(*grid_state.get_terrain(3, 4).unwrap(), deposit);
+
+        grid_state.demolish(3, 4);
+
+        assert_eq!(grid_state.get_tile(3, 4).unwrap().clone(), HexTile::Wild);
+        assert_eq!(*grid_state.get_terrain(3, 4).unwrap(), deposit);
+        assert!(!grid_state.mine_tiles.contains(&grid_state.get_index(3, 4)));
+    }
+
+    #[test]
+    fn it_hashes_state_independent_of_edit_order() {
+        let mut a = GridState::new(10, 10, HexTile::Wild);
+        let mut b = GridState::new(10, 10, HexTile::Wild);
+
+        assert_eq!(a.state_hash(), 0);
+
+        a.set_tile(1, 2, HexTile::Slime);
+        a.set_tile(3, 4, HexTile::Slime);
+
+        b.set_tile(3, 4, HexTile::Slime);
+        b.set_tile(5, 5, HexTile::Slime);
+        b.set_tile(1, 2, HexTile::Slime);
+
+        assert_ne!(a.state_hash(), b.state_hash());
+
+        b.set_tile(5, 5, HexTile::Wild);
+
+        assert_eq!(a.state_hash(), b.state_hash());
+
+        // same tiles on a differently defaulted map hash the same too
+        let mut c = GridState::new(10, 10, HexTile::Slime);
+
+        for i in 0..100 {
+            let (x, y) = c.get_coords(i);
+
+            if (x, y) != (1, 2) && (x, y) != (3, 4) {
+                c.set_tile(x, y, HexTile::Wild);
+            }
+        }
+
+        assert_eq!(a.state_hash(), c.state_hash());
+    }
+
+    #[test]
+    fn it_hashes_tiles_the_documented_way() {
+        // the frontend computes the same thing, keep them in step
+        assert_eq!(tile_hash(1, 2, &HexTile::Slime), 0x31d309dce79147b0);
+    }
+
+    #[test]
+    fn it_keeps_the_incremental_hash_in_sync() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.set_terrain(2, 2, Terrain::Ore { richness: 2 });
+
+        let mine = HexTile::Mine(MineData {
+            level: 1,
+            count: 0,
+            capacity: 5,
+            state: TileStore::default(),
+            trade_value: 0,
+        });
+
+        grid.set_tile(2, 2, mine.clone());
+        grid.set_tile(3, 3, mine);
+        grid.set_tile(4, 4, HexTile::Slime);
+        assert_eq!(grid.state_hash(), grid.compute_state_hash());
+
+        // mines filling up change the hash as well
+        let before = grid.state_hash();
+        grid.produce_mines();
+
+        assert_ne!(grid.state_hash(), before);
+        assert_eq!(grid.state_hash(), grid.compute_state_hash());
+
+        grid.demolish(2, 2);
+        grid.demolish(4, 4);
+        assert_eq!(grid.state_hash(), grid.compute_state_hash());
+    }
+
+    #[test]
+    fn it_converts_index_to_tile_state() {
+        let mut grid_state = GridState::new(10, 8, HexTile::Wild);
+
+        grid_state.set_tile(4, 6, HexTile::Slime);
+
+        let tile = grid_state.tile_state(grid_state.get_index(4, 6));
+
+        assert_eq!(
+            tile,
+            TileState {
+                col: 4,
+                row: 6,
+                data: HexTile::Slime,
+            }
+        );
+    }
+
+    #[test]
+    fn it_produces_gold_based_on_deposit() {
+        let mut grid_state = GridState::new(10, 8, HexTile::Wild);
+
+        let mine = HexTile::Mine(MineData {
+            level: 1,
+            count: 0,
+            capacity: 3,
+            state: TileStore::default(),
+            trade_value: 0,
+        });
+
+        grid_state.set_terrain(0, 0, Terrain::Ore { richness: 2 });
+        grid_state.set_terrain(1, 0, Terrain::Plains);
+        grid_state.set_tile(0, 0, mine.clone());
+        grid_state.set_tile(1, 0, mine);
+
+        let changed = grid_state.produce_mines();
+
+        // only the mine on ore produced anything
+        assert_eq!(changed, vec![grid_state.get_index(0, 0)]);
+
+        let count = |grid: &GridState, x, y| match grid.get_tile(x, y).unwrap() {
+            HexTile::Mine(data) => data.count,
+            _ => panic!("expected mine"),
+        };
+
+        assert_eq!(count(&grid_state, 0, 0), 2);
+        assert_eq!(count(&grid_state, 1, 0), 0);
+
+        // capped at capacity
+        grid_state.produce_mines();
+        assert_eq!(count(&grid_state, 0, 0), 3);
+        assert!(grid_state.produce_mines().is_empty());
+    }
+
+    #[test]
+    fn it_generates_terrain_for_every_tile() {
+        let mut grid_state = GridState::new(30, 30, HexTile::Wild);
+
+        grid_state.generate_terrain(&mut rand::rng());
+
+        assert!((0..30).all(|x| (0..30).all(|y| {
+            match grid_state.get_terrain(x, y).unwrap() {
+                Terrain::Ore { richness } => (1..=MAX_ORE_RICHNESS).contains(richness),
+                _ => true,
+            }
+        })));
+    }
+
+    #[test]
+    fn it_skips_out_of_bounds_neighbors() {
+        let grid_state = GridState::new(10, 8, HexTile::Wild);
+
+        // top left corner only has right, bottom right and bottom left
+        let corner = grid_state.get_neighbors(0, 0).collect::<HashSet<usize>>();
+        assert_eq!(corner.len(), 3);
+
+        // bottom right corner only has left and top right
+        let corner = grid_state.get_neighbors(9, 7).collect::<HashSet<usize>>();
+        assert_eq!(corner.len(), 2);
+        assert!(
+            corner
+                .iter()
+                .all(|i| *i < grid_state.width * grid_state.height)
+        );
+    }
+
+    #[test]
+    fn it_ignores_out_of_bounds_tiles() {
+        let mut grid_state = GridState::new(10, 8, HexTile::Wild);
+
+        assert!(grid_state.get_tile(10, 0).is_none());
+        assert!(grid_state.get_tile(0, 8).is_none());
+
+        grid_state.set_tile(10, 0, HexTile::Slime);
+
+        assert!(grid_state.slime_tiles.is_empty());
+        assert_eq!(grid_state.tiles.allocated_chunks(), 0);
+    }
+
+    #[test]
+    fn it_only_allocates_touched_chunks_on_large_maps() {
+        let mut grid_state = GridState::new(2000, 2000, HexTile::Wild);
+
+        grid_state.set_tile(1500, 1999, HexTile::Slime);
+
+        assert_eq!(grid_state.tiles.allocated_chunks(), 1);
+        assert_eq!(
+            grid_state.get_tile(1500, 1999).unwrap().clone(),
+            HexTile::Slime
+        );
+
+        // back to the starter tile, chunk is elided again
+        grid_state.demolish(1500, 1999);
+
+        assert_eq!(grid_state.tiles.allocated_chunks(), 0);
+    }
+
     // test for invalid range tile
 }
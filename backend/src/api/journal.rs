@@ -0,0 +1,398 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        action_log::Actor,
+        game::{Message, Rejection},
+        grid_api::GridState,
+        scripts::ScriptAssignment,
+        snapshot,
+    },
+    types::{HexTile, Terrain},
+};
+
+// one mutation of the world, as written to the journal
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JournalRecord {
+    // the simulation moved on to `tick`
+    Tick {
+        tick: u64,
+    },
+    Tile {
+        tick: u64,
+        cause: Actor,
+        col: u32,
+        row: u32,
+        before: HexTile,
+        after: HexTile,
+    },
+    // the ground underneath a tile changed
+    Terrain {
+        tick: u64,
+        col: u32,
+        row: u32,
+        before: Terrain,
+        after: Terrain,
+    },
+    // a player put a script on a tile
+    Assign {
+        tick: u64,
+        col: u32,
+        row: u32,
+        assignment: ScriptAssignment,
+    },
+    // what the conductors left each other for the next tick, written whenever
+    // that changes
+    Pending {
+        tick: u64,
+        rejections: Vec<(u32, u32, Vec<Rejection>)>,
+        inboxes: Vec<(u32, u32, Vec<Message>)>,
+    },
+}
+
+// append-only log of every mutation since the last snapshot, one json record
+// per line. together with that snapshot it's enough to rebuild the world
+// after a crash, and the archived segments double as an audit trail
+pub struct Journal {
+    path: PathBuf,
+    // the snapshot this journal continues from
+    snapshot_path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl Journal {
+    pub fn open(path: &Path, snapshot_path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Journal {
+            path: path.to_path_buf(),
+            snapshot_path: snapshot_path.to_path_buf(),
+            writer: BufWriter::new(file),
+        })
+    }
+
+    // buffered until the next sync
+    pub fn append(&mut self, record: &JournalRecord) {
+        let result = serde_json::to_writer(&mut self.writer, record)
+            .map_err(io::Error::from)
+            .and_then(|_| self.writer.write_all(b"\n"));
+
+        if let Err(e) = result {
+            error!("failed to write to journal {}: {e}", self.path.display());
+        }
+    }
+
+    // makes everything appended so far durable
+    pub fn sync(&mut self) {
+        let result = self
+            .writer
+            .flush()
+            .and_then(|_| self.writer.get_ref().sync_data());
+
+        if let Err(e) = result {
+            error!("failed to sync journal {}: {e}", self.path.display());
+        }
+    }
+
+    // moves the journal so far aside (as <name>.<tick>.jsonl) and starts an
+    // empty one; called once a snapshot covers everything in it. checkpoints
+    // can come more than once a tick (startup, shutdown), later segments of
+    // the same tick go to <name>.<tick>.<n>.jsonl instead of over the first
+    fn rotate(&mut self, tick: u64) -> io::Result<()> {
+        self.writer.flush()?;
+
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let archived = (0..)
+            .map(|n| match n {
+                0 => self.path.with_file_name(format!("{stem}.{tick}.jsonl")),
+                n => self.path.with_file_name(format!("{stem}.{tick}.{n}.jsonl")),
+            })
+            .find(|path| !path.exists())
+            .unwrap();
+
+        if fs::metadata(&self.path)?.len() > 0 {
+            fs::rename(&self.path, &archived)?;
+        }
+
+        *self = Journal::open(&self.path, &self.snapshot_path)?;
+
+        Ok(())
+    }
+}
+
+// every record in a journal file, oldest first. a torn last line (from a
+// crash mid-write) is skipped
+pub fn read(path: &Path) -> io::Result<Vec<JournalRecord>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let lines = BufReader::new(file)
+        .lines()
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut records = Vec::with_capacity(lines.len());
+
+    for (i, line) in lines.iter().enumerate() {
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(e) if i + 1 == lines.len() => {
+                warn!("skipping torn record at the end of {}: {e}", path.display());
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(records)
+}
+
+impl GridState {
+    // re-applies journal records on top of the snapshot they continue from
+    pub fn apply_journal(&mut self, records: Vec<JournalRecord>) {
+        let count = records.len();
+
+        for record in records {
+            match record {
+                JournalRecord::Tick { tick } => self.tick = tick,
+                JournalRecord::Tile {
+                    col, row, after, ..
+                } => self.set_tile(col, row, after),
+                JournalRecord::Terrain {
+                    col, row, after, ..
+                } => {
+                    self.terrain.set(col, row, after);
+                }
+                JournalRecord::Assign {
+                    col,
+                    row,
+                    assignment,
+                    ..
+                } => {
+                    let index = self.get_index(col, row);
+                    self.assignments.insert(index, assignment);
+                }
+                JournalRecord::Pending {
+                    rejections,
+                    inboxes,
+                    ..
+                } => {
+                    self.rejections = self.by_index(rejections);
+                    self.inboxes = self.by_index(inboxes);
+                }
+            }
+        }
+
+        if count > 0 {
+            info!(
+                "replayed {count} journal records, now at tick {}",
+                self.tick
+            );
+        }
+    }
+
+    pub(crate) fn journal_record(&mut self, record: impl FnOnce() -> JournalRecord) {
+        if let Some(journal) = &mut self.journal {
+            journal.append(&record());
+        }
+    }
+
+    pub fn sync_journal(&mut self) {
+        if let Some(journal) = &mut self.journal {
+            journal.sync();
+        }
+    }
+
+    // saves a snapshot and starts a fresh journal on top of it. without a
+    // journal there's nothing to checkpoint
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        let Some(mut journal) = self.journal.take() else {
+            return Ok(());
+        };
+
+        let result =
+            snapshot::save(self, &journal.snapshot_path).and_then(|_| journal.rotate(self.tick));
+
+        self.journal = Some(journal);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api::simulation, types::tests::turret};
+
+    use super::*;
+
+    // fresh directory per test, so they can run side by side
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("plu-journal-{name}-{}", std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn tiles(grid: &GridState) -> Vec<HexTile> {
+        (0..grid.width * grid.height)
+            .map(|i| grid.tile_state(i).data)
+            .collect()
+    }
+
+    #[test]
+    fn it_journals_every_mutation() {
+        let dir = temp_dir("mutations");
+
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.journal =
+            Some(Journal::open(&dir.join("journal.jsonl"), &dir.join("world.json")).unwrap());
+
+        grid.edit_tile(2, 2, HexTile::Slime, Actor::Player("ada".to_string()));
+        simulation::tick(&mut grid);
+        grid.sync_journal();
+
+        let records = read(&dir.join("journal.jsonl")).unwrap();
+
+        assert_eq!(
+            records[0],
+            JournalRecord::Tile {
+                tick: 0,
+                cause: Actor::Player("ada".to_string()),
+                col: 2,
+                row: 2,
+                before: HexTile::Wild,
+                after: HexTile::Slime,
+            }
+        );
+        assert_eq!(records[1], JournalRecord::Tick { tick: 1 });
+
+        // then the slime spreading
+        assert_eq!(records.len(), 2 + grid.get_neighbors(2, 2).count());
+        assert!(records[2..].iter().all(|r| matches!(
+            r,
+            JournalRecord::Tile {
+                cause: Actor::Slime,
+                tick: 1,
+                ..
+            }
+        )));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_recovers_from_snapshot_and_journal() {
+        let dir = temp_dir("recover");
+        let (journal_path, snapshot_path) = (dir.join("journal.jsonl"), dir.join("world.json"));
+
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.journal = Some(Journal::open(&journal_path, &snapshot_path).unwrap());
+
+        grid.edit_tile(1, 1, HexTile::Slime, Actor::Player("ada".to_string()));
+        simulation::tick(&mut grid);
+        grid.checkpoint().unwrap();
+
+        grid.edit_tile(6, 6, HexTile::Slime, Actor::Player("bob".to_string()));
+        grid.set_terrain(3, 3, Terrain::Ore { richness: 2 });
+        simulation::tick(&mut grid);
+        simulation::tick(&mut grid);
+        grid.sync_journal();
+
+        // "crash" here, and start back up
+        let mut recovered = snapshot::load(&snapshot_path).unwrap().unwrap();
+        assert_eq!(recovered.tick, 1);
+
+        recovered.apply_journal(read(&journal_path).unwrap());
+
+        assert_eq!(recovered.tick, 3);
+        assert_eq!(tiles(&recovered), tiles(&grid));
+        assert_eq!(recovered.state_hash(), grid.state_hash());
+        assert_eq!(
+            recovered.get_terrain(3, 3),
+            Some(&Terrain::Ore { richness: 2 })
+        );
+
+        // the part covered by the snapshot was archived, not thrown away
+        assert!(!read(&dir.join("journal.1.jsonl")).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_keeps_every_segment_archived_during_one_tick() {
+        let dir = temp_dir("same-tick");
+
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.journal =
+            Some(Journal::open(&dir.join("journal.jsonl"), &dir.join("world.json")).unwrap());
+
+        // say a periodic checkpoint, then edits and a shutdown before the
+        // next tick
+        grid.edit_tile(1, 1, HexTile::Slime, Actor::Player("ada".to_string()));
+        grid.checkpoint().unwrap();
+        grid.edit_tile(2, 2, HexTile::Slime, Actor::Player("bob".to_string()));
+        grid.checkpoint().unwrap();
+
+        let first = read(&dir.join("journal.0.jsonl")).unwrap();
+        let second = read(&dir.join("journal.0.1.jsonl")).unwrap();
+
+        assert!(matches!(&first[..], [JournalRecord::Tile { col: 1, .. }]));
+        assert!(matches!(&second[..], [JournalRecord::Tile { col: 2, .. }]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_recovers_what_conductors_get_told_next_tick() {
+        let dir = temp_dir("pending");
+        let (journal_path, snapshot_path) = (dir.join("journal.jsonl"), dir.join("world.json"));
+
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.journal = Some(Journal::open(&journal_path, &snapshot_path).unwrap());
+        grid.checkpoint().unwrap();
+
+        // both turrets go for the same slime, the second one misses
+        grid.set_tile(2, 2, turret());
+        grid.set_tile(4, 2, turret());
+        grid.set_tile(3, 2, HexTile::Slime);
+        grid.run_scripts();
+        grid.sync_journal();
+
+        assert_eq!(grid.rejections.len(), 1);
+
+        let mut recovered = snapshot::load(&snapshot_path).unwrap().unwrap();
+        recovered.apply_journal(read(&journal_path).unwrap());
+
+        assert_eq!(recovered.rejections, grid.rejections);
+        assert_eq!(recovered.inboxes, grid.inboxes);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_skips_a_torn_last_record() {
+        let dir = temp_dir("torn");
+        let path = dir.join("journal.jsonl");
+
+        fs::write(&path, "{\"type\":\"tick\",\"tick\":4}\n{\"type\":\"ti").unwrap();
+
+        assert_eq!(read(&path).unwrap(), vec![JournalRecord::Tick { tick: 4 }]);
+
+        // anywhere else it's corruption
+        fs::write(&path, "{\"type\":\"ti\n{\"type\":\"tick\",\"tick\":4}\n").unwrap();
+
+        assert!(read(&path).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,2 +1,15 @@
+pub mod action_log;
+pub mod chunk_map;
 pub mod game;
 pub mod grid_api;
+pub mod journal;
+pub mod profiler;
+pub mod replay;
+pub mod rhai_host;
+pub mod script_harness;
+pub mod script_host;
+pub mod scripts;
+pub mod simulation;
+pub mod snapshot;
+pub mod stats;
+pub mod tile_store;
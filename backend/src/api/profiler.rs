@@ -0,0 +1,186 @@
+// what player scripts cost to run, per tile: fuel, wall time and memory of
+// every call, added up per tick and kept for as long as the tile runs the
+// same script. not part of the world, so neither journaled nor snapshotted
+
+use std::{cmp::Reverse, collections::BTreeMap};
+
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+// what one call of a tile's script cost
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct ScriptCost {
+    // fuel for wasm, operations for rhai; out of SCRIPT_FUEL either way
+    pub fuel: u64,
+    pub wall_us: u64,
+    // linear memory the wasm instance ended up with. none for rhai, whose
+    // values live on the host heap (capped by the engine limits instead)
+    pub memory: Option<u64>,
+}
+
+// a script's cost on one tile since it started running there. a tick's
+// figures are summed over every phase the script ran in; divide the totals
+// by `ticks` for averages
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/types/types.ts")]
+pub struct ScriptProfile {
+    pub col: u32,
+    pub row: u32,
+    pub script_id: String,
+    pub owner: String,
+    #[ts(type = "number")]
+    pub ticks: u64,
+    #[ts(type = "number")]
+    pub last_tick: u64,
+    #[ts(type = "number")]
+    pub last_fuel: u64,
+    #[ts(type = "number")]
+    pub total_fuel: u64,
+    #[ts(type = "number")]
+    pub peak_fuel: u64,
+    #[ts(type = "number")]
+    pub last_wall_us: u64,
+    #[ts(type = "number")]
+    pub total_wall_us: u64,
+    #[ts(type = "number")]
+    pub peak_wall_us: u64,
+    // none until a call reports its memory, so always for rhai
+    #[ts(type = "number | null")]
+    pub peak_memory: Option<u64>,
+}
+
+impl ScriptProfile {
+    fn new(col: u32, row: u32, script_id: &str, owner: &str) -> Self {
+        ScriptProfile {
+            col,
+            row,
+            script_id: script_id.to_string(),
+            owner: owner.to_string(),
+            ticks: 0,
+            last_tick: 0,
+            last_fuel: 0,
+            total_fuel: 0,
+            peak_fuel: 0,
+            last_wall_us: 0,
+            total_wall_us: 0,
+            peak_wall_us: 0,
+            peak_memory: None,
+        }
+    }
+}
+
+// profiles by tile index
+#[derive(Debug, Default)]
+pub struct Profiler {
+    tiles: BTreeMap<usize, ScriptProfile>,
+}
+
+impl Profiler {
+    // adds a call made during `tick`. a different script (or owner) on the
+    // tile starts over
+    pub fn record(
+        &mut self,
+        index: usize,
+        (col, row): (u32, u32),
+        script_id: &str,
+        owner: &str,
+        tick: u64,
+        cost: ScriptCost,
+    ) {
+        let profile = self
+            .tiles
+            .entry(index)
+            .or_insert_with(|| ScriptProfile::new(col, row, script_id, owner));
+
+        if profile.script_id != script_id || profile.owner != owner {
+            *profile = ScriptProfile::new(col, row, script_id, owner);
+        }
+
+        if profile.ticks == 0 || profile.last_tick != tick {
+            profile.ticks += 1;
+            profile.last_tick = tick;
+            profile.last_fuel = 0;
+            profile.last_wall_us = 0;
+        }
+
+        profile.last_fuel += cost.fuel;
+        profile.last_wall_us += cost.wall_us;
+        profile.total_fuel += cost.fuel;
+        profile.total_wall_us += cost.wall_us;
+
+        profile.peak_fuel = profile.peak_fuel.max(profile.last_fuel);
+        profile.peak_wall_us = profile.peak_wall_us.max(profile.last_wall_us);
+        profile.peak_memory = profile.peak_memory.max(cost.memory);
+    }
+
+    pub fn get(&self, index: usize) -> Option<&ScriptProfile> {
+        self.tiles.get(&index)
+    }
+
+    // drops the tiles `keep` says no to
+    pub fn retain(&mut self, mut keep: impl FnMut(usize) -> bool) {
+        self.tiles.retain(|&index, _| keep(index));
+    }
+
+    // every profile (or only `owner`'s), most fuel spent overall first
+    pub fn report(&self, owner: Option<&str>) -> Vec<ScriptProfile> {
+        let mut profiles = self
+            .tiles
+            .values()
+            .filter(|profile| owner.is_none_or(|owner| profile.owner == owner))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        profiles.sort_by_key(|profile| Reverse(profile.total_fuel));
+
+        profiles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cost(fuel: u64, wall_us: u64, memory: Option<u64>) -> ScriptCost {
+        ScriptCost {
+            fuel,
+            wall_us,
+            memory,
+        }
+    }
+
+    #[test]
+    fn it_adds_up_costs_per_tick() {
+        let mut profiler = Profiler::default();
+
+        // two phases of the same tick, then a cheaper one
+        profiler.record(3, (3, 0), "a", "alice", 1, cost(100, 5, Some(65536)));
+        profiler.record(3, (3, 0), "a", "alice", 1, cost(50, 5, Some(65536)));
+        profiler.record(3, (3, 0), "a", "alice", 2, cost(20, 1, Some(131072)));
+        profiler.record(7, (7, 0), "b", "bob", 2, cost(500, 9, None));
+
+        let profile = profiler.get(3).unwrap();
+
+        assert_eq!(profile.ticks, 2);
+        assert_eq!((profile.last_tick, profile.last_fuel), (2, 20));
+        assert_eq!((profile.total_fuel, profile.peak_fuel), (170, 150));
+        assert_eq!((profile.total_wall_us, profile.peak_wall_us), (11, 10));
+        assert_eq!(profile.peak_memory, Some(131072));
+        assert_eq!(profiler.get(7).unwrap().peak_memory, None);
+
+        let everyone = profiler.report(None);
+        assert_eq!(everyone.len(), 2);
+        assert_eq!(everyone[0].script_id, "b");
+
+        let alice = profiler.report(Some("alice"));
+        assert_eq!(alice.len(), 1);
+        assert_eq!(alice[0].script_id, "a");
+
+        // another script starts from scratch
+        profiler.record(3, (3, 0), "c", "alice", 3, cost(1, 1, None));
+        assert_eq!(profiler.get(3).unwrap().total_fuel, 1);
+
+        profiler.retain(|index| index != 7);
+        assert!(profiler.get(7).is_none());
+    }
+}
@@ -0,0 +1,363 @@
+use std::{fmt::Display, fs, io, path::Path};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        grid_api::GridState,
+        scripts::{ScriptLanguage, ScriptRole},
+        simulation,
+        snapshot::GridSnapshot,
+    },
+    types::HexTile,
+};
+
+// something a player did, applied once the grid had run `tick` ticks
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReplayAction {
+    Tile {
+        tick: u64,
+        col: u32,
+        row: u32,
+        tile: HexTile,
+    },
+    // a script upload, with everything it takes to compile it again
+    Upload {
+        tick: u64,
+        language: ScriptLanguage,
+        role: ScriptRole,
+        bytes: Vec<u8>,
+    },
+    Assign {
+        tick: u64,
+        col: u32,
+        row: u32,
+        script_id: String,
+        owner: String,
+    },
+    // an upload replacing `from` on `owner`'s tiles
+    Reload {
+        tick: u64,
+        from: String,
+        to: String,
+        owner: String,
+    },
+}
+
+impl ReplayAction {
+    pub fn tick(&self) -> u64 {
+        match *self {
+            Self::Tile { tick, .. }
+            | Self::Upload { tick, .. }
+            | Self::Assign { tick, .. }
+            | Self::Reload { tick, .. } => tick,
+        }
+    }
+
+    // does it again, the way the server did the first time around
+    fn apply(&self, grid: &mut GridState) {
+        let result = match self {
+            Self::Tile { col, row, tile, .. } => {
+                grid.set_tile(*col, *row, tile.clone());
+                Ok(())
+            }
+            Self::Upload {
+                language,
+                role,
+                bytes,
+                ..
+            } => grid.scripts.upload_as(*language, *role, bytes).map(|_| ()),
+            Self::Assign {
+                col,
+                row,
+                script_id,
+                owner,
+                ..
+            } => grid.assign_script(*col, *row, script_id, owner),
+            Self::Reload {
+                from, to, owner, ..
+            } => {
+                grid.scripts.schedule_reload(from, to, owner);
+                Ok(())
+            }
+        };
+
+        if let Err(e) = result {
+            warn!("replaying {self:?} failed: {e}");
+        }
+    }
+}
+
+// everything needed to re-run a session: where it started, what players did
+// and when, and what the world looked like at the end
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    // terrain seed, if the starting world was generated this session
+    pub seed: Option<u64>,
+    pub initial: GridSnapshot,
+    // in the order they were applied
+    pub actions: Vec<ReplayAction>,
+    pub final_tick: u64,
+    pub final_hash: u64,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ReplayMismatch {
+    pub tick: u64,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl Display for ReplayMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "state hash at tick {} is {:016x}, expected {:016x}",
+            self.tick, self.actual, self.expected
+        )
+    }
+}
+
+impl Replay {
+    pub fn start(grid: &GridState, seed: Option<u64>) -> Self {
+        let mut replay = Replay {
+            seed,
+            initial: GridSnapshot::capture(grid),
+            actions: Vec::new(),
+            final_tick: grid.tick,
+            final_hash: grid.state_hash(),
+        };
+
+        // the snapshot only says which scripts already run where, they have
+        // to be uploaded again too
+        let mut running = grid
+            .assignments
+            .values()
+            .map(|assignment| assignment.script_id.as_str())
+            .collect::<Vec<_>>();
+        running.sort_unstable();
+        running.dedup();
+
+        for id in running {
+            match (grid.scripts.get(id), grid.scripts.source(id)) {
+                (Some(script), Some(bytes)) => replay.record(ReplayAction::Upload {
+                    tick: grid.tick,
+                    language: script.language(),
+                    // any of them compiles it the same
+                    role: script.roles[0],
+                    bytes,
+                }),
+                _ => warn!("script {id} isn't on disk, the replay will run without it"),
+            }
+        }
+
+        replay
+    }
+
+    pub fn record(&mut self, action: ReplayAction) {
+        self.actions.push(action);
+    }
+
+    // stamps the end state that playback has to arrive at
+    pub fn finish(&mut self, grid: &GridState) {
+        self.final_tick = grid.tick;
+        self.final_hash = grid.state_hash();
+    }
+
+    // re-runs the session up to final_tick through the same tick logic as the
+    // server, applying every action between the same two ticks it originally
+    // landed between
+    pub fn play(&self) -> GridState {
+        let mut grid = self.initial.clone().restore();
+        let mut actions = self.actions.iter().peekable();
+
+        loop {
+            while let Some(action) = actions.next_if(|a| a.tick() <= grid.tick) {
+                action.apply(&mut grid);
+            }
+
+            if grid.tick >= self.final_tick {
+                break;
+            }
+
+            simulation::tick(&mut grid);
+        }
+
+        grid
+    }
+
+    pub fn verify(&self) -> Result<GridState, ReplayMismatch> {
+        let grid = self.play();
+        let actual = grid.state_hash();
+
+        if actual != self.final_hash {
+            return Err(ReplayMismatch {
+                tick: grid.tick,
+                expected: self.final_hash,
+                actual,
+            });
+        }
+
+        Ok(grid)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec(self)?)?;
+
+        info!(
+            "saved replay of {} actions up to tick {} to {}",
+            self.actions.len(),
+            self.final_tick,
+            path.display()
+        );
+
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::scripts::{self, ScriptStore},
+        types::{MineData, tests::mine},
+    };
+
+    use super::*;
+
+    // a short session: slime dropped in before the first tick, one of the
+    // mines it spawned cleared and more slime added later on
+    fn record_session() -> Replay {
+        let mut grid = GridState::new(12, 12, HexTile::Wild);
+        let mut replay = Replay::start(&grid, None);
+
+        let edit = |grid: &mut GridState, replay: &mut Replay, col, row, tile: HexTile| {
+            replay.record(ReplayAction::Tile {
+                tick: grid.tick,
+                col,
+                row,
+                tile: tile.clone(),
+            });
+            grid.set_tile(col, row, tile);
+        };
+
+        edit(&mut grid, &mut replay, 4, 4, HexTile::Slime);
+
+        for _ in 0..3 {
+            simulation::tick(&mut grid);
+        }
+
+        edit(&mut grid, &mut replay, 5, 4, HexTile::Wild);
+        edit(&mut grid, &mut replay, 9, 9, HexTile::Slime);
+
+        for _ in 0..2 {
+            simulation::tick(&mut grid);
+        }
+
+        replay.finish(&grid);
+        replay
+    }
+
+    #[test]
+    fn it_reproduces_the_recorded_session() {
+        let replay = record_session();
+
+        assert_eq!(replay.final_tick, 5);
+
+        let grid = replay.verify().unwrap();
+
+        assert_eq!(grid.tick, 5);
+        assert_eq!(grid.state_hash(), replay.final_hash);
+    }
+
+    #[test]
+    fn it_reports_a_diverging_replay() {
+        let mut replay = record_session();
+
+        // as if a different tile had been edited
+        let ReplayAction::Tile { col, .. } = &mut replay.actions[2] else {
+            panic!("expected a tile edit");
+        };
+        *col -= 1;
+
+        let Err(mismatch) = replay.verify() else {
+            panic!("replay should have diverged");
+        };
+
+        assert_eq!(mismatch.tick, 5);
+        assert_eq!(mismatch.expected, replay.final_hash);
+        assert_ne!(mismatch.actual, mismatch.expected);
+    }
+
+    #[test]
+    fn it_replays_script_uploads_and_reloads() {
+        let dir = std::env::temp_dir().join(format!("plu-replay-scripts-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.scripts = ScriptStore::open(&dir).unwrap();
+        grid.set_tile(2, 2, mine(0, 10, 0));
+        grid.set_tile(5, 5, mine(0, 10, 0));
+
+        let upload = |grid: &mut GridState, value: i32, replaces: Option<&str>| {
+            let bytes = format!("fn mine_tick() {{ set_trade_value({value}); }}").into_bytes();
+            let script = scripts::compile(
+                grid.scripts.engine(),
+                ScriptLanguage::Rhai,
+                ScriptRole::Mine,
+                &bytes,
+            )
+            .unwrap();
+
+            grid.upload_script(script, &bytes, "ada", replaces).unwrap()
+        };
+
+        // already running before the recording starts
+        let first = upload(&mut grid, 3, None);
+        grid.assign_script(2, 2, &first, "ada").unwrap();
+        simulation::tick(&mut grid);
+
+        grid.replay = Some(Replay::start(&grid, None));
+
+        let second = upload(&mut grid, 5, None);
+        grid.assign_script(5, 5, &second, "ada").unwrap();
+        simulation::tick(&mut grid);
+
+        upload(&mut grid, 7, Some(&first));
+        simulation::tick(&mut grid);
+        simulation::tick(&mut grid);
+
+        let mut replay = grid.replay.take().unwrap();
+        replay.finish(&grid);
+
+        let trade_values = |grid: &GridState| {
+            [(2, 2), (5, 5)].map(|(x, y)| match grid.get_tile(x, y) {
+                Some(HexTile::Mine(MineData { trade_value, .. })) => *trade_value,
+                tile => panic!("expected a mine, got {tile:?}"),
+            })
+        };
+
+        assert_eq!(trade_values(&grid), [7, 5]);
+        assert_eq!(trade_values(&replay.verify().unwrap()), [7, 5]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_round_trips_through_disk() {
+        let replay = record_session();
+
+        let path = std::env::temp_dir().join(format!("plu-replay-{}.json", std::process::id()));
+        replay.save(&path).unwrap();
+
+        let loaded = Replay::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, replay);
+    }
+}
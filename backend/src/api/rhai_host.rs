@@ -0,0 +1,575 @@
+// rhai flavour of player scripts, for anyone without a wasm toolchain: plain
+// source that defines the same entry points a wasm module would export
+// (`fn mine_tick() { ... }`, `fn migrate() { ... }`) and runs on the same
+// fuel. top-level statements never run, only those functions
+//
+// what scripts can call mirrors GlobalApi and the role contexts:
+//   position() tick() tile(col, row) terrain(col, row) nearest(range, kind)
+//   rejected() inbox() message(col, row, text)
+//   state_get(key) state_set(key, value) state_remove(key)
+//   state_version() set_state_version(version) log(level, message)
+//   mine() yield_per_tick() set_trade_value(value) upgrade()
+//   send(col, row, amount) request(col, row, amount)
+//   turret() fire_at(col, row)
+// tiles come back as maps with a `kind` ("wild", "slime", "mine", "turret")
+// and their fields, nothing that isn't visible as (). intents return false
+// once MAX_INTENTS are queued, like the wasm imports
+
+use std::{
+    cell::Cell,
+    sync::{Arc, Mutex},
+};
+
+use rhai::{
+    AST, Array, CallFnOptions, Dynamic, Engine, EvalAltResult, INT, ImmutableString, Map,
+    NativeCallContext, Scope, module_resolvers::DummyModuleResolver,
+};
+
+use crate::{
+    api::{
+        game::{Intent, MAX_MESSAGE_BYTES, RejectReason, Rejection},
+        script_host::{HostState, LogLevel, RunError, SCRIPT_FUEL, TileInfo, TileKind},
+        scripts::{Script, ScriptCode, ScriptError, ScriptRole, script_id},
+        tile_store::{MAX_STATE_BYTES, TileStore},
+    },
+    types::Terrain,
+};
+
+// keeps scripts from eating the stack or memory before they run out of fuel
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_COLLECTION_SIZE: usize = 1024;
+
+type RhaiResult<T> = Result<T, Box<EvalAltResult>>;
+
+thread_local! {
+    // operations of the call running on this thread so far, as its fuel. the
+    // engine is shared, but a call never leaves the thread it started on
+    static OPERATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+// tiles as maps with a `kind` and whatever numbers it has
+fn tile_map(tile: TileInfo) -> Map {
+    let int = |name: &str, value: INT| (name.into(), Dynamic::from(value));
+    let mut map = Map::from([("kind".into(), tile.kind.name().into())]);
+
+    match tile.kind {
+        TileKind::Wild | TileKind::Slime => {}
+        TileKind::Mine => map.extend([
+            int("level", tile.level.into()),
+            int("count", tile.count.into()),
+            int("capacity", tile.capacity.into()),
+            int("trade_value", tile.trade_value.into()),
+        ]),
+        TileKind::Turret => map.extend([int("level", tile.level.into())]),
+    }
+
+    map
+}
+
+// () for nothing, like everything that isn't visible
+fn found<T>(value: Option<T>, to_dynamic: impl FnOnce(T) -> Dynamic) -> Dynamic {
+    value.map_or(Dynamic::UNIT, to_dynamic)
+}
+
+fn terrain_map(terrain: Terrain) -> Map {
+    let (kind, richness) = match terrain {
+        Terrain::Plains => ("plains", 0),
+        Terrain::Rock => ("rock", 0),
+        Terrain::Water => ("water", 0),
+        Terrain::Ore { richness } => ("ore", richness),
+    };
+
+    Map::from([
+        ("kind".into(), kind.into()),
+        ("richness".into(), (richness as INT).into()),
+        ("yield".into(), (terrain.mine_yield() as INT).into()),
+    ])
+}
+
+fn rejection_map(rejection: &Rejection) -> Map {
+    let (kind, col, row, amount) = match rejection.intent {
+        Intent::SetTradeValue(value) => ("set_trade_value", 0, 0, value as INT),
+        Intent::Upgrade => ("upgrade", 0, 0, 0),
+        Intent::Transfer { col, row, amount } => ("transfer", col, row, amount as INT),
+        Intent::Request { col, row, amount } => ("request", col, row, amount as INT),
+        Intent::Fire { col, row } => ("fire", col, row, 0),
+        Intent::Send {
+            col,
+            row,
+            ref payload,
+        } => ("message", col, row, payload.len() as INT),
+    };
+
+    let reason = match rejection.reason {
+        RejectReason::OutOfRange => "out_of_range",
+        RejectReason::WrongTile => "wrong_tile",
+        RejectReason::NoGold => "no_gold",
+        RejectReason::NoRoom => "no_room",
+        RejectReason::NotOffered => "not_offered",
+        RejectReason::TargetGone => "target_gone",
+        RejectReason::AlreadyFired => "already_fired",
+        RejectReason::Quota => "quota",
+        RejectReason::InboxFull => "inbox_full",
+        RejectReason::OutOfPhase => "out_of_phase",
+    };
+
+    Map::from([
+        ("kind".into(), kind.into()),
+        ("col".into(), (col as INT).into()),
+        ("row".into(), (row as INT).into()),
+        ("amount".into(), amount.into()),
+        ("reason".into(), reason.into()),
+    ])
+}
+
+// anything negative or too large is off the map
+fn coord(value: INT) -> u32 {
+    u32::try_from(value).unwrap_or(u32::MAX)
+}
+
+fn amount(value: INT) -> u32 {
+    u32::try_from(value.max(0)).unwrap_or(u32::MAX)
+}
+
+// what one call has access to, handed to every function through the call's tag
+struct RhaiCall {
+    host: HostState,
+}
+
+type SharedCall = Arc<Mutex<RhaiCall>>;
+
+fn with_call<T>(ctx: &NativeCallContext, f: impl FnOnce(&mut RhaiCall) -> T) -> RhaiResult<T> {
+    let call = ctx
+        .tag()
+        .and_then(|tag| tag.clone().try_cast::<SharedCall>())
+        .ok_or("only available while a tile's script runs")?;
+
+    let mut call = call.lock().unwrap();
+
+    Ok(f(&mut call))
+}
+
+// the engine every rhai script runs on, with the api registered and the same
+// limits wasm scripts get
+pub fn engine() -> Engine {
+    let mut engine = Engine::new();
+
+    engine
+        .set_max_operations(SCRIPT_FUEL)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_EXPR_DEPTH)
+        .set_max_string_size(MAX_STATE_BYTES)
+        .set_max_array_size(MAX_COLLECTION_SIZE)
+        .set_max_map_size(MAX_COLLECTION_SIZE)
+        .set_module_resolver(DummyModuleResolver::new())
+        .disable_symbol("eval")
+        // nowhere to print to, scripts log instead
+        .on_print(|_| {})
+        .on_debug(|_, _, _| {})
+        .on_progress(|operations| {
+            OPERATIONS.set(operations);
+            None
+        });
+
+    engine
+        .register_fn("position", |ctx: NativeCallContext| {
+            with_call(&ctx, |call| {
+                let (col, row) = call.host.view.position();
+
+                Array::from([(col as INT).into(), (row as INT).into()])
+            })
+        })
+        .register_fn("tick", |ctx: NativeCallContext| {
+            with_call(&ctx, |call| call.host.view.tick() as INT)
+        })
+        .register_fn("tile", |ctx: NativeCallContext, col: INT, row: INT| {
+            with_call(&ctx, |call| {
+                let tile = call.host.view.tile(coord(col), coord(row));
+
+                found(tile, |tile| tile_map(tile).into())
+            })
+        })
+        .register_fn("terrain", |ctx: NativeCallContext, col: INT, row: INT| {
+            with_call(&ctx, |call| {
+                let terrain = call.host.view.terrain(coord(col), coord(row));
+
+                found(terrain, |terrain| terrain_map(terrain).into())
+            })
+        })
+        // [col, row] of the closest tile of `kind` within `range` steps
+        .register_fn(
+            "nearest",
+            |ctx: NativeCallContext, range: INT, kind: ImmutableString| {
+                with_call(&ctx, |call| {
+                    let range = u32::try_from(range.max(0)).unwrap_or(u32::MAX);
+                    let nearest = TileKind::from_name(&kind)
+                        .and_then(|kind| call.host.view.nearest(range, kind));
+
+                    found(nearest, |(col, row)| {
+                        Array::from([(col as INT).into(), (row as INT).into()]).into()
+                    })
+                })
+            },
+        )
+        .register_fn("rejected", |ctx: NativeCallContext| {
+            with_call(&ctx, |call| {
+                call.host
+                    .rejected
+                    .iter()
+                    .map(|rejection| rejection_map(rejection).into())
+                    .collect::<Array>()
+            })
+        })
+        // [#{col, row, text}], text decoded lossily if it isn't utf-8
+        .register_fn("inbox", |ctx: NativeCallContext| {
+            with_call(&ctx, |call| {
+                call.host
+                    .inbox
+                    .iter()
+                    .map(|message| {
+                        Map::from([
+                            ("col".into(), (message.col as INT).into()),
+                            ("row".into(), (message.row as INT).into()),
+                            (
+                                "text".into(),
+                                String::from_utf8_lossy(&message.payload)
+                                    .into_owned()
+                                    .into(),
+                            ),
+                        ])
+                        .into()
+                    })
+                    .collect::<Array>()
+            })
+        })
+        .register_fn(
+            "message",
+            |ctx: NativeCallContext, col: INT, row: INT, text: ImmutableString| {
+                with_call(&ctx, |call| {
+                    text.len() <= MAX_MESSAGE_BYTES
+                        && call.host.push_intent(Intent::Send {
+                            col: coord(col),
+                            row: coord(row),
+                            payload: text.as_bytes().to_vec(),
+                        })
+                })
+            },
+        )
+        .register_fn(
+            "state_get",
+            |ctx: NativeCallContext, key: ImmutableString| {
+                with_call(&ctx, |call| {
+                    call.host
+                        .state
+                        .get(&key)
+                        .map_or(Dynamic::UNIT, |value| value.to_string().into())
+                })
+            },
+        )
+        // false if the key is invalid or the state would be over quota
+        .register_fn(
+            "state_set",
+            |ctx: NativeCallContext, key: ImmutableString, value: ImmutableString| {
+                with_call(&ctx, |call| call.host.state.set(&key, &value).is_ok())
+            },
+        )
+        .register_fn(
+            "state_remove",
+            |ctx: NativeCallContext, key: ImmutableString| {
+                with_call(&ctx, |call| call.host.state.remove(&key).is_some())
+            },
+        )
+        .register_fn("state_version", |ctx: NativeCallContext| {
+            with_call(&ctx, |call| call.host.state.version as INT)
+        })
+        .register_fn(
+            "set_state_version",
+            |ctx: NativeCallContext, version: INT| {
+                with_call(&ctx, |call| call.host.state.version = version as u32)
+            },
+        )
+        // level is "debug", "info", "warn" or "error"
+        .register_fn(
+            "log",
+            |ctx: NativeCallContext, level: ImmutableString, message: ImmutableString| {
+                let level = match level.as_str() {
+                    "debug" => LogLevel::Debug,
+                    "info" => LogLevel::Info,
+                    "warn" => LogLevel::Warn,
+                    "error" => LogLevel::Error,
+                    _ => return Err(format!("invalid log level {level:?}").into()),
+                };
+
+                with_call(&ctx, |call| call.host.log(level, message.as_bytes()))
+            },
+        )
+        .register_fn("mine", |ctx: NativeCallContext| {
+            with_call(&ctx, |call| {
+                found(call.host.view.own(TileKind::Mine), |tile| {
+                    tile_map(tile).into()
+                })
+            })
+        })
+        .register_fn("yield_per_tick", |ctx: NativeCallContext| {
+            with_call(&ctx, |call| call.host.view.yield_per_tick() as INT)
+        })
+        .register_fn("set_trade_value", |ctx: NativeCallContext, value: INT| {
+            with_call(&ctx, |call| {
+                let value = value.clamp(i32::MIN.into(), i32::MAX.into()) as i32;
+
+                call.host.push_intent(Intent::SetTradeValue(value))
+            })
+        })
+        .register_fn("upgrade", |ctx: NativeCallContext| {
+            with_call(&ctx, |call| call.host.push_intent(Intent::Upgrade))
+        })
+        .register_fn(
+            "send",
+            |ctx: NativeCallContext, col: INT, row: INT, gold: INT| {
+                with_call(&ctx, |call| {
+                    call.host.push_intent(Intent::Transfer {
+                        col: coord(col),
+                        row: coord(row),
+                        amount: amount(gold),
+                    })
+                })
+            },
+        )
+        .register_fn(
+            "request",
+            |ctx: NativeCallContext, col: INT, row: INT, gold: INT| {
+                with_call(&ctx, |call| {
+                    call.host.push_intent(Intent::Request {
+                        col: coord(col),
+                        row: coord(row),
+                        amount: amount(gold),
+                    })
+                })
+            },
+        )
+        .register_fn("turret", |ctx: NativeCallContext| {
+            with_call(&ctx, |call| {
+                found(call.host.view.own(TileKind::Turret), |tile| {
+                    tile_map(tile).into()
+                })
+            })
+        })
+        .register_fn("fire_at", |ctx: NativeCallContext, col: INT, row: INT| {
+            with_call(&ctx, |call| {
+                call.host.push_intent(Intent::Fire {
+                    col: coord(col),
+                    row: coord(row),
+                })
+            })
+        });
+
+    engine
+}
+
+// parses `bytes` and checks it defines at least the entry point for `role`
+pub fn compile(engine: &Engine, role: ScriptRole, bytes: &[u8]) -> Result<Script, ScriptError> {
+    let source = std::str::from_utf8(bytes).map_err(|e| ScriptError::Syntax(e.to_string()))?;
+    let ast = engine
+        .compile(source)
+        .map_err(|e| ScriptError::Syntax(e.to_string()))?;
+
+    // None if there's no such function, otherwise whether there's one
+    // without parameters
+    let unit_fn = |name: &str| {
+        ast.iter_functions()
+            .filter(|f| f.name == name)
+            .fold(None, |found, f| {
+                Some(found.unwrap_or(false) || f.params.is_empty())
+            })
+    };
+
+    let mut roles = Vec::new();
+
+    for candidate in ScriptRole::ALL {
+        match unit_fn(candidate.entry_point()) {
+            None => continue,
+            Some(false) => return Err(ScriptError::BadEntryPoint(candidate)),
+            Some(true) => roles.push(candidate),
+        }
+    }
+
+    if !roles.contains(&role) {
+        return Err(ScriptError::MissingEntryPoint(role));
+    }
+
+    let migrates = match unit_fn(super::script_host::MIGRATE_EXPORT) {
+        Some(false) => return Err(ScriptError::BadMigrate),
+        found => found.is_some(),
+    };
+
+    Ok(Script {
+        id: script_id(bytes),
+        roles,
+        migrates,
+        code: ScriptCode::Rhai(ast),
+    })
+}
+
+impl From<Box<EvalAltResult>> for RunError {
+    fn from(e: Box<EvalAltResult>) -> Self {
+        match e.unwrap_inner() {
+            EvalAltResult::ErrorTooManyOperations(_) => Self::OutOfFuel,
+            _ => Self::Trap(e.to_string()),
+        }
+    }
+}
+
+// calls `export` of `ast` once, like script_host::run does for wasm
+pub fn run(
+    engine: &Engine,
+    ast: &AST,
+    export: &str,
+    host: HostState,
+) -> (HostState, Result<(), RunError>) {
+    let call = Arc::new(Mutex::new(RhaiCall { host }));
+
+    let options = CallFnOptions::new().eval_ast(false).with_tag(call.clone());
+
+    OPERATIONS.set(0);
+
+    let result = engine
+        .call_fn_with_options::<Dynamic>(options, &mut Scope::new(), ast, export, ())
+        .map(drop)
+        .map_err(RunError::from);
+
+    let mut host = std::mem::replace(
+        &mut call.lock().unwrap().host,
+        HostState::new(TileStore::default()),
+    );
+    host.cost.fuel = OPERATIONS.get();
+
+    (host, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{
+            grid_api::GridState,
+            script_host::SCRIPT_FUEL,
+            scripts::{ScriptLanguage, ScriptStore},
+        },
+        types::{HexTile, tests::mine},
+    };
+
+    use super::*;
+
+    fn upload(grid: &mut GridState, source: &str) -> String {
+        grid.scripts
+            .upload_as(ScriptLanguage::Rhai, ScriptRole::Mine, source.as_bytes())
+            .unwrap()
+    }
+
+    fn state(grid: &GridState, x: u32, y: u32) -> TileStore {
+        grid.get_tile(x, y).unwrap().store().unwrap().clone()
+    }
+
+    #[test]
+    fn it_runs_rhai_scripts_through_the_same_api() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+        grid.set_tile(2, 2, mine(4, 10, 0));
+        grid.set_tile(4, 2, HexTile::Slime);
+        grid.set_terrain(2, 2, Terrain::Ore { richness: 2 });
+
+        let id = upload(
+            &mut grid,
+            r#"
+                state_set("top", "level code never runs");
+
+                fn mine_tick() {
+                    let slime = nearest(6, "slime");
+                    state_set("slime", `${slime[0]},${slime[1]}`);
+                    state_set("gold", `${mine().count}+${yield_per_tick()}`);
+
+                    for rejection in rejected() {
+                        state_set("rejected", rejection.kind + " " + rejection.reason);
+                    }
+
+                    log("info", "tick " + tick());
+                }
+
+                fn logistics_tick() {
+                    send(50, 50, 1);
+                }
+            "#,
+        );
+        grid.assign_script(2, 2, &id, "ada").unwrap();
+
+        grid.run_scripts();
+        grid.run_scripts();
+
+        let state = state(&grid, 2, 2);
+        assert_eq!(state.get("top"), None);
+        assert_eq!(state.get("slime"), Some("4,2"));
+        assert_eq!(state.get("gold"), Some("4+2"));
+        assert_eq!(state.get("rejected"), Some("transfer out_of_range"));
+
+        assert_eq!(grid.script_logs.len(), 1);
+        assert_eq!(grid.script_logs[0].message, "tick 0");
+        assert_eq!(grid.script_logs[0].owner, "ada");
+    }
+
+    #[test]
+    fn it_gives_rhai_the_same_fuel() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.set_tile(1, 1, mine(0, 10, 0));
+
+        let id = upload(&mut grid, "fn mine_tick() { loop { state_version(); } }");
+        grid.assign_script(1, 1, &id, "ada").unwrap();
+
+        grid.run_scripts();
+
+        let last = grid.script_logs.last().unwrap();
+        assert_eq!(last.level, LogLevel::Error);
+        assert_eq!(last.message, RunError::OutOfFuel.to_string());
+        assert!(last.message.contains(&SCRIPT_FUEL.to_string()));
+
+        // operations count as fuel in the profile too
+        let profile = grid.profiler.get(grid.get_index(1, 1)).unwrap();
+        assert_eq!(profile.last_fuel, SCRIPT_FUEL);
+    }
+
+    #[test]
+    fn it_validates_rhai_uploads() {
+        let mut store = ScriptStore::new();
+        let mut upload = |source: &str| {
+            store.upload_as(ScriptLanguage::Rhai, ScriptRole::Mine, source.as_bytes())
+        };
+
+        let id = upload("fn mine_tick() {} fn defender_tick() {} fn migrate() {}").unwrap();
+        let script = store.get(&id).unwrap();
+
+        assert_eq!(script.roles, vec![ScriptRole::Mine, ScriptRole::Defender]);
+        assert!(script.migrates);
+        assert_eq!(script.language(), ScriptLanguage::Rhai);
+
+        let mut upload = |source: &str| {
+            store
+                .upload_as(ScriptLanguage::Rhai, ScriptRole::Mine, source.as_bytes())
+                .map(|_| ())
+        };
+
+        assert!(matches!(
+            upload("fn mine_tick( {"),
+            Err(ScriptError::Syntax(_))
+        ));
+        assert_eq!(
+            upload("fn logistics_tick() {}"),
+            Err(ScriptError::MissingEntryPoint(ScriptRole::Mine))
+        );
+        assert_eq!(
+            upload("fn mine_tick(x) {}"),
+            Err(ScriptError::BadEntryPoint(ScriptRole::Mine))
+        );
+        assert_eq!(
+            upload("fn mine_tick() {} fn migrate(old) {}"),
+            Err(ScriptError::BadMigrate)
+        );
+    }
+}
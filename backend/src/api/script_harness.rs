@@ -0,0 +1,285 @@
+// offline harness for player scripts: a small world built from a text map, the
+// real tick logic and script host, and no server. meant to be driven from a
+// bot's own `cargo test`, or through plu-script-test
+//
+// maps have one line per row and one character per tile:
+//   .  wild
+//   s  slime
+//   m  mine (level 1, empty, room for 10 gold)
+//   t  turret (level 1)
+// leading/trailing whitespace and blank lines are ignored, every row has to be
+// equally long. the terrain underneath is plains, change it through `grid`
+
+use std::fmt::Display;
+
+use crate::{
+    api::{
+        grid_api::GridState,
+        script_host::ScriptLog,
+        scripts::{ScriptError, ScriptLanguage, ScriptRole},
+        simulation,
+        tile_store::TileStore,
+    },
+    types::{HexTile, MineData, TurretData},
+};
+
+// who scripts run on behalf of, as far as the harness is concerned
+pub const HARNESS_PLAYER: &str = "tester";
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum MapError {
+    Empty,
+    Ragged {
+        row: usize,
+        expected: usize,
+        found: usize,
+    },
+    UnknownTile {
+        col: usize,
+        row: usize,
+        found: char,
+    },
+}
+
+impl Display for MapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "map has no tiles"),
+            Self::Ragged {
+                row,
+                expected,
+                found,
+            } => write!(f, "row {row} is {found} tiles wide, expected {expected}"),
+            Self::UnknownTile { col, row, found } => {
+                write!(f, "unknown tile {found:?} at <{col}, {row}>")
+            }
+        }
+    }
+}
+
+fn parse_tile(c: char) -> Option<HexTile> {
+    match c {
+        '.' => Some(HexTile::Wild),
+        's' => Some(HexTile::Slime),
+        'm' => Some(HexTile::Mine(MineData {
+            level: 1,
+            count: 0,
+            capacity: 10,
+            state: TileStore::default(),
+            trade_value: 0,
+        })),
+        't' => Some(HexTile::Turret(TurretData {
+            level: 1,
+            state: TileStore::default(),
+        })),
+        _ => None,
+    }
+}
+
+fn tile_char(tile: &HexTile) -> char {
+    match tile {
+        HexTile::Wild => '.',
+        HexTile::Slime => 's',
+        HexTile::Mine(_) => 'm',
+        HexTile::Turret(_) => 't',
+    }
+}
+
+pub fn parse_map(text: &str) -> Result<GridState, MapError> {
+    let rows = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+
+    let width = rows.first().map_or(0, |row| row.chars().count());
+
+    if width == 0 {
+        return Err(MapError::Empty);
+    }
+
+    let mut grid = GridState::new(width, rows.len(), HexTile::Wild);
+
+    for (y, row) in rows.iter().enumerate() {
+        let found = row.chars().count();
+
+        if found != width {
+            return Err(MapError::Ragged {
+                row: y,
+                expected: width,
+                found,
+            });
+        }
+
+        for (x, c) in row.chars().enumerate() {
+            let tile = parse_tile(c).ok_or(MapError::UnknownTile {
+                col: x,
+                row: y,
+                found: c,
+            })?;
+
+            if tile != HexTile::Wild {
+                grid.set_tile(x as u32, y as u32, tile);
+            }
+        }
+    }
+
+    Ok(grid)
+}
+
+// the tiles of `grid` in the format parse_map reads, one line per row
+pub fn render_map(grid: &GridState) -> String {
+    let mut map = String::with_capacity((grid.width + 1) * grid.height);
+
+    for y in 0..grid.height as u32 {
+        for x in 0..grid.width as u32 {
+            map.push(tile_char(grid.tiles.get(x, y)));
+        }
+
+        map.push('\n');
+    }
+
+    map
+}
+
+pub struct Harness {
+    pub grid: GridState,
+    // everything logged since the harness was created, oldest first
+    pub logs: Vec<ScriptLog>,
+}
+
+impl Harness {
+    pub fn new(map: &str) -> Result<Self, MapError> {
+        Ok(Harness {
+            grid: parse_map(map)?,
+            logs: Vec::new(),
+        })
+    }
+
+    // validates `bytes` the same way uploads are, returning the script's id
+    pub fn load(
+        &mut self,
+        language: ScriptLanguage,
+        role: ScriptRole,
+        bytes: &[u8],
+    ) -> Result<String, ScriptError> {
+        self.grid.scripts.upload_as(language, role, bytes)
+    }
+
+    pub fn assign(&mut self, col: u32, row: u32, script_id: &str) -> Result<(), ScriptError> {
+        self.grid.assign_script(col, row, script_id, HARNESS_PLAYER)
+    }
+
+    // assigns the script to every tile it can run on, returning how many
+    pub fn assign_everywhere(&mut self, script_id: &str) -> Result<usize, ScriptError> {
+        let script = self
+            .grid
+            .scripts
+            .get(script_id)
+            .ok_or_else(|| ScriptError::UnknownScript(script_id.to_string()))?
+            .clone();
+
+        let fitting = (0..self.grid.height as u32)
+            .flat_map(|y| (0..self.grid.width as u32).map(move |x| (x, y)))
+            .filter(|&(x, y)| script.fits(self.grid.tiles.get(x, y)))
+            .collect::<Vec<_>>();
+
+        for &(x, y) in &fitting {
+            self.assign(x, y, script_id)?;
+        }
+
+        Ok(fitting.len())
+    }
+
+    // runs `ticks` full simulation ticks, like the server's game loop would
+    pub fn run(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            simulation::tick(&mut self.grid);
+            self.logs.append(&mut self.grid.script_logs);
+        }
+    }
+
+    pub fn tile(&self, col: u32, row: u32) -> Option<&HexTile> {
+        self.grid.get_tile(col, row)
+    }
+
+    // script state of a mine/turret
+    pub fn state(&self, col: u32, row: u32) -> Option<&TileStore> {
+        self.tile(col, row).and_then(HexTile::store)
+    }
+
+    pub fn map(&self) -> String {
+        render_map(&self.grid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::script_host::{
+        LogLevel,
+        tests::{log, script, set},
+    };
+
+    use super::*;
+
+    const MAP: &str = "
+        ....
+        .m.t
+        ...s
+    ";
+
+    #[test]
+    fn it_round_trips_maps() {
+        let harness = Harness::new(MAP).unwrap();
+
+        assert_eq!((harness.grid.width, harness.grid.height), (4, 3));
+        assert!(matches!(harness.tile(1, 1), Some(HexTile::Mine(_))));
+        assert_eq!(harness.map(), "....\n.m.t\n...s\n");
+
+        assert_eq!(parse_map("\n  \n").err(), Some(MapError::Empty));
+        assert_eq!(
+            parse_map("..\n...").err(),
+            Some(MapError::Ragged {
+                row: 1,
+                expected: 2,
+                found: 3
+            })
+        );
+        assert_eq!(
+            parse_map("..\n.x").err(),
+            Some(MapError::UnknownTile {
+                col: 1,
+                row: 1,
+                found: 'x'
+            })
+        );
+    }
+
+    #[test]
+    fn it_runs_scripts_through_the_simulation() {
+        let mut harness = Harness::new(MAP).unwrap();
+
+        let bytes = script(
+            b"seenyes",
+            &[("mine_tick", [set((0, 4), (4, 3)), log(1, (0, 4))].concat())],
+        );
+
+        let id = harness
+            .load(ScriptLanguage::Wasm, ScriptRole::Mine, &bytes)
+            .unwrap();
+        assert_eq!(harness.assign_everywhere(&id), Ok(1));
+
+        harness.run(3);
+
+        assert_eq!(harness.grid.tick, 3);
+        assert_eq!(harness.state(1, 1).unwrap().get("seen"), Some("yes"));
+
+        assert_eq!(harness.logs.len(), 3);
+        assert!(
+            harness
+                .logs
+                .iter()
+                .all(|l| l.owner == HARNESS_PLAYER && l.level == LogLevel::Info)
+        );
+    }
+}
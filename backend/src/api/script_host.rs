@@ -0,0 +1,1935 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    time::Instant,
+};
+
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+use wasmi::{
+    Caller, Engine, Error, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    core::TrapCode,
+};
+
+use crate::{
+    api::{
+        action_log::Actor,
+        game::{
+            Intent, MAX_INTENTS, MAX_MESSAGE_BYTES, Message, NextTick, Outcome, Rejection,
+            VIEW_RANGE,
+        },
+        grid_api::GridState,
+        journal::JournalRecord,
+        profiler::ScriptCost,
+        rhai_host,
+        scripts::{HOST_MODULE, Script, ScriptAssignment, ScriptCode, ScriptRole},
+        tile_store::{MAX_STATE_BYTES, TileStore},
+    },
+    types::{HexTile, Terrain},
+};
+
+// instructions (roughly) one script gets per call
+pub const SCRIPT_FUEL: u64 = 1_000_000;
+pub const MAX_SCRIPT_MEMORY: usize = 16 << 20;
+
+// optional export, called once with the old version's state when a tile
+// switches to a new version of its script
+pub const MIGRATE_EXPORT: &str = "migrate";
+
+// lines a script may log per call, anything past that is dropped. each line
+// gets cut off after MAX_LOG_BYTES
+pub const MAX_LOG_LINES: usize = 16;
+pub const MAX_LOG_BYTES: usize = 256;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/types/types.ts")]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    // the `level` argument of the `log` import
+    pub(crate) fn from_i32(level: i32) -> Option<Self> {
+        match level {
+            0 => Some(Self::Debug),
+            1 => Some(Self::Info),
+            2 => Some(Self::Warn),
+            3 => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+// one line of output from a tile's script (or about it, for traps), meant
+// for the player who assigned it
+#[derive(PartialEq, Debug, Clone)]
+pub struct ScriptLog {
+    pub owner: String,
+    pub col: u32,
+    pub row: u32,
+    pub tick: u64,
+    pub level: LogLevel,
+    pub message: String,
+}
+
+// what a running script has access to
+pub struct HostState {
+    // the tile's own state; only written back if the call succeeds
+    pub state: TileStore,
+    // whatever it logged, kept even if it fails afterwards
+    pub logs: Vec<(LogLevel, String)>,
+    // what it wants done; dropped if it fails
+    pub intents: Vec<Intent>,
+    // what the tile's conductor didn't get last tick
+    pub rejected: Vec<Rejection>,
+    // what was sent to it last tick
+    pub inbox: Vec<Message>,
+    // the world around it
+    pub view: View,
+    // fuel and memory the call used, filled in once it returns
+    pub cost: ScriptCost,
+    pub(crate) limits: StoreLimits,
+}
+
+impl HostState {
+    pub fn new(state: TileStore) -> Self {
+        HostState {
+            state,
+            logs: Vec::new(),
+            intents: Vec::new(),
+            rejected: Vec::new(),
+            inbox: Vec::new(),
+            view: View::default(),
+            cost: ScriptCost::default(),
+            limits: StoreLimitsBuilder::new()
+                .memory_size(MAX_SCRIPT_MEMORY)
+                .build(),
+        }
+    }
+
+    // false once MAX_INTENTS are queued
+    pub fn push_intent(&mut self, intent: Intent) -> bool {
+        if self.intents.len() >= MAX_INTENTS {
+            return false;
+        }
+
+        self.intents.push(intent);
+        true
+    }
+
+    // anything past MAX_LOG_LINES is dropped, and every line cut off after
+    // MAX_LOG_BYTES. that may split a character, so no strict utf-8 here
+    pub fn log(&mut self, level: LogLevel, message: &[u8]) {
+        if self.logs.len() < MAX_LOG_LINES {
+            let message = &message[..message.len().min(MAX_LOG_BYTES)];
+
+            self.logs
+                .push((level, String::from_utf8_lossy(message).into_owned()));
+        }
+    }
+}
+
+// what a tile is, as scripts see it. the numbers are what the wasm imports
+// use
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum TileKind {
+    #[default]
+    Wild = 0,
+    Slime = 1,
+    Mine = 2,
+    Turret = 3,
+}
+
+impl TileKind {
+    const ALL: [Self; 4] = [Self::Wild, Self::Slime, Self::Mine, Self::Turret];
+
+    // what rhai scripts call it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Wild => "wild",
+            Self::Slime => "slime",
+            Self::Mine => "mine",
+            Self::Turret => "turret",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    pub(crate) fn from_i32(kind: i32) -> Option<Self> {
+        Self::ALL.into_iter().find(|&k| k as i32 == kind)
+    }
+}
+
+// a tile as scripts get to see it: what it is and its numbers (0 if it
+// doesn't have them), never its state
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct TileInfo {
+    pub kind: TileKind,
+    pub level: u32,
+    pub count: u32,
+    pub capacity: u32,
+    pub trade_value: i32,
+}
+
+impl From<&HexTile> for TileInfo {
+    fn from(tile: &HexTile) -> Self {
+        match tile {
+            HexTile::Wild => TileInfo::default(),
+            HexTile::Slime => TileInfo {
+                kind: TileKind::Slime,
+                ..TileInfo::default()
+            },
+            HexTile::Mine(mine) => TileInfo {
+                kind: TileKind::Mine,
+                level: mine.level,
+                count: mine.count,
+                capacity: mine.capacity,
+                trade_value: mine.trade_value,
+            },
+            HexTile::Turret(turret) => TileInfo {
+                kind: TileKind::Turret,
+                level: turret.level,
+                ..TileInfo::default()
+            },
+        }
+    }
+}
+
+// the neighbourhood of a tile as its script gets to see it, whichever
+// language it's in; the script side of GlobalApi. copied out of the grid up
+// front, since rhai's functions have to be 'static
+#[derive(Debug, Default)]
+pub struct View {
+    col: u32,
+    row: u32,
+    tick: u64,
+    width: u32,
+    height: u32,
+    // visible tiles that aren't wild
+    tiles: HashMap<(u32, u32), TileInfo>,
+    // visible ground that isn't plains
+    terrain: HashMap<(u32, u32), Terrain>,
+    // everything within VIEW_RANGE steps, closest (then lowest index) first
+    steps: Vec<((u32, u32), u32)>,
+}
+
+impl View {
+    pub fn position(&self) -> (u32, u32) {
+        (self.col, self.row)
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    fn visible(&self, col: u32, row: u32) -> bool {
+        col < self.width
+            && row < self.height
+            && self.col.abs_diff(col) <= VIEW_RANGE
+            && self.row.abs_diff(row) <= VIEW_RANGE
+    }
+
+    // None out of view
+    pub fn tile(&self, col: u32, row: u32) -> Option<TileInfo> {
+        self.visible(col, row)
+            .then(|| self.tiles.get(&(col, row)).copied().unwrap_or_default())
+    }
+
+    pub fn terrain(&self, col: u32, row: u32) -> Option<Terrain> {
+        self.visible(col, row).then(|| {
+            self.terrain
+                .get(&(col, row))
+                .copied()
+                .unwrap_or(Terrain::Plains)
+        })
+    }
+
+    // the closest other tile of `kind` within `range` steps
+    pub fn nearest(&self, range: u32, kind: TileKind) -> Option<(u32, u32)> {
+        self.steps
+            .iter()
+            .find(|&&((col, row), steps)| {
+                steps > 0 && steps <= range && self.tile(col, row).unwrap_or_default().kind == kind
+            })
+            .map(|&(position, _)| position)
+    }
+
+    // the own tile, if it's a `kind`
+    pub fn own(&self, kind: TileKind) -> Option<TileInfo> {
+        self.tile(self.col, self.row)
+            .filter(|tile| tile.kind == kind)
+    }
+
+    // gold the ground underneath adds every tick, if this is a mine
+    pub fn yield_per_tick(&self) -> u32 {
+        self.terrain(self.col, self.row)
+            .map_or(0, |terrain| terrain.mine_yield())
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum RunError {
+    // couldn't even be instantiated
+    Link(String),
+    Trap(String),
+    OutOfFuel,
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Link(e) => write!(f, "failed to instantiate: {e}"),
+            Self::Trap(e) => write!(f, "trapped: {e}"),
+            Self::OutOfFuel => write!(f, "ran out of fuel ({SCRIPT_FUEL})"),
+        }
+    }
+}
+
+impl From<Error> for RunError {
+    fn from(e: Error) -> Self {
+        match e.as_trap_code() {
+            Some(TrapCode::OutOfFuel) => Self::OutOfFuel,
+            _ => Self::Trap(e.to_string()),
+        }
+    }
+}
+
+fn memory(caller: &Caller<'_, HostState>) -> Result<Memory, Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Error::new("script has to export its memory as `memory`"))
+}
+
+// nothing a script passes us is ever longer than what a tile can store
+fn read_bytes(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Result<Vec<u8>, Error> {
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|&len| len <= MAX_STATE_BYTES)
+        .ok_or_else(|| Error::new(format!("invalid string length {len}")))?;
+
+    let mut bytes = vec![0; len];
+    memory(caller)?
+        .read(caller, ptr as u32 as usize, &mut bytes)
+        .map_err(|e| Error::new(e.to_string()))?;
+
+    Ok(bytes)
+}
+
+fn read_string(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Result<String, Error> {
+    String::from_utf8(read_bytes(caller, ptr, len)?).map_err(|e| Error::new(e.to_string()))
+}
+
+// `values` as little endian i32s at `ptr`
+fn write_i32s(caller: &mut Caller<'_, HostState>, ptr: i32, values: &[i32]) -> Result<(), Error> {
+    let bytes = values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect::<Vec<_>>();
+
+    memory(caller)?
+        .write(caller, ptr as u32 as usize, &bytes)
+        .map_err(|e| Error::new(e.to_string()))
+}
+
+// how the `tile`, `mine` and `turret` imports hand a tile to scripts: kind
+// (see TileKind), level, count, capacity and trade value
+fn encode_tile(tile: TileInfo) -> [i32; 5] {
+    [
+        tile.kind as i32,
+        tile.level as i32,
+        tile.count as i32,
+        tile.capacity as i32,
+        tile.trade_value,
+    ]
+}
+
+// and the `terrain` import the ground: kind (0 plains, 1 rock, 2 water, 3
+// ore), richness and what a mine on it yields
+fn encode_terrain(terrain: Terrain) -> [i32; 3] {
+    let (kind, richness) = match terrain {
+        Terrain::Plains => (0, 0),
+        Terrain::Rock => (1, 0),
+        Terrain::Water => (2, 0),
+        Terrain::Ore { richness } => (3, richness as i32),
+    };
+
+    [kind, richness, terrain.mine_yield() as i32]
+}
+
+// writes what `read` found to `out_ptr` and returns 0, or -1 if it found
+// nothing
+fn write_found<const N: usize>(
+    caller: &mut Caller<'_, HostState>,
+    out_ptr: i32,
+    read: impl FnOnce(&View) -> Option<[i32; N]>,
+) -> Result<i32, Error> {
+    match read(&caller.data().view) {
+        Some(values) => write_i32s(caller, out_ptr, &values).map(|()| 0),
+        None => Ok(-1),
+    }
+}
+
+// 0 if queued, -1 once MAX_INTENTS are
+fn push_intent(caller: &mut Caller<'_, HostState>, intent: Intent) -> i32 {
+    if caller.data_mut().push_intent(intent) {
+        0
+    } else {
+        -1
+    }
+}
+
+// how the `rejected` import hands a rejection to scripts: the intent as
+// (kind, col, row, amount) and then why. kinds are 0 set_trade_value (value
+// as the amount), 1 upgrade, 2 transfer, 3 request, 4 fire, 5 message
+// (payload length as the amount); unused fields are 0
+fn encode_rejection(rejection: &Rejection) -> [i32; 5] {
+    let [kind, col, row, amount] = match rejection.intent {
+        Intent::SetTradeValue(value) => [0, 0, 0, value],
+        Intent::Upgrade => [1, 0, 0, 0],
+        Intent::Transfer { col, row, amount } => [2, col as i32, row as i32, amount as i32],
+        Intent::Request { col, row, amount } => [3, col as i32, row as i32, amount as i32],
+        Intent::Fire { col, row } => [4, col as i32, row as i32, 0],
+        Intent::Send {
+            col,
+            row,
+            ref payload,
+        } => [5, col as i32, row as i32, payload.len() as i32],
+    };
+
+    [kind, col, row, amount, rejection.reason as i32]
+}
+
+// the `plu` module every script imports from. strings are passed as
+// (pointer, length) into the script's exported `memory`
+pub fn linker(engine: &Engine) -> Linker<HostState> {
+    let mut linker = Linker::new(engine);
+
+    linker
+        // the world around the tile, as it was when the phase started. tiles
+        // further than VIEW_RANGE away (or off the map) aren't visible
+        //
+        // (col, row) of the own tile, as two little endian i32s at `out_ptr`
+        .func_wrap(
+            HOST_MODULE,
+            "position",
+            |mut caller: Caller<'_, HostState>, out_ptr: i32| -> Result<(), Error> {
+                let (col, row) = caller.data().view.position();
+
+                write_i32s(&mut caller, out_ptr, &[col as i32, row as i32])
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            HOST_MODULE,
+            "tick",
+            |caller: Caller<'_, HostState>| -> i64 { caller.data().view.tick() as i64 },
+        )
+        .unwrap()
+        // writes the tile as five little endian i32s (see encode_tile) to
+        // `out_ptr`, returning 0, or -1 if it isn't visible
+        .func_wrap(
+            HOST_MODULE,
+            "tile",
+            |mut caller: Caller<'_, HostState>, col: i32, row: i32, out_ptr: i32| {
+                write_found(&mut caller, out_ptr, |view| {
+                    view.tile(col as u32, row as u32).map(encode_tile)
+                })
+            },
+        )
+        .unwrap()
+        // the same with three i32s, see encode_terrain
+        .func_wrap(
+            HOST_MODULE,
+            "terrain",
+            |mut caller: Caller<'_, HostState>, col: i32, row: i32, out_ptr: i32| {
+                write_found(&mut caller, out_ptr, |view| {
+                    view.terrain(col as u32, row as u32).map(encode_terrain)
+                })
+            },
+        )
+        .unwrap()
+        // (col, row) of the closest tile of `kind` (see TileKind) within
+        // `range` steps to `out_ptr`, returning 0, or -1 if there's none
+        .func_wrap(
+            HOST_MODULE,
+            "nearest",
+            |mut caller: Caller<'_, HostState>, range: i32, kind: i32, out_ptr: i32| {
+                write_found(&mut caller, out_ptr, |view| {
+                    let kind = TileKind::from_i32(kind)?;
+                    let (col, row) = view.nearest(range.max(0) as u32, kind)?;
+
+                    Some([col as i32, row as i32])
+                })
+            },
+        )
+        .unwrap()
+        // the own tile like `tile`, -1 if it isn't a mine
+        .func_wrap(
+            HOST_MODULE,
+            "mine",
+            |mut caller: Caller<'_, HostState>, out_ptr: i32| {
+                write_found(&mut caller, out_ptr, |view| {
+                    view.own(TileKind::Mine).map(encode_tile)
+                })
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            HOST_MODULE,
+            "yield_per_tick",
+            |caller: Caller<'_, HostState>| -> i32 { caller.data().view.yield_per_tick() as i32 },
+        )
+        .unwrap()
+        // and -1 if it isn't a turret
+        .func_wrap(
+            HOST_MODULE,
+            "turret",
+            |mut caller: Caller<'_, HostState>, out_ptr: i32| {
+                write_found(&mut caller, out_ptr, |view| {
+                    view.own(TileKind::Turret).map(encode_tile)
+                })
+            },
+        )
+        .unwrap()
+        // copies up to `out_len` bytes of the value into `out_ptr`, returning
+        // its full length or -1 if there's no such key
+        .func_wrap(
+            HOST_MODULE,
+            "state_get",
+            |mut caller: Caller<'_, HostState>,
+             key_ptr: i32,
+             key_len: i32,
+             out_ptr: i32,
+             out_len: i32|
+             -> Result<i32, Error> {
+                let key = read_string(&caller, key_ptr, key_len)?;
+
+                let Some(value) = caller.data().state.get(&key).map(str::to_owned) else {
+                    return Ok(-1);
+                };
+
+                let n = value.len().min(out_len.max(0) as usize);
+                memory(&caller)?
+                    .write(&mut caller, out_ptr as u32 as usize, &value.as_bytes()[..n])
+                    .map_err(|e| Error::new(e.to_string()))?;
+
+                Ok(value.len() as i32)
+            },
+        )
+        .unwrap()
+        // 0 if stored, -1 if the key is invalid or the state would be over quota
+        .func_wrap(
+            HOST_MODULE,
+            "state_set",
+            |mut caller: Caller<'_, HostState>,
+             key_ptr: i32,
+             key_len: i32,
+             value_ptr: i32,
+             value_len: i32|
+             -> Result<i32, Error> {
+                let key = read_string(&caller, key_ptr, key_len)?;
+                let value = read_string(&caller, value_ptr, value_len)?;
+
+                Ok(match caller.data_mut().state.set(&key, &value) {
+                    Ok(()) => 0,
+                    Err(_) => -1,
+                })
+            },
+        )
+        .unwrap()
+        // 1 if there was something to remove
+        .func_wrap(
+            HOST_MODULE,
+            "state_remove",
+            |mut caller: Caller<'_, HostState>, key_ptr: i32, key_len: i32| -> Result<i32, Error> {
+                let key = read_string(&caller, key_ptr, key_len)?;
+
+                Ok(caller.data_mut().state.remove(&key).is_some() as i32)
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            HOST_MODULE,
+            "state_version",
+            |caller: Caller<'_, HostState>| -> i32 { caller.data().state.version as i32 },
+        )
+        .unwrap()
+        .func_wrap(
+            HOST_MODULE,
+            "set_state_version",
+            |mut caller: Caller<'_, HostState>, version: i32| {
+                caller.data_mut().state.version = version as u32;
+            },
+        )
+        .unwrap()
+        // level is 0 (debug) to 3 (error)
+        .func_wrap(
+            HOST_MODULE,
+            "log",
+            |mut caller: Caller<'_, HostState>,
+             level: i32,
+             ptr: i32,
+             len: i32|
+             -> Result<(), Error> {
+                let level = LogLevel::from_i32(level)
+                    .ok_or_else(|| Error::new(format!("invalid log level {level}")))?;
+
+                if caller.data().logs.len() >= MAX_LOG_LINES {
+                    return Ok(());
+                }
+
+                let bytes = read_bytes(&caller, ptr, len.min(MAX_LOG_BYTES as i32))?;
+                caller.data_mut().log(level, &bytes);
+
+                Ok(())
+            },
+        )
+        .unwrap()
+        // the intents below are only carried out once the whole phase has
+        // run, each returns 0 if queued or -1 if the tile already queued
+        // MAX_INTENTS. negative coordinates are off the map
+        .func_wrap(
+            HOST_MODULE,
+            "set_trade_value",
+            |mut caller: Caller<'_, HostState>, value: i32| -> i32 {
+                push_intent(&mut caller, Intent::SetTradeValue(value))
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            HOST_MODULE,
+            "upgrade",
+            |mut caller: Caller<'_, HostState>| -> i32 {
+                push_intent(&mut caller, Intent::Upgrade)
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            HOST_MODULE,
+            "transfer",
+            |mut caller: Caller<'_, HostState>, col: i32, row: i32, amount: i32| -> i32 {
+                let (col, row, amount) = (col as u32, row as u32, amount.max(0) as u32);
+
+                push_intent(&mut caller, Intent::Transfer { col, row, amount })
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            HOST_MODULE,
+            "request",
+            |mut caller: Caller<'_, HostState>, col: i32, row: i32, amount: i32| -> i32 {
+                let (col, row, amount) = (col as u32, row as u32, amount.max(0) as u32);
+
+                push_intent(&mut caller, Intent::Request { col, row, amount })
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            HOST_MODULE,
+            "fire",
+            |mut caller: Caller<'_, HostState>, col: i32, row: i32| -> i32 {
+                let (col, row) = (col as u32, row as u32);
+
+                push_intent(&mut caller, Intent::Fire { col, row })
+            },
+        )
+        .unwrap()
+        // how many intents were turned down last tick
+        .func_wrap(
+            HOST_MODULE,
+            "rejected_count",
+            |caller: Caller<'_, HostState>| -> i32 { caller.data().rejected.len() as i32 },
+        )
+        .unwrap()
+        // writes the `i`th of those to `out_ptr` as five little endian i32s
+        // (see encode_rejection), returning 0, or -1 if there's no such one
+        .func_wrap(
+            HOST_MODULE,
+            "rejected",
+            |mut caller: Caller<'_, HostState>, i: i32, out_ptr: i32| -> Result<i32, Error> {
+                let Some(rejection) = usize::try_from(i)
+                    .ok()
+                    .and_then(|i| caller.data().rejected.get(i))
+                else {
+                    return Ok(-1);
+                };
+
+                let fields = encode_rejection(rejection);
+                write_i32s(&mut caller, out_ptr, &fields)?;
+
+                Ok(0)
+            },
+        )
+        .unwrap()
+        // queues a message to the mine/turret at (col, row), see MAX_MESSAGES.
+        // 0 if queued, -1 if too many intents are, -2 if it's too long
+        .func_wrap(
+            HOST_MODULE,
+            "message",
+            |mut caller: Caller<'_, HostState>,
+             col: i32,
+             row: i32,
+             ptr: i32,
+             len: i32|
+             -> Result<i32, Error> {
+                if len as u32 as usize > MAX_MESSAGE_BYTES {
+                    return Ok(-2);
+                }
+
+                let payload = read_bytes(&caller, ptr, len)?;
+                let (col, row) = (col as u32, row as u32);
+
+                Ok(push_intent(&mut caller, Intent::Send { col, row, payload }))
+            },
+        )
+        .unwrap()
+        // how many messages arrived for this tile
+        .func_wrap(
+            HOST_MODULE,
+            "inbox_count",
+            |caller: Caller<'_, HostState>| -> i32 { caller.data().inbox.len() as i32 },
+        )
+        .unwrap()
+        // copies up to `out_len` bytes of the `i`th message into `out_ptr` and
+        // the (col, row) it came from, as two little endian i32s, into
+        // `from_ptr`. returns the message's full length, -1 if there's no
+        // such one
+        .func_wrap(
+            HOST_MODULE,
+            "inbox_read",
+            |mut caller: Caller<'_, HostState>,
+             i: i32,
+             from_ptr: i32,
+             out_ptr: i32,
+             out_len: i32|
+             -> Result<i32, Error> {
+                let Some(message) = usize::try_from(i)
+                    .ok()
+                    .and_then(|i| caller.data().inbox.get(i))
+                    .cloned()
+                else {
+                    return Ok(-1);
+                };
+
+                write_i32s(
+                    &mut caller,
+                    from_ptr,
+                    &[message.col as i32, message.row as i32],
+                )?;
+
+                let n = message.payload.len().min(out_len.max(0) as usize);
+                memory(&caller)?
+                    .write(&mut caller, out_ptr as u32 as usize, &message.payload[..n])
+                    .map_err(|e| Error::new(e.to_string()))?;
+
+                Ok(message.payload.len() as i32)
+            },
+        )
+        .unwrap();
+
+    linker
+}
+
+// a fresh instance of `module` with `host`, calling `export` on it once.
+// hands back the host state as the script left it, even if it failed
+pub fn run(
+    engine: &Engine,
+    linker: &Linker<HostState>,
+    module: &Module,
+    export: &str,
+    host: HostState,
+) -> (HostState, Result<(), RunError>) {
+    let mut store = Store::new(engine, host);
+    store.limiter(|host| &mut host.limits);
+    store.set_fuel(SCRIPT_FUEL).unwrap();
+
+    let mut memory = None;
+
+    let result = linker
+        .instantiate(&mut store, module)
+        .and_then(|pre| pre.start(&mut store))
+        .map_err(|e| RunError::Link(e.to_string()))
+        .and_then(|instance| {
+            let func = instance
+                .get_typed_func::<(), ()>(&store, export)
+                .map_err(|e| RunError::Link(e.to_string()))?;
+
+            let called = func.call(&mut store, ());
+
+            memory = instance
+                .get_memory(&store, "memory")
+                .map(|memory| memory.data(&store).len() as u64);
+
+            Ok(called?)
+        });
+
+    // running out can leave a little that wasn't enough for the next
+    // instruction, that counts as all of it
+    let fuel = match result {
+        Err(RunError::OutOfFuel) => SCRIPT_FUEL,
+        _ => SCRIPT_FUEL - store.get_fuel().unwrap_or(0),
+    };
+
+    let mut host = store.into_data();
+    host.cost = ScriptCost {
+        fuel,
+        memory,
+        ..ScriptCost::default()
+    };
+
+    (host, result)
+}
+
+// tiles that run `from` on behalf of `owner` switch to `to` at the next tick
+#[derive(PartialEq, Debug, Clone)]
+pub struct Reload {
+    pub from: String,
+    pub to: String,
+    pub owner: String,
+}
+
+// a reload that's been applied but not survived a tick yet: the tiles it
+// switched, with their state from before any migration
+struct ReloadTrial {
+    reload: Reload,
+    tiles: Vec<(usize, TileStore)>,
+}
+
+impl GridState {
+    // what the script of the tile at `index` gets to see
+    pub(crate) fn view(&self, index: usize) -> View {
+        let (col, row) = self.get_coords(index);
+
+        let mut tiles = HashMap::new();
+        let mut terrain = HashMap::new();
+
+        let rows = row.saturating_sub(VIEW_RANGE)..=(row + VIEW_RANGE).min(self.height as u32 - 1);
+        let cols = col.saturating_sub(VIEW_RANGE)..=(col + VIEW_RANGE).min(self.width as u32 - 1);
+
+        for y in rows {
+            for x in cols.clone() {
+                let tile = self.tiles.get(x, y);
+
+                if *tile != HexTile::Wild {
+                    tiles.insert((x, y), TileInfo::from(tile));
+                }
+
+                let ground = *self.terrain.get(x, y);
+
+                if ground != Terrain::Plains {
+                    terrain.insert((x, y), ground);
+                }
+            }
+        }
+
+        let mut steps = self.steps_from(col, row, VIEW_RANGE).collect::<Vec<_>>();
+        steps.sort_unstable_by_key(|&(i, steps)| (steps, i));
+
+        View {
+            col,
+            row,
+            tick: self.tick,
+            width: self.width as u32,
+            height: self.height as u32,
+            tiles,
+            terrain,
+            steps: steps
+                .into_iter()
+                .map(|(i, steps)| (self.get_coords(i), steps))
+                .collect(),
+        }
+    }
+}
+
+impl GridState {
+    // the order conductors run in within a tick
+    const SCRIPT_PHASES: [ScriptRole; 3] = [
+        ScriptRole::Mine,
+        ScriptRole::Logistics,
+        ScriptRole::Defender,
+    ];
+
+    // runs every mine's and turret's conductor once: its script if it has
+    // one for the phase (first applying any pending reloads), the built-in
+    // one otherwise.
+    // returns the indices of tiles that changed; what the scripts logged
+    // ends up in script_logs, intents that were turned down in rejections
+    // and messages in the receivers' inboxes, for the next tick. what each
+    // script cost goes into the profiler
+    pub fn run_scripts(&mut self) -> Vec<usize> {
+        let mut changed = BTreeSet::new();
+        let mut failed = HashSet::new();
+        let mut next = NextTick::default();
+
+        self.script_logs.clear();
+
+        let trials = self.start_reloads(&mut changed, &mut failed, &mut next);
+
+        for role in Self::SCRIPT_PHASES {
+            let mut tiles = match role {
+                ScriptRole::Mine | ScriptRole::Logistics => self.mine_tiles.clone(),
+                ScriptRole::Defender => self.turret_tiles.clone(),
+            };
+
+            // outcomes get applied in tile index order, so the result doesn't
+            // depend on which thread finished first
+            tiles.sort_unstable();
+            tiles.retain(|index| !failed.contains(index));
+
+            // everyone sees the world as it was at the start of the phase
+            let grid = &*self;
+            // a script that doesn't cover this phase (or is gone) leaves the
+            // tile to the built-in conductor for it
+            let outcomes = tiles
+                .par_iter()
+                .map(|&index| {
+                    let scripted = grid.assignments.get(&index).and_then(|assignment| {
+                        grid.scripts
+                            .get(&assignment.script_id)
+                            .filter(|script| script.roles.contains(&role))
+                            .map(|script| (script, &assignment.owner))
+                    });
+
+                    match scripted {
+                        Some((script, owner)) => {
+                            grid.plan_script(index, script, role.entry_point(), owner)
+                        }
+                        None => grid.plan_conductor(index, role),
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            let mut intents = Vec::new();
+
+            for outcome in outcomes {
+                let index = outcome.index;
+
+                match self.apply_outcome(outcome, &mut intents) {
+                    Ok(tiles) => changed.extend(tiles),
+                    Err(e) => {
+                        warn!("script on tile {index} {e}");
+                        failed.insert(index);
+                    }
+                }
+            }
+
+            changed.extend(self.resolve_intents(Some(role), intents, &mut next));
+        }
+
+        // scripts only get to see these on the next tick
+        if self.journal.is_some()
+            && (next.rejected != self.rejections || next.inboxes != self.inboxes)
+        {
+            let record = JournalRecord::Pending {
+                tick: self.tick,
+                rejections: self.by_coords(&next.rejected),
+                inboxes: self.by_coords(&next.inboxes),
+            };
+            self.journal_record(|| record);
+        }
+
+        self.rejections = next.rejected;
+        self.inboxes = next.inboxes;
+
+        let assignments = &self.assignments;
+        self.profiler
+            .retain(|index| assignments.contains_key(&index));
+
+        for trial in trials {
+            if trial.tiles.iter().any(|(index, _)| failed.contains(index)) {
+                self.roll_back(trial, &mut changed);
+            }
+        }
+
+        changed.into_iter().collect()
+    }
+
+    // calls `export` of the script on a tile, without changing anything yet
+    fn plan_script(&self, index: usize, script: &Script, export: &str, owner: &str) -> Outcome {
+        let (x, y) = self.get_coords(index);
+
+        let mut host = HostState::new(self.tiles.get(x, y).store().cloned().unwrap_or_default());
+        host.rejected = self.rejections.get(&index).cloned().unwrap_or_default();
+        host.inbox = self.inboxes.get(&index).cloned().unwrap_or_default();
+        host.view = self.view(index);
+
+        let started = Instant::now();
+
+        let (host, result) = match &script.code {
+            ScriptCode::Wasm(module) => run(
+                self.scripts.engine(),
+                self.scripts.linker(),
+                module,
+                export,
+                host,
+            ),
+            ScriptCode::Rhai(ast) => rhai_host::run(self.scripts.rhai(), ast, export, host),
+        };
+
+        Outcome {
+            index,
+            actor: Actor::Script(owner.to_string()),
+            logs: host.logs,
+            cost: Some(ScriptCost {
+                wall_us: started.elapsed().as_micros() as u64,
+                ..host.cost
+            }),
+            result: result.map(|()| (host.state, host.intents)),
+        }
+    }
+
+    // writes back the state of a conductor run and adds its intents to
+    // `intents` for resolve_intents, returning every tile that changed. a
+    // failed run changes nothing, but its logs (and the error) still go to
+    // the owner
+    fn apply_outcome(
+        &mut self,
+        outcome: Outcome,
+        intents: &mut Vec<(usize, Actor, Intent)>,
+    ) -> Result<Vec<usize>, RunError> {
+        let Outcome {
+            index,
+            actor,
+            logs,
+            cost,
+            result,
+        } = outcome;
+
+        let owner = match &actor {
+            Actor::Script(owner) => Some(owner.as_str()),
+            _ => None,
+        };
+
+        if let Some(owner) = owner {
+            for (level, message) in logs {
+                self.log_script(index, owner, level, message);
+            }
+        }
+
+        if let (Some(owner), Some(cost), Some(assignment)) =
+            (owner, cost, self.assignments.get(&index))
+        {
+            let coords = self.get_coords(index);
+
+            self.profiler
+                .record(index, coords, &assignment.script_id, owner, self.tick, cost);
+        }
+
+        let (state, queued) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                if let Some(owner) = owner {
+                    self.log_script(index, owner, LogLevel::Error, e.to_string());
+                }
+
+                return Err(e);
+            }
+        };
+
+        let changed = self.update_tile_store(index, state, actor.clone());
+
+        intents.extend(
+            queued
+                .into_iter()
+                .map(|intent| (index, actor.clone(), intent)),
+        );
+
+        Ok(if changed { vec![index] } else { Vec::new() })
+    }
+
+    fn log_script(&mut self, index: usize, owner: &str, level: LogLevel, message: String) {
+        let (col, row) = self.get_coords(index);
+
+        self.script_logs.push(ScriptLog {
+            owner: owner.to_string(),
+            col,
+            row,
+            tick: self.tick,
+            level,
+            message,
+        });
+    }
+
+    // replaces the script state of a mine/turret through replace_tile
+    pub(crate) fn update_tile_store(
+        &mut self,
+        index: usize,
+        state: TileStore,
+        cause: Actor,
+    ) -> bool {
+        let (x, y) = self.get_coords(index);
+        let mut after = self.tiles.get(x, y).clone();
+
+        match after.store_mut() {
+            Some(store) => *store = state,
+            None => return false,
+        }
+
+        self.replace_tile(index, after, cause)
+    }
+
+    pub(crate) fn set_assignment(&mut self, index: usize, assignment: ScriptAssignment) {
+        let (x, y) = self.get_coords(index);
+        let tick = self.tick;
+
+        self.journal_record(|| JournalRecord::Assign {
+            tick,
+            col: x,
+            row: y,
+            assignment: assignment.clone(),
+        });
+
+        self.assignments.insert(index, assignment);
+    }
+
+    // switches tiles over to their new scripts and lets those migrate the old
+    // state; tiles where that already fails go into `failed`. whatever a
+    // migration wants done is resolved right away
+    fn start_reloads(
+        &mut self,
+        changed: &mut BTreeSet<usize>,
+        failed: &mut HashSet<usize>,
+        next: &mut NextTick,
+    ) -> Vec<ReloadTrial> {
+        let mut trials = Vec::new();
+
+        for reload in self.scripts.take_reloads() {
+            let Some(script) = self.scripts.get(&reload.to).cloned() else {
+                continue;
+            };
+
+            let tiles = self
+                .assignments
+                .iter()
+                .filter(|(_, a)| a.script_id == reload.from && a.owner == reload.owner)
+                .map(|(&index, _)| index)
+                .collect::<Vec<_>>();
+
+            let mut trial = ReloadTrial {
+                reload: reload.clone(),
+                tiles: Vec::new(),
+            };
+
+            for index in tiles {
+                let (x, y) = self.get_coords(index);
+
+                // a new version doesn't necessarily implement the same roles
+                let tile = self.tiles.get(x, y);
+                if !script.fits(tile) {
+                    continue;
+                }
+
+                let state = tile.store().cloned().unwrap_or_default();
+                trial.tiles.push((index, state));
+
+                self.set_assignment(
+                    index,
+                    ScriptAssignment {
+                        script_id: reload.to.clone(),
+                        owner: reload.owner.clone(),
+                    },
+                );
+
+                if script.migrates {
+                    let outcome = self.plan_script(index, &script, MIGRATE_EXPORT, &reload.owner);
+                    let mut intents = Vec::new();
+
+                    match self.apply_outcome(outcome, &mut intents) {
+                        Ok(tiles) => changed.extend(tiles),
+                        Err(e) => {
+                            warn!("migrating tile {index} to script {} {e}", script.id);
+                            failed.insert(index);
+                        }
+                    }
+
+                    changed.extend(self.resolve_intents(None, intents, next));
+                }
+            }
+
+            info!(
+                "switched {} tiles of {} from script {} to {}",
+                trial.tiles.len(),
+                reload.owner,
+                reload.from,
+                reload.to
+            );
+
+            trials.push(trial);
+        }
+
+        trials
+    }
+
+    // puts every tile of a failed reload back on the old script, with the
+    // state it had before
+    fn roll_back(&mut self, trial: ReloadTrial, changed: &mut BTreeSet<usize>) {
+        let ReloadTrial { reload, tiles } = trial;
+
+        warn!(
+            "script {} failed on its first tick, rolling {} back to {}",
+            reload.to, reload.owner, reload.from
+        );
+
+        for (index, state) in tiles {
+            self.log_script(
+                index,
+                &reload.owner,
+                LogLevel::Warn,
+                format!("rolled back to script {}", reload.from),
+            );
+
+            self.set_assignment(
+                index,
+                ScriptAssignment {
+                    script_id: reload.from.clone(),
+                    owner: reload.owner.clone(),
+                },
+            );
+
+            if self.update_tile_store(index, state, Actor::Script(reload.owner.clone())) {
+                changed.insert(index);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use wasm_encoder::{
+        BlockType, CodeSection, ConstExpr, DataSection, EntityType, ExportKind, ExportSection,
+        Function, FunctionSection, ImportSection, Instruction, MemArg, MemorySection, MemoryType,
+        TypeSection, ValType,
+    };
+
+    use crate::{
+        api::{
+            game::RejectReason,
+            scripts::{ScriptError, ScriptLanguage, ScriptRole},
+        },
+        types::{
+            HexTile,
+            tests::{mine, turret},
+        },
+    };
+
+    use super::*;
+
+    // host functions every test script imports, in function index order
+    pub(crate) const STATE_SET: u32 = 1;
+    pub(crate) const SET_STATE_VERSION: u32 = 4;
+    pub(crate) const LOG: u32 = 5;
+    const SET_TRADE_VALUE: u32 = 6;
+    const UPGRADE: u32 = 7;
+    const TRANSFER: u32 = 8;
+    const REQUEST: u32 = 9;
+    const REJECTED: u32 = 12;
+    const MESSAGE: u32 = 13;
+    const INBOX_READ: u32 = 15;
+    const POSITION: u32 = 16;
+    const TICK: u32 = 17;
+    const TILE: u32 = 18;
+    const TERRAIN: u32 = 19;
+    const NEAREST: u32 = 20;
+    const MINE: u32 = 21;
+    const YIELD_PER_TICK: u32 = 22;
+    const TURRET: u32 = 23;
+    const HOST_FUNCTIONS: u32 = 24;
+
+    // a script with one page of memory holding `data` at offset 0, and an
+    // exported `() -> ()` function per entry of `exports`
+    pub(crate) fn script(data: &[u8], exports: &[(&str, Vec<Instruction>)]) -> Vec<u8> {
+        let mut module = wasm_encoder::Module::new();
+
+        let mut types = TypeSection::new();
+        types.ty().function([], []);
+        types.ty().function([ValType::I32; 4], [ValType::I32]);
+        types.ty().function([ValType::I32; 2], [ValType::I32]);
+        types.ty().function([], [ValType::I32]);
+        types.ty().function([ValType::I32], []);
+        types.ty().function([ValType::I32; 3], []);
+        types.ty().function([ValType::I32], [ValType::I32]);
+        types.ty().function([ValType::I32; 3], [ValType::I32]);
+        types.ty().function([], [ValType::I64]);
+        module.section(&types);
+
+        let mut imports = ImportSection::new();
+        for (name, ty) in [
+            ("state_get", 1),
+            ("state_set", 1),
+            ("state_remove", 2),
+            ("state_version", 3),
+            ("set_state_version", 4),
+            ("log", 5),
+            ("set_trade_value", 6),
+            ("upgrade", 3),
+            ("transfer", 7),
+            ("request", 7),
+            ("fire", 2),
+            ("rejected_count", 3),
+            ("rejected", 2),
+            ("message", 1),
+            ("inbox_count", 3),
+            ("inbox_read", 1),
+            ("position", 4),
+            ("tick", 8),
+            ("tile", 7),
+            ("terrain", 7),
+            ("nearest", 7),
+            ("mine", 6),
+            ("yield_per_tick", 3),
+            ("turret", 6),
+        ] {
+            imports.import(HOST_MODULE, name, EntityType::Function(ty));
+        }
+        module.section(&imports);
+
+        let mut functions = FunctionSection::new();
+        for _ in exports {
+            functions.function(0);
+        }
+        module.section(&functions);
+
+        let mut memory = MemorySection::new();
+        memory.memory(MemoryType {
+            minimum: 1,
+            maximum: None,
+            memory64: false,
+            shared: false,
+            page_size_log2: None,
+        });
+        module.section(&memory);
+
+        let mut export_section = ExportSection::new();
+        export_section.export("memory", ExportKind::Memory, 0);
+        for (i, (name, _)) in exports.iter().enumerate() {
+            export_section.export(name, ExportKind::Func, HOST_FUNCTIONS + i as u32);
+        }
+        module.section(&export_section);
+
+        let mut code = CodeSection::new();
+        for (_, body) in exports {
+            let mut function = Function::new([]);
+            for instruction in body {
+                function.instruction(instruction);
+            }
+            function.instruction(&Instruction::End);
+            code.function(&function);
+        }
+        module.section(&code);
+
+        let mut data_section = DataSection::new();
+        data_section.active(0, &ConstExpr::i32_const(0), data.iter().copied());
+        module.section(&data_section);
+
+        module.finish()
+    }
+
+    // state_set of two strings in the data, as (offset, length)
+    pub(crate) fn set(key: (i32, i32), value: (i32, i32)) -> Vec<Instruction<'static>> {
+        vec![
+            Instruction::I32Const(key.0),
+            Instruction::I32Const(key.1),
+            Instruction::I32Const(value.0),
+            Instruction::I32Const(value.1),
+            Instruction::Call(STATE_SET),
+            Instruction::Drop,
+        ]
+    }
+
+    // log of a string in the data
+    pub(crate) fn log(level: i32, message: (i32, i32)) -> Vec<Instruction<'static>> {
+        vec![
+            Instruction::I32Const(level),
+            Instruction::I32Const(message.0),
+            Instruction::I32Const(message.1),
+            Instruction::Call(LOG),
+        ]
+    }
+
+    // "gen12": sets gen to 1 or 2
+    fn generation(value: i32, migrate: bool) -> Vec<u8> {
+        let mut exports = vec![("mine_tick", set((0, 3), (2 + value, 1)))];
+
+        if migrate {
+            exports.push((
+                MIGRATE_EXPORT,
+                vec![
+                    Instruction::I32Const(2),
+                    Instruction::Call(SET_STATE_VERSION),
+                ],
+            ));
+        }
+
+        script(b"gen12", &exports)
+    }
+
+    fn state(grid: &GridState, x: u32, y: u32) -> TileStore {
+        grid.get_tile(x, y).unwrap().store().unwrap().clone()
+    }
+
+    fn upload(grid: &mut GridState, bytes: &[u8]) -> String {
+        grid.scripts.upload(ScriptRole::Mine, bytes).unwrap()
+    }
+
+    #[test]
+    fn it_runs_assigned_scripts() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.set_tile(1, 1, mine(0, 10, 0));
+        grid.set_tile(2, 1, mine(0, 10, 0));
+
+        let id = upload(&mut grid, &generation(1, false));
+        grid.assign_script(1, 1, &id, "ada").unwrap();
+
+        // the other one gets the built-in conductor
+        assert_eq!(
+            grid.run_scripts(),
+            vec![grid.get_index(1, 1), grid.get_index(2, 1)]
+        );
+        assert_eq!(state(&grid, 1, 1).get("gen"), Some("1"));
+        assert!(state(&grid, 2, 1).is_empty());
+
+        // nothing new to write the second time around
+        assert!(grid.run_scripts().is_empty());
+    }
+
+    #[test]
+    fn it_falls_back_to_the_built_in_conductor() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.set_tile(1, 1, mine(0, 10, 0));
+        grid.set_tile(4, 4, turret());
+        grid.set_tile(5, 4, HexTile::Slime);
+
+        // only does logistics, so the mine phase is still the built-in one's
+        let id = grid
+            .scripts
+            .upload(
+                ScriptRole::Logistics,
+                &script(b"", &[("logistics_tick", Vec::new())]),
+            )
+            .unwrap();
+        grid.assign_script(1, 1, &id, "ada").unwrap();
+
+        // and a script that's gone doesn't leave the turret idle
+        grid.assignments.insert(
+            grid.get_index(4, 4),
+            ScriptAssignment {
+                script_id: "gone".to_string(),
+                owner: "ada".to_string(),
+            },
+        );
+
+        grid.run_scripts();
+
+        match grid.get_tile(1, 1) {
+            Some(HexTile::Mine(mine)) => assert_eq!(mine.trade_value, -10),
+            tile => panic!("expected a mine, got {tile:?}"),
+        }
+        assert_eq!(grid.get_tile(5, 4), Some(&HexTile::Wild));
+    }
+
+    #[test]
+    fn it_collects_logs_for_the_owner() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.set_tile(1, 1, mine(0, 10, 0));
+        grid.set_tile(2, 1, mine(0, 10, 0));
+        grid.tick = 7;
+
+        let chatty = upload(
+            &mut grid,
+            &script(
+                b"hello",
+                &[("mine_tick", [log(1, (0, 5)), log(2, (1, 4))].concat())],
+            ),
+        );
+        let broken = upload(
+            &mut grid,
+            &script(
+                b"bye",
+                &[(
+                    "mine_tick",
+                    [log(1, (0, 3)), vec![Instruction::Unreachable]].concat(),
+                )],
+            ),
+        );
+
+        grid.assign_script(1, 1, &chatty, "ada").unwrap();
+        grid.assign_script(2, 1, &broken, "bob").unwrap();
+        grid.run_scripts();
+
+        let lines = grid
+            .script_logs
+            .iter()
+            .map(|l| (l.owner.as_str(), l.col, l.tick, l.level, l.message.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            lines[..3],
+            [
+                ("ada", 1, 7, LogLevel::Info, "hello"),
+                ("ada", 1, 7, LogLevel::Warn, "ello"),
+                // what it logged before trapping still counts
+                ("bob", 2, 7, LogLevel::Info, "bye"),
+            ]
+        );
+
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[3].3, LogLevel::Error);
+        assert!(lines[3].4.starts_with("trapped"));
+
+        // only ever the last tick's
+        grid.run_scripts();
+        assert_eq!(grid.script_logs.len(), 4);
+    }
+
+    #[test]
+    fn it_caps_log_lines_per_call() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.set_tile(1, 1, mine(0, 10, 0));
+
+        let body = (0..MAX_LOG_LINES + 5)
+            .flat_map(|_| log(0, (0, 1)))
+            .collect();
+        let id = upload(&mut grid, &script(b"x", &[("mine_tick", body)]));
+
+        grid.assign_script(1, 1, &id, "ada").unwrap();
+        grid.run_scripts();
+
+        assert_eq!(grid.script_logs.len(), MAX_LOG_LINES);
+    }
+
+    #[test]
+    fn it_hands_rejected_intents_back_the_next_tick() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.set_tile(1, 1, mine(5, 10, 0));
+
+        // upgrades, ships gold off the map on its logistics turn, and keeps
+        // the first rejection it was told about under "r"
+        let body = [
+            vec![
+                Instruction::Call(UPGRADE),
+                Instruction::Drop,
+                Instruction::I32Const(0),
+                Instruction::I32Const(16),
+                Instruction::Call(REJECTED),
+                Instruction::Drop,
+            ],
+            set((0, 1), (16, 20)),
+        ]
+        .concat();
+
+        let ship = vec![
+            Instruction::I32Const(50),
+            Instruction::I32Const(50),
+            Instruction::I32Const(1),
+            Instruction::Call(TRANSFER),
+            Instruction::Drop,
+        ];
+
+        let id = upload(
+            &mut grid,
+            &script(b"r", &[("mine_tick", body), ("logistics_tick", ship)]),
+        );
+        grid.assign_script(1, 1, &id, "ada").unwrap();
+
+        grid.run_scripts();
+
+        let Some(HexTile::Mine(upgraded)) = grid.get_tile(1, 1) else {
+            panic!("mine went missing");
+        };
+        assert_eq!((upgraded.level, upgraded.count), (2, 0));
+        assert_eq!(state(&grid, 1, 1).get("r"), Some(&"\0".repeat(20)[..]));
+
+        grid.run_scripts();
+
+        let expected = [2, 50, 50, 1, RejectReason::OutOfRange as i32]
+            .iter()
+            .flat_map(|field| field.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        assert_eq!(state(&grid, 1, 1).get("r").unwrap().as_bytes(), expected);
+
+        // the upgrade it can't afford anymore is up next
+        let reasons = grid.rejections[&grid.get_index(1, 1)]
+            .iter()
+            .map(|r| (r.intent.clone(), r.reason))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            reasons,
+            [
+                (Intent::Upgrade, RejectReason::NoGold),
+                (
+                    Intent::Transfer {
+                        col: 50,
+                        row: 50,
+                        amount: 1
+                    },
+                    RejectReason::OutOfRange
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reads_messages_sent_the_tick_before() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.set_tile(1, 1, mine(0, 10, 0));
+
+        // keeps the first message it got under "m", then messages itself
+        let body = [
+            vec![
+                Instruction::I32Const(0),
+                Instruction::I32Const(16),
+                Instruction::I32Const(32),
+                Instruction::I32Const(2),
+                Instruction::Call(INBOX_READ),
+                Instruction::Drop,
+            ],
+            set((0, 1), (32, 2)),
+            vec![
+                Instruction::I32Const(1),
+                Instruction::I32Const(1),
+                Instruction::I32Const(1),
+                Instruction::I32Const(2),
+                Instruction::Call(MESSAGE),
+                Instruction::Drop,
+            ],
+        ]
+        .concat();
+
+        let id = upload(&mut grid, &script(b"mhi", &[("mine_tick", body)]));
+        grid.assign_script(1, 1, &id, "ada").unwrap();
+
+        grid.run_scripts();
+        assert_eq!(state(&grid, 1, 1).get("m"), Some("\0\0"));
+
+        grid.run_scripts();
+        assert_eq!(state(&grid, 1, 1).get("m"), Some("hi"));
+        assert_eq!(
+            grid.inboxes[&grid.get_index(1, 1)],
+            [Message {
+                col: 1,
+                row: 1,
+                payload: b"hi".to_vec(),
+            }]
+        );
+    }
+
+    #[test]
+    fn it_shows_both_languages_the_same_world() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+        grid.set_tile(2, 2, mine(4, 10, 3));
+        grid.set_tile(4, 2, HexTile::Slime);
+        grid.set_terrain(2, 2, Terrain::Ore { richness: 2 });
+        grid.set_terrain(3, 2, Terrain::Rock);
+
+        // reads the world and queues what it saw as requests and trade
+        // values, which are easy to compare
+        let rhai = r#"
+            fn mine_tick() {
+                let kinds = ["wild", "slime", "mine", "turret"];
+                let grounds = ["plains", "rock", "water", "ore"];
+                let unseen = |value| if type_of(value) == "()" { -1 } else { 0 };
+
+                let here = position();
+                request(here[0], here[1], tick());
+
+                let own = mine();
+                request(own.count, own.capacity, own.trade_value);
+
+                let slime = nearest(6, "slime");
+                request(slime[0], slime[1], yield_per_tick());
+
+                for at in [[2, 2], [3, 2]] {
+                    let ground = terrain(at[0], at[1]);
+                    request(grounds.index_of(ground.kind), ground.richness, ground["yield"]);
+                }
+
+                set_trade_value(kinds.index_of(tile(4, 2).kind));
+                set_trade_value(unseen.call(tile(20, 20)));
+                set_trade_value(unseen.call(turret()));
+            }
+        "#;
+
+        // what the wasm imports wrote at 64, field by field
+        let load = |field: u64| {
+            vec![
+                Instruction::I32Const(0),
+                Instruction::I32Load(MemArg {
+                    offset: 64 + 4 * field,
+                    align: 2,
+                    memory_index: 0,
+                }),
+            ]
+        };
+        let call = |args: Vec<Instruction<'static>>, function: u32| {
+            [args, vec![Instruction::Call(function), Instruction::Drop]].concat()
+        };
+        let terrain = |col: i32| {
+            [
+                call(
+                    vec![
+                        Instruction::I32Const(col),
+                        Instruction::I32Const(2),
+                        Instruction::I32Const(64),
+                    ],
+                    TERRAIN,
+                ),
+                call([load(0), load(1), load(2)].concat(), REQUEST),
+            ]
+            .concat()
+        };
+
+        let body = [
+            vec![Instruction::I32Const(64), Instruction::Call(POSITION)],
+            call(
+                [
+                    load(0),
+                    load(1),
+                    vec![Instruction::Call(TICK), Instruction::I32WrapI64],
+                ]
+                .concat(),
+                REQUEST,
+            ),
+            call(vec![Instruction::I32Const(64)], MINE),
+            call([load(2), load(3), load(4)].concat(), REQUEST),
+            call(
+                vec![
+                    Instruction::I32Const(6),
+                    Instruction::I32Const(TileKind::Slime as i32),
+                    Instruction::I32Const(64),
+                ],
+                NEAREST,
+            ),
+            call(
+                [load(0), load(1), vec![Instruction::Call(YIELD_PER_TICK)]].concat(),
+                REQUEST,
+            ),
+            terrain(2),
+            terrain(3),
+            call(
+                vec![
+                    Instruction::I32Const(4),
+                    Instruction::I32Const(2),
+                    Instruction::I32Const(64),
+                ],
+                TILE,
+            ),
+            call(load(0), SET_TRADE_VALUE),
+            call(
+                vec![
+                    Instruction::I32Const(20),
+                    Instruction::I32Const(20),
+                    Instruction::I32Const(64),
+                    Instruction::Call(TILE),
+                ],
+                SET_TRADE_VALUE,
+            ),
+            call(
+                vec![Instruction::I32Const(64), Instruction::Call(TURRET)],
+                SET_TRADE_VALUE,
+            ),
+        ]
+        .concat();
+
+        let scripts = [
+            grid.scripts
+                .upload_as(ScriptLanguage::Rhai, ScriptRole::Mine, rhai.as_bytes())
+                .unwrap(),
+            upload(&mut grid, &script(b"", &[("mine_tick", body)])),
+        ];
+
+        let index = grid.get_index(2, 2);
+        let intents = scripts.map(|id| {
+            let script = grid.scripts.get(&id).unwrap();
+            let outcome = grid.plan_script(index, script, "mine_tick", "ada");
+
+            outcome.result.unwrap().1
+        });
+
+        let request = |col, row, amount| Intent::Request { col, row, amount };
+        assert_eq!(
+            intents[0],
+            [
+                request(2, 2, 0),
+                request(4, 10, 3),
+                request(4, 2, 2),
+                request(3, 2, 2),
+                request(1, 0, 1),
+                Intent::SetTradeValue(1),
+                Intent::SetTradeValue(-1),
+                Intent::SetTradeValue(-1),
+            ]
+        );
+        assert_eq!(intents[0], intents[1]);
+    }
+
+    #[test]
+    fn it_stops_scripts_that_run_out_of_fuel() {
+        let mut store = crate::api::scripts::ScriptStore::new();
+
+        let bytes = script(
+            b"",
+            &[(
+                "mine_tick",
+                vec![
+                    Instruction::Loop(BlockType::Empty),
+                    Instruction::Br(0),
+                    Instruction::End,
+                ],
+            )],
+        );
+
+        let id = store.upload(ScriptRole::Mine, &bytes).unwrap();
+        let script = store.get(&id).unwrap();
+
+        let ScriptCode::Wasm(module) = &script.code else {
+            unreachable!("uploaded as wasm");
+        };
+
+        let (_, result) = run(
+            store.engine(),
+            store.linker(),
+            module,
+            "mine_tick",
+            HostState::new(TileStore::default()),
+        );
+
+        assert_eq!(result, Err(RunError::OutOfFuel));
+    }
+
+    #[test]
+    fn it_profiles_what_scripts_cost() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.set_tile(1, 1, mine(0, 10, 0));
+        grid.set_tile(2, 1, mine(0, 10, 0));
+        grid.set_tile(3, 1, mine(0, 10, 0));
+
+        let cheap = upload(&mut grid, &generation(1, false));
+        let endless = upload(
+            &mut grid,
+            &script(
+                b"",
+                &[(
+                    "mine_tick",
+                    vec![
+                        Instruction::Loop(BlockType::Empty),
+                        Instruction::Br(0),
+                        Instruction::End,
+                    ],
+                )],
+            ),
+        );
+
+        grid.assign_script(1, 1, &cheap, "ada").unwrap();
+        grid.assign_script(2, 1, &endless, "bob").unwrap();
+
+        for tick in 1..=2 {
+            grid.tick = tick;
+            grid.run_scripts();
+        }
+
+        let cheap = grid.profiler.get(grid.get_index(1, 1)).unwrap();
+        assert_eq!((cheap.ticks, cheap.last_tick), (2, 2));
+        assert!(cheap.last_fuel > 0 && cheap.last_fuel < SCRIPT_FUEL);
+        assert_eq!(cheap.peak_memory, Some(1 << 16));
+
+        // running out still counts, at the full budget
+        let endless = grid.profiler.get(grid.get_index(2, 1)).unwrap();
+        assert_eq!(endless.total_fuel, 2 * SCRIPT_FUEL);
+        assert_eq!(endless.peak_fuel, SCRIPT_FUEL);
+
+        // the built-in conductor isn't profiled
+        assert!(grid.profiler.get(grid.get_index(3, 1)).is_none());
+
+        let report = grid.profiler.report(None);
+        assert_eq!(
+            report.iter().map(|p| p.owner.as_str()).collect::<Vec<_>>(),
+            ["bob", "ada"]
+        );
+    }
+
+    #[test]
+    fn it_hot_reloads_with_migration() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.set_tile(1, 1, mine(0, 10, 0));
+        grid.set_tile(2, 2, mine(0, 10, 0));
+
+        let v1 = upload(&mut grid, &generation(1, false));
+        let v2 = upload(&mut grid, &generation(2, true));
+
+        grid.assign_script(1, 1, &v1, "ada").unwrap();
+        grid.assign_script(2, 2, &v1, "bob").unwrap();
+        grid.run_scripts();
+
+        grid.scripts.schedule_reload(&v1, &v2, "ada");
+        grid.run_scripts();
+
+        // ada's tile migrated and ran the new version, bob's didn't
+        assert_eq!(grid.assignments[&grid.get_index(1, 1)].script_id, v2);
+        assert_eq!(state(&grid, 1, 1).version, 2);
+        assert_eq!(state(&grid, 1, 1).get("gen"), Some("2"));
+
+        assert_eq!(grid.assignments[&grid.get_index(2, 2)].script_id, v1);
+        assert_eq!(state(&grid, 2, 2).get("gen"), Some("1"));
+    }
+
+    #[test]
+    fn it_rolls_back_a_version_that_traps() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.set_tile(1, 1, mine(0, 10, 0));
+
+        let v1 = upload(&mut grid, &generation(1, false));
+
+        // migrates fine, then falls over
+        let broken = upload(
+            &mut grid,
+            &script(
+                b"",
+                &[
+                    ("mine_tick", vec![Instruction::Unreachable]),
+                    (
+                        MIGRATE_EXPORT,
+                        vec![
+                            Instruction::I32Const(7),
+                            Instruction::Call(SET_STATE_VERSION),
+                        ],
+                    ),
+                ],
+            ),
+        );
+
+        grid.assign_script(1, 1, &v1, "ada").unwrap();
+        grid.run_scripts();
+
+        let before = state(&grid, 1, 1);
+
+        grid.scripts.schedule_reload(&v1, &broken, "ada");
+        grid.run_scripts();
+
+        assert_eq!(grid.assignments[&grid.get_index(1, 1)].script_id, v1);
+        assert_eq!(state(&grid, 1, 1), before);
+    }
+
+    #[test]
+    fn it_rejects_a_bad_migrate_hook() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+
+        // a migrate taking an argument
+        let bytes = crate::api::scripts::tests::module(&["mine_tick", "bad_migrate"], &[]);
+
+        assert_eq!(
+            grid.scripts.upload(ScriptRole::Mine, &bytes),
+            Err(ScriptError::BadMigrate)
+        );
+    }
+}
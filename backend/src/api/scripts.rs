@@ -0,0 +1,933 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs, io,
+    mem::discriminant,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ts_rs::TS;
+use wasmi::{Config, Engine, ExternType, Linker, Module, Store};
+
+use crate::{
+    api::{
+        grid_api::GridState,
+        replay::ReplayAction,
+        rhai_host,
+        script_host::{self, HostState, MIGRATE_EXPORT, Reload},
+    },
+    types::HexTile,
+};
+
+// anything bigger is almost certainly not a bot
+pub const MAX_SCRIPT_BYTES: usize = 1 << 20;
+
+// what one player may have uploaded at a time, assigned or not
+pub const MAX_OWNER_SCRIPTS: usize = 16;
+pub const MAX_OWNER_SCRIPT_BYTES: usize = 4 * MAX_SCRIPT_BYTES;
+
+// how long an upload gets to be assigned somewhere before it's collected
+pub const UNASSIGNED_SCRIPT_TICKS: u64 = 100;
+
+// the only module scripts may import from; what's in it is up to the host
+pub const HOST_MODULE: &str = "plu";
+
+// what a script is written in: compiled wasm, or rhai source that can just
+// be pasted in
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/types/types.ts")]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptLanguage {
+    #[default]
+    Wasm,
+    Rhai,
+}
+
+impl ScriptLanguage {
+    pub const ALL: [ScriptLanguage; 2] = [Self::Wasm, Self::Rhai];
+
+    // of the file it's kept in
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Wasm => "wasm",
+            Self::Rhai => "rhai",
+        }
+    }
+}
+
+// which conductor a script implements
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/types/types.ts")]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptRole {
+    Mine,
+    Defender,
+    Logistics,
+}
+
+impl ScriptRole {
+    pub const ALL: [ScriptRole; 3] = [Self::Mine, Self::Defender, Self::Logistics];
+
+    // what a script has to export to implement this role, as a function
+    // without parameters or results
+    pub fn entry_point(self) -> &'static str {
+        match self {
+            Self::Mine => "mine_tick",
+            Self::Defender => "defender_tick",
+            Self::Logistics => "logistics_tick",
+        }
+    }
+
+    // whether this role has anything to do on `tile`
+    pub fn fits(self, tile: &HexTile) -> bool {
+        match self {
+            Self::Mine | Self::Logistics => matches!(tile, HexTile::Mine(_)),
+            Self::Defender => matches!(tile, HexTile::Turret(_)),
+        }
+    }
+}
+
+impl Display for ScriptRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mine => write!(f, "mine"),
+            Self::Defender => write!(f, "defender"),
+            Self::Logistics => write!(f, "logistics"),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ScriptError {
+    TooLarge { size: usize },
+    // didn't compile/validate as wasm
+    Invalid(String),
+    // rhai that doesn't parse
+    Syntax(String),
+    MissingEntryPoint(ScriptRole),
+    // exported, but not as a `() -> ()` function
+    BadEntryPoint(ScriptRole),
+    BadMigrate,
+    // anything not taken from HOST_MODULE
+    BadImport { module: String, name: String },
+    UnknownScript(String),
+    // the script has no role that can run on this tile
+    WrongTile { tile: String },
+    // the tile already runs a script another player assigned
+    NotOwner { owner: String },
+    // replacing a script the player doesn't run anywhere
+    NotAssigned(String),
+    // the player has MAX_OWNER_SCRIPTS or MAX_OWNER_SCRIPT_BYTES uploaded
+    // already
+    OverQuota { scripts: usize, bytes: usize },
+    OutOfBounds,
+    Storage(String),
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooLarge { size } => {
+                write!(f, "script is {size} bytes, the limit is {MAX_SCRIPT_BYTES}")
+            }
+            Self::Invalid(e) => write!(f, "invalid module: {e}"),
+            Self::Syntax(e) => write!(f, "invalid script: {e}"),
+            Self::MissingEntryPoint(role) => {
+                write!(f, "{role} scripts have to export `{}`", role.entry_point())
+            }
+            Self::BadEntryPoint(role) => write!(
+                f,
+                "`{}` has to be a function without parameters or results",
+                role.entry_point()
+            ),
+            Self::BadMigrate => write!(
+                f,
+                "`{MIGRATE_EXPORT}` has to be a function without parameters or results"
+            ),
+            Self::BadImport { module, name } => write!(
+                f,
+                "can't import {module}.{name}, only functions from `{HOST_MODULE}`"
+            ),
+            Self::UnknownScript(id) => write!(f, "no script with id {id}"),
+            Self::WrongTile { tile } => write!(f, "script can't run on a {tile} tile"),
+            Self::NotOwner { owner } => write!(f, "tile already runs a script of {owner}"),
+            Self::NotAssigned(id) => write!(f, "script {id} isn't assigned to any of your tiles"),
+            Self::OverQuota { scripts, bytes } => write!(
+                f,
+                "you have {scripts} scripts ({bytes} bytes) uploaded already, the limit is \
+                 {MAX_OWNER_SCRIPTS} ({MAX_OWNER_SCRIPT_BYTES} bytes)"
+            ),
+            Self::OutOfBounds => write!(f, "tile is out of bounds"),
+            Self::Storage(e) => write!(f, "failed to store script: {e}"),
+        }
+    }
+}
+
+pub enum ScriptCode {
+    Wasm(Module),
+    Rhai(rhai::AST),
+}
+
+// a compiled, validated player script
+pub struct Script {
+    // hex sha256 of the module bytes (or source)
+    pub id: String,
+    // every role it has an entry point for
+    pub roles: Vec<ScriptRole>,
+    // whether it exports a migrate hook
+    pub migrates: bool,
+    pub code: ScriptCode,
+}
+
+impl Script {
+    pub fn fits(&self, tile: &HexTile) -> bool {
+        self.roles.iter().any(|role| role.fits(tile))
+    }
+
+    pub fn language(&self) -> ScriptLanguage {
+        match self.code {
+            ScriptCode::Wasm(_) => ScriptLanguage::Wasm,
+            ScriptCode::Rhai(_) => ScriptLanguage::Rhai,
+        }
+    }
+}
+
+pub fn script_id(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn is_unit_func(ty: &ExternType) -> bool {
+    matches!(ty, ExternType::Func(ty) if ty.params().is_empty() && ty.results().is_empty())
+}
+
+// compiles `bytes` and checks it has at least the entry point for `role`.
+// wasm modules may only import host functions; rhai gets compiled with an
+// engine of its own, it's not worth sharing for a parse
+pub fn compile(
+    engine: &Engine,
+    language: ScriptLanguage,
+    role: ScriptRole,
+    bytes: &[u8],
+) -> Result<Script, ScriptError> {
+    if bytes.len() > MAX_SCRIPT_BYTES {
+        return Err(ScriptError::TooLarge { size: bytes.len() });
+    }
+
+    match language {
+        ScriptLanguage::Wasm => compile_wasm(engine, role, bytes),
+        ScriptLanguage::Rhai => rhai_host::compile(&rhai_host::engine(), role, bytes),
+    }
+}
+
+fn compile_wasm(engine: &Engine, role: ScriptRole, bytes: &[u8]) -> Result<Script, ScriptError> {
+    let module = Module::new(engine, bytes).map_err(|e| ScriptError::Invalid(e.to_string()))?;
+
+    if let Some(import) = module
+        .imports()
+        .find(|i| i.module() != HOST_MODULE || !matches!(i.ty(), ExternType::Func(_)))
+    {
+        return Err(ScriptError::BadImport {
+            module: import.module().to_string(),
+            name: import.name().to_string(),
+        });
+    }
+
+    // catches unknown host functions, wrong signatures and oversized
+    // memories, without running anything yet
+    let mut store = Store::new(engine, HostState::new(Default::default()));
+    store.limiter(|host| &mut host.limits);
+
+    script_host::linker(engine)
+        .instantiate(&mut store, &module)
+        .map_err(|e| ScriptError::Invalid(e.to_string()))?;
+
+    let mut roles = Vec::new();
+
+    for candidate in ScriptRole::ALL {
+        let Some(export) = module
+            .exports()
+            .find(|e| e.name() == candidate.entry_point())
+        else {
+            continue;
+        };
+
+        if !is_unit_func(export.ty()) {
+            return Err(ScriptError::BadEntryPoint(candidate));
+        }
+
+        roles.push(candidate);
+    }
+
+    if !roles.contains(&role) {
+        return Err(ScriptError::MissingEntryPoint(role));
+    }
+
+    let migrate = module.exports().find(|e| e.name() == MIGRATE_EXPORT);
+
+    if migrate.as_ref().is_some_and(|e| !is_unit_func(e.ty())) {
+        return Err(ScriptError::BadMigrate);
+    }
+
+    Ok(Script {
+        id: script_id(bytes),
+        roles,
+        migrates: migrate.is_some(),
+        code: ScriptCode::Wasm(module),
+    })
+}
+
+// every script uploaded so far, by id. with a directory they're also kept on
+// disk as <id>.wasm/<id>.rhai and survive restarts
+pub struct ScriptStore {
+    engine: Engine,
+    linker: Linker<HostState>,
+    rhai: rhai::Engine,
+    dir: Option<PathBuf>,
+    scripts: HashMap<String, Arc<Script>>,
+    // new versions waiting for the next tick
+    reloads: Vec<Reload>,
+    // what each player uploaded that's still around, as id -> size
+    uploads: HashMap<String, HashMap<String, usize>>,
+    // tick each script was last uploaded at. scripts loaded from disk don't
+    // have one, and go as soon as nothing runs them
+    uploaded_at: HashMap<String, u64>,
+}
+
+impl ScriptStore {
+    pub fn new() -> Self {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+
+        let engine = Engine::new(&config);
+
+        ScriptStore {
+            linker: script_host::linker(&engine),
+            engine,
+            rhai: rhai_host::engine(),
+            dir: None,
+            scripts: HashMap::new(),
+            reloads: Vec::new(),
+            uploads: HashMap::new(),
+            uploaded_at: HashMap::new(),
+        }
+    }
+
+    // loads whatever scripts are already in `dir`
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut store = ScriptStore {
+            dir: Some(dir.to_path_buf()),
+            ..ScriptStore::new()
+        };
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            let Some(language) = ScriptLanguage::ALL
+                .into_iter()
+                .find(|language| path.extension().is_some_and(|e| e == language.extension()))
+            else {
+                continue;
+            };
+
+            let bytes = fs::read(&path)?;
+
+            // roles are only checked on upload, anything with at least one is
+            // fine here
+            let compiled = ScriptRole::ALL
+                .iter()
+                .find_map(|&role| compile(&store.engine, language, role, &bytes).ok());
+
+            match compiled {
+                Some(script) => {
+                    store.scripts.insert(script.id.clone(), Arc::new(script));
+                }
+                None => warn!("skipping invalid script {}", path.display()),
+            }
+        }
+
+        info!("loaded {} scripts from {}", store.len(), dir.display());
+
+        Ok(store)
+    }
+
+    // for compiling outside of whatever lock the store is behind
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    pub fn linker(&self) -> &Linker<HostState> {
+        &self.linker
+    }
+
+    pub fn rhai(&self) -> &rhai::Engine {
+        &self.rhai
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Arc<Script>> {
+        self.scripts.get(id)
+    }
+
+    // the bytes `id` was uploaded as, if it's kept on disk
+    pub fn source(&self, id: &str) -> Option<Vec<u8>> {
+        fs::read(self.path(self.scripts.get(id)?)?).ok()
+    }
+
+    // where `script` is kept, with a directory
+    fn path(&self, script: &Script) -> Option<PathBuf> {
+        let extension = script.language().extension();
+
+        Some(
+            self.dir
+                .as_ref()?
+                .join(format!("{}.{extension}", script.id)),
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.scripts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+
+    // adds a script `compile`d from `bytes`; uploading the same bytes again
+    // just gives back the same id
+    pub fn insert(&mut self, script: Script, bytes: &[u8]) -> Result<String, ScriptError> {
+        let id = script.id.clone();
+
+        if self.scripts.contains_key(&id) {
+            return Ok(id);
+        }
+
+        if let Some(path) = self.path(&script) {
+            fs::write(path, bytes).map_err(|e| ScriptError::Storage(e.to_string()))?;
+        }
+
+        self.scripts.insert(id.clone(), Arc::new(script));
+
+        Ok(id)
+    }
+
+    // insert on behalf of `owner`, counting against their quota (unless
+    // they uploaded the same thing before). kept until at least
+    // UNASSIGNED_SCRIPT_TICKS after `tick`, see collect
+    pub fn insert_for(
+        &mut self,
+        script: Script,
+        bytes: &[u8],
+        owner: &str,
+        tick: u64,
+    ) -> Result<String, ScriptError> {
+        let uploads = self.uploads.get(owner);
+
+        if !uploads.is_some_and(|uploads| uploads.contains_key(&script.id)) {
+            let (scripts, size) = uploads.map_or((0, 0), |uploads| {
+                (uploads.len(), uploads.values().sum::<usize>())
+            });
+
+            if scripts >= MAX_OWNER_SCRIPTS || size + bytes.len() > MAX_OWNER_SCRIPT_BYTES {
+                return Err(ScriptError::OverQuota {
+                    scripts,
+                    bytes: size,
+                });
+            }
+        }
+
+        let id = self.insert(script, bytes)?;
+
+        self.uploads
+            .entry(owner.to_string())
+            .or_default()
+            .insert(id.clone(), bytes.len());
+        self.uploaded_at.insert(id.clone(), tick);
+
+        Ok(id)
+    }
+
+    // drops (from disk too) every script that isn't `in_use` or about to be
+    // reloaded into, and wasn't uploaded in the UNASSIGNED_SCRIPT_TICKS
+    // before `tick`; that also frees up its uploaders' quota. returns how
+    // many went
+    pub fn collect(&mut self, in_use: &HashSet<&str>, tick: u64) -> usize {
+        let reloading = self
+            .reloads
+            .iter()
+            .map(|reload| reload.to.as_str())
+            .collect::<HashSet<_>>();
+
+        let garbage = self
+            .scripts
+            .keys()
+            .filter(|id| !in_use.contains(id.as_str()) && !reloading.contains(id.as_str()))
+            .filter(|id| {
+                self.uploaded_at
+                    .get(*id)
+                    .is_none_or(|&at| tick >= at + UNASSIGNED_SCRIPT_TICKS)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for id in &garbage {
+            let Some(script) = self.scripts.remove(id) else {
+                continue;
+            };
+
+            self.uploaded_at.remove(id);
+
+            for uploads in self.uploads.values_mut() {
+                uploads.remove(id);
+            }
+
+            if let Some(path) = self.path(&script)
+                && let Err(e) = fs::remove_file(&path)
+            {
+                warn!("failed to remove script {}: {e}", path.display());
+            }
+        }
+
+        self.uploads.retain(|_, uploads| !uploads.is_empty());
+
+        garbage.len()
+    }
+
+    pub fn upload(&mut self, role: ScriptRole, bytes: &[u8]) -> Result<String, ScriptError> {
+        self.upload_as(ScriptLanguage::Wasm, role, bytes)
+    }
+
+    pub fn upload_as(
+        &mut self,
+        language: ScriptLanguage,
+        role: ScriptRole,
+        bytes: &[u8],
+    ) -> Result<String, ScriptError> {
+        let script = compile(&self.engine, language, role, bytes)?;
+
+        self.insert(script, bytes)
+    }
+
+    // moves `owner`'s tiles running `from` over to `to` at the start of the
+    // next tick, see GridState::run_scripts
+    pub fn schedule_reload(&mut self, from: &str, to: &str, owner: &str) {
+        if from != to {
+            self.reloads.push(Reload {
+                from: from.to_string(),
+                to: to.to_string(),
+                owner: owner.to_string(),
+            });
+        }
+    }
+
+    pub(crate) fn take_reloads(&mut self) -> Vec<Reload> {
+        std::mem::take(&mut self.reloads)
+    }
+}
+
+impl Default for ScriptStore {
+    fn default() -> Self {
+        ScriptStore::new()
+    }
+}
+
+// which script runs on a tile, and on whose behalf
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptAssignment {
+    pub script_id: String,
+    // the player that assigned it
+    pub owner: String,
+}
+
+impl GridState {
+    pub fn assign_script(
+        &mut self,
+        x: u32,
+        y: u32,
+        script_id: &str,
+        owner: &str,
+    ) -> Result<(), ScriptError> {
+        let tile = self.get_tile(x, y).ok_or(ScriptError::OutOfBounds)?;
+
+        let script = self
+            .scripts
+            .get(script_id)
+            .ok_or_else(|| ScriptError::UnknownScript(script_id.to_string()))?;
+
+        if !script.fits(tile) {
+            return Err(ScriptError::WrongTile {
+                tile: tile.to_string(),
+            });
+        }
+
+        let index = self.get_index(x, y);
+
+        // first come, first served until the building is gone
+        if let Some(assignment) = self.assignments.get(&index)
+            && assignment.owner != owner
+        {
+            return Err(ScriptError::NotOwner {
+                owner: assignment.owner.clone(),
+            });
+        }
+
+        if let Some(replay) = &mut self.replay {
+            replay.record(ReplayAction::Assign {
+                tick: self.tick,
+                col: x,
+                row: y,
+                script_id: script_id.to_string(),
+                owner: owner.to_string(),
+            });
+        }
+
+        self.set_assignment(
+            index,
+            ScriptAssignment {
+                script_id: script_id.to_string(),
+                owner: owner.to_string(),
+            },
+        );
+
+        Ok(())
+    }
+
+    // an upload by `owner` (see ScriptStore::insert_for), also moving their
+    // tiles running `replaces` over to it on the next tick. both go into the
+    // replay
+    pub fn upload_script(
+        &mut self,
+        script: Script,
+        bytes: &[u8],
+        owner: &str,
+        replaces: Option<&str>,
+    ) -> Result<String, ScriptError> {
+        // reloads only ever touch the uploader's own tiles, but there should
+        // be some
+        if let Some(old) = replaces
+            && !self.runs_script(old, owner)
+        {
+            return Err(ScriptError::NotAssigned(old.to_string()));
+        }
+
+        let (tick, language) = (self.tick, script.language());
+        // any of them compiles it the same
+        let role = script.roles[0];
+
+        let id = self.scripts.insert_for(script, bytes, owner, tick)?;
+
+        if let Some(replay) = &mut self.replay {
+            replay.record(ReplayAction::Upload {
+                tick,
+                language,
+                role,
+                bytes: bytes.to_vec(),
+            });
+        }
+
+        if let Some(old) = replaces {
+            self.scripts.schedule_reload(old, &id, owner);
+
+            if let Some(replay) = &mut self.replay {
+                replay.record(ReplayAction::Reload {
+                    tick,
+                    from: old.to_string(),
+                    to: id.clone(),
+                    owner: owner.to_string(),
+                });
+            }
+        }
+
+        Ok(id)
+    }
+
+    // see ScriptStore::collect
+    pub fn collect_scripts(&mut self) -> usize {
+        let in_use = self
+            .assignments
+            .values()
+            .map(|assignment| assignment.script_id.as_str())
+            .collect::<HashSet<_>>();
+
+        self.scripts.collect(&in_use, self.tick)
+    }
+
+    // whether `owner` runs `script_id` on any tile, i.e. has something for
+    // an upload replacing it to reload
+    pub fn runs_script(&self, script_id: &str, owner: &str) -> bool {
+        self.assignments
+            .values()
+            .any(|assignment| assignment.script_id == script_id && assignment.owner == owner)
+    }
+
+    // called by set_tile; a script belongs to the building it was assigned
+    // to, so it's gone once that's replaced by something else
+    pub(crate) fn drop_stale_assignment(&mut self, index: usize, old: &HexTile, new: &HexTile) {
+        if discriminant(old) != discriminant(new) {
+            self.assignments.remove(&index);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use wasm_encoder::{
+        CodeSection, EntityType, ExportKind, ExportSection, Function, FunctionSection,
+        ImportSection, Instruction, TypeSection, ValType,
+    };
+
+    use crate::types::tests::{mine, turret};
+
+    use super::*;
+
+    // a module exporting an empty `() -> ()` function under each of `exports`,
+    // and importing each of `imports` (module, name) as one too
+    pub(crate) fn module(exports: &[&str], imports: &[(&str, &str)]) -> Vec<u8> {
+        let mut module = wasm_encoder::Module::new();
+
+        let mut types = TypeSection::new();
+        types.ty().function([], []);
+        types.ty().function([ValType::I32], []);
+        module.section(&types);
+
+        let mut import_section = ImportSection::new();
+        for (module, name) in imports {
+            import_section.import(module, name, EntityType::Function(0));
+        }
+        module.section(&import_section);
+
+        let mut functions = FunctionSection::new();
+        let mut code = CodeSection::new();
+        let mut export_section = ExportSection::new();
+
+        for (i, name) in exports.iter().enumerate() {
+            // a `bad_` prefix gets the wrong signature
+            let bad = name.starts_with("bad_");
+
+            functions.function(if bad { 1 } else { 0 });
+
+            let mut body = Function::new([]);
+            body.instruction(&Instruction::End);
+            code.function(&body);
+
+            export_section.export(
+                name.trim_start_matches("bad_"),
+                ExportKind::Func,
+                (imports.len() + i) as u32,
+            );
+        }
+
+        module.section(&functions);
+        module.section(&export_section);
+        module.section(&code);
+
+        module.finish()
+    }
+
+    #[test]
+    fn it_validates_uploads() {
+        let mut store = ScriptStore::new();
+
+        let bytes = module(&["mine_tick", "logistics_tick"], &[]);
+        let id = store.upload(ScriptRole::Mine, &bytes).unwrap();
+
+        assert_eq!(id, script_id(&bytes));
+        assert_eq!(
+            store.get(&id).unwrap().roles,
+            vec![ScriptRole::Mine, ScriptRole::Logistics]
+        );
+
+        // same bytes, same script
+        assert_eq!(store.upload(ScriptRole::Logistics, &bytes), Ok(id));
+        assert_eq!(store.len(), 1);
+
+        assert_eq!(
+            store.upload(ScriptRole::Defender, &bytes).map(|_| ()),
+            Err(ScriptError::MissingEntryPoint(ScriptRole::Defender))
+        );
+        assert_eq!(
+            store
+                .upload(ScriptRole::Mine, &module(&["bad_mine_tick"], &[]))
+                .map(|_| ()),
+            Err(ScriptError::BadEntryPoint(ScriptRole::Mine))
+        );
+        assert_eq!(
+            store
+                .upload(
+                    ScriptRole::Mine,
+                    &module(&["mine_tick"], &[("wasi", "fd_write")])
+                )
+                .map(|_| ()),
+            Err(ScriptError::BadImport {
+                module: "wasi".to_string(),
+                name: "fd_write".to_string()
+            })
+        );
+        assert!(matches!(
+            store.upload(
+                ScriptRole::Mine,
+                &module(&["mine_tick"], &[("plu", "launch_nukes")])
+            ),
+            Err(ScriptError::Invalid(_))
+        ));
+        assert!(matches!(
+            store.upload(ScriptRole::Mine, b"not wasm"),
+            Err(ScriptError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn it_keeps_scripts_on_disk() {
+        let dir = std::env::temp_dir().join(format!("plu-scripts-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let id = ScriptStore::open(&dir)
+            .unwrap()
+            .upload(ScriptRole::Defender, &module(&["defender_tick"], &[]))
+            .unwrap();
+
+        let reopened = ScriptStore::open(&dir).unwrap();
+
+        assert_eq!(reopened.get(&id).unwrap().roles, vec![ScriptRole::Defender]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_assigns_scripts_to_fitting_tiles() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        let id = grid
+            .scripts
+            .upload(ScriptRole::Mine, &module(&["mine_tick"], &[]))
+            .unwrap();
+
+        grid.set_tile(2, 2, mine(0, 10, 0));
+
+        assert!(matches!(
+            grid.assign_script(3, 3, &id, "ada"),
+            Err(ScriptError::WrongTile { .. })
+        ));
+        assert!(matches!(
+            grid.assign_script(2, 2, "cafe", "ada"),
+            Err(ScriptError::UnknownScript(_))
+        ));
+
+        grid.assign_script(2, 2, &id, "ada").unwrap();
+
+        let index = grid.get_index(2, 2);
+        assert_eq!(grid.assignments[&index].owner, "ada");
+
+        // taken, only ada gets to swap it out now
+        assert!(matches!(
+            grid.assign_script(2, 2, &id, "bob"),
+            Err(ScriptError::NotOwner { owner }) if owner == "ada"
+        ));
+        grid.assign_script(2, 2, &id, "ada").unwrap();
+
+        // still a mine, still assigned
+        grid.set_tile(2, 2, mine(0, 10, 0));
+        assert!(grid.assignments.contains_key(&index));
+
+        // demolished
+        grid.set_tile(2, 2, HexTile::Slime);
+        assert!(!grid.assignments.contains_key(&index));
+    }
+
+    // rhai source for the defender role, `padding` bytes longer than it has
+    // to be and different for every `n`
+    fn defender(n: usize, padding: usize) -> Vec<u8> {
+        format!("fn defender_tick() {{}} // {n} {}", " ".repeat(padding)).into_bytes()
+    }
+
+    fn upload_for(
+        store: &mut ScriptStore,
+        bytes: &[u8],
+        owner: &str,
+        tick: u64,
+    ) -> Result<String, ScriptError> {
+        let script = compile(
+            &store.engine,
+            ScriptLanguage::Rhai,
+            ScriptRole::Defender,
+            bytes,
+        )?;
+
+        store.insert_for(script, bytes, owner, tick)
+    }
+
+    #[test]
+    fn it_holds_players_to_an_upload_quota() {
+        let mut store = ScriptStore::new();
+
+        let ids = (0..MAX_OWNER_SCRIPTS)
+            .map(|n| upload_for(&mut store, &defender(n, 0), "ada", 0).unwrap())
+            .collect::<Vec<_>>();
+
+        assert!(matches!(
+            upload_for(&mut store, &defender(99, 0), "ada", 0),
+            Err(ScriptError::OverQuota { scripts, .. }) if scripts == MAX_OWNER_SCRIPTS
+        ));
+
+        // the same thing again costs nothing, and others have their own quota
+        assert_eq!(
+            upload_for(&mut store, &defender(0, 0), "ada", 0).unwrap(),
+            ids[0]
+        );
+        upload_for(&mut store, &defender(99, 0), "bob", 0).unwrap();
+
+        // a few big ones use up the bytes first
+        let big = MAX_SCRIPT_BYTES - 100;
+        let fits = MAX_OWNER_SCRIPT_BYTES / (big + 100);
+
+        for n in 0..fits {
+            upload_for(&mut store, &defender(n, big), "cyd", 0).unwrap();
+        }
+        assert!(matches!(
+            upload_for(&mut store, &defender(fits, big), "cyd", 0),
+            Err(ScriptError::OverQuota { .. })
+        ));
+    }
+
+    #[test]
+    fn it_collects_unassigned_scripts() {
+        let dir = std::env::temp_dir().join(format!("plu-scripts-gc-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+        grid.scripts = ScriptStore::open(&dir).unwrap();
+        grid.set_tile(1, 1, turret());
+
+        let [used, unused, next] =
+            [0, 1, 2].map(|n| upload_for(&mut grid.scripts, &defender(n, 0), "ada", 0).unwrap());
+
+        grid.assign_script(1, 1, &used, "ada").unwrap();
+        grid.scripts.schedule_reload(&used, &next, "ada");
+
+        // still time to assign it
+        grid.tick = UNASSIGNED_SCRIPT_TICKS - 1;
+        assert_eq!(grid.collect_scripts(), 0);
+
+        grid.tick = UNASSIGNED_SCRIPT_TICKS;
+        assert_eq!(grid.collect_scripts(), 1);
+
+        assert!(grid.scripts.get(&used).is_some());
+        assert!(grid.scripts.get(&next).is_some());
+        assert!(grid.scripts.get(&unused).is_none());
+        assert!(!dir.join(format!("{unused}.rhai")).exists());
+        assert_eq!(grid.scripts.uploads["ada"].len(), 2);
+
+        // the reload went through and left the old version unused
+        grid.run_scripts();
+        assert_eq!(grid.collect_scripts(), 1);
+        assert!(grid.scripts.get(&used).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,329 @@
+use std::{sync::Arc, time::Duration};
+
+use log::{debug, error, info};
+use tokio::{
+    sync::{RwLock, watch},
+    time::Instant,
+};
+
+use crate::{
+    UpdateBroadcast,
+    api::{action_log::Actor, grid_api::GridState, tile_store::TileStore},
+    types::{HexTile, MineData, TileState},
+};
+
+pub const DEFAULT_TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+// anything faster just burns cpu, anything slower looks like a hang
+pub const MIN_TICK_INTERVAL: Duration = Duration::from_millis(50);
+pub const MAX_TICK_INTERVAL: Duration = Duration::from_secs(60);
+
+// snapshot the world (and start a fresh journal) every this many ticks, so
+// recovering never has to replay more than that
+pub const CHECKPOINT_EVERY: u64 = 100;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct SimSettings {
+    pub paused: bool,
+    pub tick_interval: Duration,
+    // ticks still to run back to back (while paused) because of a step request
+    pub pending_steps: u32,
+}
+
+// runtime knobs for the game loop, shared with whoever is allowed to turn them
+pub struct SimControl {
+    settings: watch::Sender<SimSettings>,
+}
+
+impl SimControl {
+    pub fn new(tick_interval: Duration) -> Self {
+        SimControl {
+            settings: watch::Sender::new(SimSettings {
+                paused: false,
+                tick_interval: tick_interval.clamp(MIN_TICK_INTERVAL, MAX_TICK_INTERVAL),
+                pending_steps: 0,
+            }),
+        }
+    }
+
+    pub fn settings(&self) -> SimSettings {
+        *self.settings.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<SimSettings> {
+        self.settings.subscribe()
+    }
+
+    // also drops any steps that haven't run yet
+    pub fn pause(&self) {
+        self.settings.send_modify(|s| {
+            s.paused = true;
+            s.pending_steps = 0;
+        });
+    }
+
+    pub fn resume(&self) {
+        self.settings.send_modify(|s| s.paused = false);
+    }
+
+    // pauses, then runs exactly `ticks` more ticks
+    pub fn step(&self, ticks: u32) {
+        self.settings.send_modify(|s| {
+            s.paused = true;
+            s.pending_steps = s.pending_steps.saturating_add(ticks);
+        });
+    }
+
+    // clamped to MIN_TICK_INTERVAL..=MAX_TICK_INTERVAL, returns what was applied
+    pub fn set_tick_interval(&self, interval: Duration) -> Duration {
+        let interval = interval.clamp(MIN_TICK_INTERVAL, MAX_TICK_INTERVAL);
+
+        self.settings.send_modify(|s| s.tick_interval = interval);
+
+        interval
+    }
+
+    // used up by the game loop for every stepped tick; false if none were pending
+    fn take_step(&self) -> bool {
+        self.settings.send_if_modified(|s| {
+            if s.pending_steps == 0 {
+                return false;
+            }
+
+            s.pending_steps -= 1;
+            true
+        })
+    }
+}
+
+impl Default for SimControl {
+    fn default() -> Self {
+        SimControl::new(DEFAULT_TICK_INTERVAL)
+    }
+}
+
+// advances the world by one tick, returning every tile that changed
+pub fn tick(grid: &mut GridState) -> Vec<TileState> {
+    grid.advance_tick();
+
+    let tiles_to_modify = grid
+        .slime_tiles
+        .iter()
+        .flat_map(|t| {
+            let (x, y) = grid.get_coords(*t);
+
+            grid.get_neighbors(x, y)
+                .map(|n| grid.get_coords(n))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut changed = grid.produce_mines();
+    changed.extend(grid.run_scripts());
+
+    for (nx, ny) in tiles_to_modify {
+        let tile = grid.get_tile(nx, ny).unwrap();
+
+        if !matches!(tile, HexTile::Mine(_)) {
+            let index = grid.get_index(nx, ny);
+
+            grid.replace_tile(
+                index,
+                HexTile::Mine(MineData {
+                    count: 1,
+                    level: 1,
+                    capacity: 1,
+                    trade_value: 1,
+                    state: TileStore::default(),
+                }),
+                Actor::Slime,
+            );
+
+            changed.push(index);
+        }
+    }
+
+    changed.sort_unstable();
+    changed.dedup();
+
+    // mine, logistics and defense updates all happened in run_scripts above,
+    // through player scripts or the built-in conductors
+
+    changed.into_iter().map(|i| grid.tile_state(i)).collect()
+}
+
+// runs until `stop` flips to true; that's only checked between ticks, so a
+// tick in progress always finishes
+pub async fn game_loop(
+    state: Arc<RwLock<GridState>>,
+    tx: UpdateBroadcast,
+    sim: Arc<SimControl>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut settings = sim.subscribe();
+    let mut last_tick = Instant::now();
+
+    while !*stop.borrow() {
+        let current = *settings.borrow_and_update();
+
+        // stepped ticks run back to back, otherwise wait out the interval
+        // (forever while paused) or until the settings change
+        if !sim.take_step() {
+            tokio::select! {
+                _ = tokio::time::sleep_until(last_tick + current.tick_interval), if !current.paused => {}
+                _ = settings.changed() => {
+                    // so resuming waits a full interval instead of ticking
+                    // straight away
+                    if current.paused {
+                        last_tick = Instant::now();
+                    }
+
+                    continue;
+                }
+                _ = async { stop.wait_for(|stop| *stop).await.is_ok() } => break,
+            }
+        }
+
+        last_tick = Instant::now();
+
+        debug!("sending update...");
+
+        let mut grid = state.write().await;
+        let updates = tick(&mut grid);
+
+        // durable before anyone gets to see it
+        grid.sync_journal();
+
+        if grid.tick % CHECKPOINT_EVERY == 0 {
+            // a good time to also let go of uploads nobody ended up using
+            let collected = grid.collect_scripts();
+            if collected > 0 {
+                info!("collected {collected} unassigned scripts");
+            }
+
+            if let Err(e) = grid.checkpoint() {
+                error!("failed to checkpoint the world at tick {}: {e}", grid.tick);
+            }
+        }
+
+        // published while still holding the lock, see UpdateChannel::publish
+        if !updates.is_empty() {
+            tx.publish(grid.tick, grid.state_hash(), updates);
+        }
+
+        if !grid.script_logs.is_empty() {
+            tx.publish_logs(std::mem::take(&mut grid.script_logs));
+        }
+    }
+
+    info!("game loop stopped at tick {}", state.read().await.tick);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::network::history::UpdateChannel;
+
+    use super::*;
+
+    fn spawn_loop(
+        sim: Arc<SimControl>,
+    ) -> (
+        Arc<RwLock<GridState>>,
+        watch::Sender<bool>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let state = Arc::new(RwLock::new(GridState::new(10, 10, HexTile::Wild)));
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+        let (stop_tx, stop_rx) = watch::channel(false);
+
+        let handle = tokio::spawn(game_loop(state.clone(), tx, sim, stop_rx));
+
+        (state, stop_tx, handle)
+    }
+
+    // polls until the grid is at least `expected` ticks in
+    async fn wait_for_tick(state: &Arc<RwLock<GridState>>, expected: u64) -> bool {
+        for _ in 0..200 {
+            if state.read().await.tick >= expected {
+                return true;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        false
+    }
+
+    #[test]
+    fn it_spreads_slime_into_mines() {
+        let mut grid = GridState::new(5, 5, HexTile::Wild);
+        grid.set_tile(2, 2, HexTile::Slime);
+
+        let changed = tick(&mut grid);
+
+        assert_eq!(grid.tick, 1);
+        assert_eq!(changed.len(), grid.get_neighbors(2, 2).count());
+        assert!(changed.iter().all(|t| matches!(t.data, HexTile::Mine(_))));
+    }
+
+    #[test]
+    fn it_clamps_tick_interval() {
+        let sim = SimControl::default();
+
+        assert_eq!(sim.set_tick_interval(Duration::ZERO), MIN_TICK_INTERVAL);
+        assert_eq!(
+            sim.set_tick_interval(Duration::from_secs(3600)),
+            MAX_TICK_INTERVAL
+        );
+        assert_eq!(
+            sim.set_tick_interval(Duration::from_millis(250)),
+            Duration::from_millis(250)
+        );
+        assert_eq!(sim.settings().tick_interval, Duration::from_millis(250));
+    }
+
+    #[tokio::test]
+    async fn it_runs_exactly_the_requested_steps() {
+        let sim = Arc::new(SimControl::new(MAX_TICK_INTERVAL));
+        sim.pause();
+
+        let (state, stop, handle) = spawn_loop(sim.clone());
+
+        sim.step(3);
+        assert!(wait_for_tick(&state, 3).await);
+
+        // and then stays paused
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(state.read().await.tick, 3);
+        assert!(sim.settings().paused);
+        assert_eq!(sim.settings().pending_steps, 0);
+
+        let _ = stop.send(true);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_picks_up_a_new_tick_rate() {
+        let sim = Arc::new(SimControl::new(MAX_TICK_INTERVAL));
+
+        let (state, stop, handle) = spawn_loop(sim.clone());
+
+        // would take a minute at the old rate
+        sim.set_tick_interval(MIN_TICK_INTERVAL);
+        assert!(wait_for_tick(&state, 2).await);
+
+        // a tick that already started still finishes
+        sim.pause();
+        tokio::time::sleep(MIN_TICK_INTERVAL).await;
+        let paused_at = state.read().await.tick;
+
+        tokio::time::sleep(MIN_TICK_INTERVAL * 4).await;
+        assert_eq!(state.read().await.tick, paused_at);
+
+        sim.resume();
+        assert!(wait_for_tick(&state, paused_at + 1).await);
+
+        let _ = stop.send(true);
+        handle.await.unwrap();
+    }
+}
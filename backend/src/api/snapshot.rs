@@ -0,0 +1,192 @@
+use std::{fs, io, path::Path};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{
+        game::{Message, Rejection},
+        grid_api::GridState,
+        scripts::ScriptAssignment,
+    },
+    types::{HexTile, Terrain},
+};
+
+// on-disk form of a GridState; like the chunk maps it only lists tiles that
+// differ from the defaults, so mostly empty worlds stay small
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct GridSnapshot {
+    pub width: usize,
+    pub height: usize,
+    pub tick: u64,
+    pub starter_tile: HexTile,
+    pub tiles: Vec<(u32, u32, HexTile)>,
+    pub terrain: Vec<(u32, u32, Terrain)>,
+    // older saves predate scripts
+    #[serde(default)]
+    pub scripts: Vec<(u32, u32, ScriptAssignment)>,
+    // what conductors get told about on the next tick
+    #[serde(default)]
+    pub rejections: Vec<(u32, u32, Vec<Rejection>)>,
+    #[serde(default)]
+    pub inboxes: Vec<(u32, u32, Vec<Message>)>,
+}
+
+impl GridSnapshot {
+    pub fn capture(grid: &GridState) -> Self {
+        let mut snapshot = GridSnapshot {
+            width: grid.width,
+            height: grid.height,
+            tick: grid.tick,
+            starter_tile: grid.tiles.default_value().clone(),
+            tiles: grid
+                .tiles
+                .iter_non_default()
+                .map(|(x, y, t)| (x, y, t.clone()))
+                .collect(),
+            terrain: grid
+                .terrain
+                .iter_non_default()
+                .map(|(x, y, t)| (x, y, *t))
+                .collect(),
+            scripts: grid.by_coords(&grid.assignments),
+            rejections: grid.by_coords(&grid.rejections),
+            inboxes: grid.by_coords(&grid.inboxes),
+        };
+
+        // chunks come out in hash order, keep saves stable
+        snapshot.tiles.sort_by_key(|&(x, y, _)| (y, x));
+        snapshot.terrain.sort_by_key(|&(x, y, _)| (y, x));
+
+        snapshot
+    }
+
+    // goes through set_tile so the per-type tile lists are rebuilt too
+    pub fn restore(self) -> GridState {
+        let mut grid = GridState::new(self.width, self.height, self.starter_tile);
+        grid.tick = self.tick;
+
+        for (x, y, tile) in self.tiles {
+            grid.set_tile(x, y, tile);
+        }
+
+        for (x, y, terrain) in self.terrain {
+            grid.set_terrain(x, y, terrain);
+        }
+
+        // the scripts themselves are loaded separately, see ScriptStore::open
+        grid.assignments = grid.by_index(self.scripts);
+        grid.rejections = grid.by_index(self.rejections);
+        grid.inboxes = grid.by_index(self.inboxes);
+
+        grid
+    }
+}
+
+// writes to a temporary file first so a crash mid-save can't leave a
+// half written world behind
+pub fn save(grid: &GridState, path: &Path) -> io::Result<()> {
+    let json = serde_json::to_vec(&GridSnapshot::capture(grid))?;
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, json)?;
+    fs::rename(&tmp, path)?;
+
+    info!("saved world at tick {} to {}", grid.tick, path.display());
+
+    Ok(())
+}
+
+// None if there's no save yet
+pub fn load(path: &Path) -> io::Result<Option<GridState>> {
+    let json = match fs::read(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let snapshot: GridSnapshot = serde_json::from_slice(&json)?;
+
+    info!(
+        "loaded world at tick {} from {}",
+        snapshot.tick,
+        path.display()
+    );
+
+    Ok(Some(snapshot.restore()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{api::tile_store::TileStore, types::MineData};
+
+    use super::*;
+
+    fn sample_grid() -> GridState {
+        let mut grid = GridState::new(40, 30, HexTile::Wild);
+        grid.tick = 12;
+
+        let mut state = TileStore::default();
+        state.version = 1;
+        state.set("saved", "yes").unwrap();
+
+        grid.set_tile(3, 4, HexTile::Slime);
+        grid.set_tile(
+            35,
+            20,
+            HexTile::Mine(MineData {
+                level: 2,
+                count: 1,
+                capacity: 5,
+                state,
+                trade_value: 0,
+            }),
+        );
+        grid.set_terrain(35, 20, Terrain::Ore { richness: 3 });
+
+        grid
+    }
+
+    #[test]
+    fn it_only_captures_non_default_tiles() {
+        let snapshot = GridSnapshot::capture(&sample_grid());
+
+        assert_eq!(snapshot.tick, 12);
+        assert_eq!(snapshot.tiles.len(), 2);
+        assert_eq!(
+            snapshot.terrain,
+            vec![(35, 20, Terrain::Ore { richness: 3 })]
+        );
+    }
+
+    #[test]
+    fn it_restores_tiles_and_registrations() {
+        let grid = GridSnapshot::capture(&sample_grid()).restore();
+        let original = sample_grid();
+
+        assert_eq!((grid.width, grid.height, grid.tick), (40, 30, 12));
+        assert_eq!(grid.get_tile(3, 4), original.get_tile(3, 4));
+        assert_eq!(grid.get_tile(35, 20), original.get_tile(35, 20));
+        assert_eq!(grid.get_terrain(35, 20), original.get_terrain(35, 20));
+
+        assert_eq!(grid.slime_tiles, vec![grid.get_index(3, 4)]);
+        assert_eq!(grid.mine_tiles, vec![grid.get_index(35, 20)]);
+    }
+
+    #[test]
+    fn it_saves_and_loads_from_disk() {
+        let path = std::env::temp_dir().join(format!("plu-snapshot-{}.json", std::process::id()));
+
+        save(&sample_grid(), &path).unwrap();
+        let loaded = load(&path).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            GridSnapshot::capture(&loaded),
+            GridSnapshot::capture(&sample_grid())
+        );
+
+        // nothing saved yet
+        assert!(load(&path).unwrap().is_none());
+    }
+}
@@ -0,0 +1,120 @@
+use serde::Serialize;
+
+use crate::{api::grid_api::GridState, types::HexTile};
+
+// summary of the world at one tick, for comparing balance between runs
+#[derive(PartialEq, Debug, Clone, Serialize)]
+pub struct WorldStats {
+    pub tick: u64,
+    pub slime_tiles: usize,
+    // fraction of the map covered in slime, 0..=1
+    pub slime_coverage: f64,
+    pub mines: usize,
+    pub turrets: usize,
+    // gold currently stored in all mines together
+    pub total_gold: u64,
+    // slime the turrets shot down during the last tick
+    pub turret_kills: usize,
+}
+
+impl WorldStats {
+    pub fn collect(grid: &GridState) -> Self {
+        let total_gold = grid
+            .mine_tiles
+            .iter()
+            .map(|&i| {
+                let (x, y) = grid.get_coords(i);
+
+                match grid.get_tile(x, y) {
+                    Some(HexTile::Mine(mine)) => mine.count as u64,
+                    _ => 0,
+                }
+            })
+            .sum();
+
+        let area = (grid.width * grid.height).max(1);
+
+        WorldStats {
+            tick: grid.tick,
+            slime_tiles: grid.slime_tiles.len(),
+            slime_coverage: grid.slime_tiles.len() as f64 / area as f64,
+            mines: grid.mine_tiles.len(),
+            turrets: grid.turret_tiles.len(),
+            total_gold,
+            turret_kills: grid.turret_kills,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{simulation, tile_store::TileStore},
+        types::{MineData, TurretData, tests::turret},
+    };
+
+    use super::*;
+
+    #[test]
+    fn it_summarizes_the_grid() {
+        let mut grid = GridState::new(4, 5, HexTile::Wild);
+
+        grid.set_tile(0, 0, HexTile::Slime);
+        grid.set_tile(1, 0, HexTile::Slime);
+        grid.set_tile(
+            2,
+            3,
+            HexTile::Turret(TurretData {
+                level: 1,
+                state: TileStore::default(),
+            }),
+        );
+
+        for (x, count) in [(0, 3), (3, 4)] {
+            grid.set_tile(
+                x,
+                4,
+                HexTile::Mine(MineData {
+                    level: 1,
+                    count,
+                    capacity: 10,
+                    state: TileStore::default(),
+                    trade_value: 0,
+                }),
+            );
+        }
+
+        grid.tick = 12;
+
+        assert_eq!(
+            WorldStats::collect(&grid),
+            WorldStats {
+                tick: 12,
+                slime_tiles: 2,
+                slime_coverage: 0.1,
+                mines: 2,
+                turrets: 1,
+                total_gold: 7,
+                turret_kills: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn it_counts_slime_the_turrets_shot() {
+        let mut grid = GridState::new(8, 8, HexTile::Wild);
+
+        // out of the slime's reach, but not the other way around
+        grid.set_tile(2, 2, turret());
+        grid.set_tile(4, 2, HexTile::Slime);
+
+        simulation::tick(&mut grid);
+
+        assert_eq!(grid.get_tile(4, 2), Some(&HexTile::Wild));
+        assert_eq!(WorldStats::collect(&grid).turret_kills, 1);
+
+        // nothing left to shoot
+        simulation::tick(&mut grid);
+        assert_eq!(WorldStats::collect(&grid).turret_kills, 0);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+// limits per tile, so one script can't bloat every update and save
+pub const MAX_STATE_KEYS: usize = 32;
+pub const MAX_STATE_KEY_LEN: usize = 32;
+// keys and values together
+pub const MAX_STATE_BYTES: usize = 2048;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum StateError {
+    // not [A-Za-z_][A-Za-z0-9_]*, or longer than MAX_STATE_KEY_LEN
+    InvalidKey(String),
+    TooManyKeys,
+    TooLarge { size: usize },
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidKey(key) => write!(f, "invalid state key {key:?}"),
+            Self::TooManyKeys => write!(f, "tile state is limited to {MAX_STATE_KEYS} keys"),
+            Self::TooLarge { size } => write!(
+                f,
+                "tile state would take {size} bytes, the limit is {MAX_STATE_BYTES}"
+            ),
+        }
+    }
+}
+
+// what a tile's script keeps between ticks: a small string map plus the
+// version of the layout it's stored in, so a newer script can tell old data
+// apart and migrate it. only changes through the methods below, which keep it
+// inside the quotas; anything deserialized from elsewhere needs a `check`
+#[derive(PartialEq, Debug, Clone, Default, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/types/types.ts")]
+pub struct TileStore {
+    pub version: u32,
+    entries: BTreeMap<String, String>,
+}
+
+// keys are plain identifiers; besides being readable that keeps them from
+// looking like array indices, which js would reorder (and so break the map
+// hash on the client)
+fn valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+
+    key.len() <= MAX_STATE_KEY_LEN
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl TileStore {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    // leaves the store untouched if the result wouldn't fit
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), StateError> {
+        if !valid_key(key) {
+            return Err(StateError::InvalidKey(key.to_string()));
+        }
+
+        let replaced = self.entries.get(key).map(|old| key.len() + old.len());
+
+        if replaced.is_none() && self.entries.len() >= MAX_STATE_KEYS {
+            return Err(StateError::TooManyKeys);
+        }
+
+        let size = self.size() - replaced.unwrap_or(0) + key.len() + value.len();
+
+        if size > MAX_STATE_BYTES {
+            return Err(StateError::TooLarge { size });
+        }
+
+        self.entries.insert(key.to_string(), value.to_string());
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.entries.remove(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // bytes counted against MAX_STATE_BYTES
+    pub fn size(&self) -> usize {
+        self.entries.iter().map(|(k, v)| k.len() + v.len()).sum()
+    }
+
+    // for stores that didn't come through `set`, e.g. sent by a client
+    pub fn check(&self) -> Result<(), StateError> {
+        if let Some(key) = self.entries.keys().find(|k| !valid_key(k)) {
+            return Err(StateError::InvalidKey(key.clone()));
+        }
+
+        if self.entries.len() > MAX_STATE_KEYS {
+            return Err(StateError::TooManyKeys);
+        }
+
+        match self.size() {
+            size if size > MAX_STATE_BYTES => Err(StateError::TooLarge { size }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_stores_and_replaces_values() {
+        let mut store = TileStore::default();
+
+        store.set("target", "3,4").unwrap();
+        store.set("mode", "idle").unwrap();
+        store.set("mode", "hunting").unwrap();
+
+        assert_eq!(store.get("mode"), Some("hunting"));
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.size(), "target3,4modehunting".len());
+
+        assert_eq!(store.remove("target"), Some("3,4".to_string()));
+        assert_eq!(store.get("target"), None);
+    }
+
+    #[test]
+    fn it_enforces_the_quotas() {
+        let mut store = TileStore::default();
+
+        for key in ["", "1st", "has space", &"k".repeat(MAX_STATE_KEY_LEN + 1)] {
+            assert_eq!(
+                store.set(key, "x"),
+                Err(StateError::InvalidKey(key.to_string()))
+            );
+        }
+
+        for i in 0..MAX_STATE_KEYS {
+            store.set(&format!("k{i}"), "").unwrap();
+        }
+
+        assert_eq!(store.set("one_more", ""), Err(StateError::TooManyKeys));
+
+        // existing keys can still be overwritten, up to the byte limit
+        let fits = "x".repeat(MAX_STATE_BYTES - store.size());
+        store.set("k0", &fits).unwrap();
+
+        assert!(matches!(
+            store.set("k1", "x"),
+            Err(StateError::TooLarge { .. })
+        ));
+        assert_eq!(store.get("k1"), Some(""));
+        assert_eq!(store.size(), MAX_STATE_BYTES);
+    }
+
+    #[test]
+    fn it_checks_deserialized_stores() {
+        let store: TileStore =
+            serde_json::from_str(r#"{"version":2,"entries":{"hits":"7"}}"#).unwrap();
+
+        assert_eq!(store.version, 2);
+        assert_eq!(store.check(), Ok(()));
+
+        let store: TileStore =
+            serde_json::from_str(r#"{"version":1,"entries":{"0":"sneaky"}}"#).unwrap();
+
+        assert_eq!(store.check(), Err(StateError::InvalidKey("0".to_string())));
+    }
+}
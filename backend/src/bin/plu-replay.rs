@@ -0,0 +1,40 @@
+// re-runs a replay recorded by the server (see PLU_REPLAY_PATH) and checks
+// that it ends up in exactly the recorded state
+//
+// usage: plu-replay <replay.json>
+
+use std::path::PathBuf;
+
+use backend::api::replay::Replay;
+
+fn main() {
+    env_logger::init();
+
+    let Some(path) = std::env::args().nth(1).map(PathBuf::from) else {
+        eprintln!("usage: plu-replay <replay.json>");
+        std::process::exit(2);
+    };
+
+    let replay = match Replay::load(&path) {
+        Ok(replay) => replay,
+        Err(e) => {
+            eprintln!("failed to load replay from {}: {e}", path.display());
+            std::process::exit(2);
+        }
+    };
+
+    println!(
+        "replaying {} actions from tick {} to {}",
+        replay.actions.len(),
+        replay.initial.tick,
+        replay.final_tick
+    );
+
+    match replay.verify() {
+        Ok(grid) => println!("ok: tick {} hash {:016x}", grid.tick, grid.state_hash()),
+        Err(mismatch) => {
+            eprintln!("mismatch: {mismatch}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+// runs a player script against a small text map (see api::script_harness for
+// the format) without a server: prints what it logged, the map it ended up
+// with, and the state, turned down intents and cost of every tile it ran on.
+// with --expect, fails unless the final map matches the one in that file
+//
+// usage: plu-script-test --script bot.wasm --role mine --map map.txt
+//                        [--ticks N] [--expect final.txt]
+//
+// scripts ending in .rhai are taken as rhai source, anything else as wasm
+
+use std::{fs, path::PathBuf};
+
+use backend::api::{
+    script_harness::Harness,
+    script_host::SCRIPT_FUEL,
+    scripts::{ScriptLanguage, ScriptRole},
+};
+
+#[derive(PartialEq, Debug)]
+struct Args {
+    script: PathBuf,
+    role: ScriptRole,
+    map: PathBuf,
+    ticks: u64,
+    expect: Option<PathBuf>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut script = None;
+    let mut role = ScriptRole::Mine;
+    let mut map = None;
+    let mut ticks = 1;
+    let mut expect = None;
+
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+
+        match flag.as_str() {
+            "--script" => script = Some(PathBuf::from(value)),
+            "--role" => {
+                role = ScriptRole::ALL
+                    .into_iter()
+                    .find(|role| role.to_string() == value)
+                    .ok_or_else(|| format!("unknown role {value}"))?;
+            }
+            "--map" => map = Some(PathBuf::from(value)),
+            "--ticks" => {
+                ticks = value.parse().map_err(|e| format!("{flag} {value}: {e}"))?;
+            }
+            "--expect" => expect = Some(PathBuf::from(value)),
+            _ => return Err(format!("unknown flag {flag}")),
+        }
+    }
+
+    Ok(Args {
+        script: script.ok_or("missing --script")?,
+        role,
+        map: map.ok_or("missing --map")?,
+        ticks,
+        expect,
+    })
+}
+
+// the report printed at the end, and whether the expected map (if any) matched
+fn run(args: &Args) -> Result<(String, bool), String> {
+    let read = |path: &PathBuf| fs::read(path).map_err(|e| format!("{}: {e}", path.display()));
+
+    let map = String::from_utf8_lossy(&read(&args.map)?).into_owned();
+    let mut harness = Harness::new(&map).map_err(|e| format!("{}: {e}", args.map.display()))?;
+
+    let language = match args.script.extension() {
+        Some(extension) if extension == ScriptLanguage::Rhai.extension() => ScriptLanguage::Rhai,
+        _ => ScriptLanguage::Wasm,
+    };
+
+    let id = harness
+        .load(language, args.role, &read(&args.script)?)
+        .map_err(|e| format!("{}: {e}", args.script.display()))?;
+    let assigned = harness.assign_everywhere(&id).map_err(|e| e.to_string())?;
+
+    harness.run(args.ticks);
+
+    let mut report = format!(
+        "ran script {id} on {assigned} tiles for {} ticks\n",
+        args.ticks
+    );
+
+    for log in &harness.logs {
+        report += &format!(
+            "[tick {}] <{}, {}> {:?}: {}\n",
+            log.tick, log.col, log.row, log.level, log.message
+        );
+    }
+
+    let result = harness.map();
+    report += &format!("\n{result}\n");
+
+    for &index in harness.grid.assignments.keys() {
+        let (x, y) = harness.grid.get_coords(index);
+
+        if let Some(state) = harness.state(x, y) {
+            let entries = state
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            report += &format!("<{x}, {y}> v{} {entries}\n", state.version);
+        }
+
+        if let Some(profile) = harness.grid.profiler.get(index) {
+            report += &format!(
+                "<{x}, {y}> fuel {} avg, {} peak of {SCRIPT_FUEL}; {}us avg",
+                profile.total_fuel / profile.ticks,
+                profile.peak_fuel,
+                profile.total_wall_us / profile.ticks,
+            );
+
+            // rhai doesn't report any
+            if let Some(memory) = profile.peak_memory {
+                report += &format!("; {memory} bytes of memory");
+            }
+
+            report += "\n";
+        }
+
+        // only the last tick's
+        for rejection in harness.grid.rejections.get(&index).into_iter().flatten() {
+            report += &format!(
+                "<{x}, {y}> rejected {:?}: {:?}\n",
+                rejection.intent, rejection.reason
+            );
+        }
+    }
+
+    let matched = match &args.expect {
+        Some(path) => {
+            let expected = Harness::new(&String::from_utf8_lossy(&read(path)?))
+                .map_err(|e| format!("{}: {e}", path.display()))?
+                .map();
+
+            if expected != result {
+                report += &format!("\nexpected\n\n{expected}");
+            }
+
+            expected == result
+        }
+        None => true,
+    };
+
+    Ok((report, matched))
+}
+
+fn main() {
+    env_logger::init();
+
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!(
+                "usage: plu-script-test --script bot.wasm --role mine --map map.txt [--ticks N] [--expect final.txt]"
+            );
+            std::process::exit(2);
+        }
+    };
+
+    match run(&args) {
+        Ok((report, true)) => print!("{report}"),
+        Ok((report, false)) => {
+            print!("{report}");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn it_parses_flags() {
+        let parsed = parse_args(args(&[
+            "--script", "bot.wasm", "--map", "m.txt", "--role", "defender", "--ticks", "5",
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            parsed,
+            Args {
+                script: PathBuf::from("bot.wasm"),
+                role: ScriptRole::Defender,
+                map: PathBuf::from("m.txt"),
+                ticks: 5,
+                expect: None,
+            }
+        );
+
+        assert!(parse_args(args(&["--map", "m.txt"])).is_err());
+        assert!(parse_args(args(&["--script", "a", "--map", "b", "--role", "pilot"])).is_err());
+        assert!(parse_args(args(&["--script", "a", "--map", "b", "--ticks", "many"])).is_err());
+    }
+}
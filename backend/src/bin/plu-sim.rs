@@ -0,0 +1,336 @@
+// headless simulation runner for balance testing: runs the same tick logic as
+// the server, without any networking, over a range of seeds and writes world
+// stats over time as csv (or json, if the output file ends in .json)
+//
+// usage: plu-sim [--config sim.json] [--map world.json] [--ticks N] [--seeds N]
+//                [--first-seed N] [--out stats.csv]
+
+use std::{
+    fs, io,
+    io::Write,
+    mem,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use backend::{
+    api::{
+        grid_api::GridState,
+        simulation,
+        snapshot::{self, GridSnapshot},
+        stats::WorldStats,
+    },
+    types::HexTile,
+};
+use log::info;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::{Deserialize, Serialize};
+
+#[derive(PartialEq, Debug, Clone, Deserialize)]
+#[serde(default)]
+struct SimConfig {
+    width: usize,
+    height: usize,
+    // world to start every run from instead of generating terrain
+    map: Option<PathBuf>,
+    ticks: u64,
+    seeds: u64,
+    first_seed: u64,
+    // slime tiles dropped at random on top of the starting world
+    slime_spawns: u32,
+    // record stats every this many ticks
+    sample_every: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            width: 20,
+            height: 40,
+            map: None,
+            ticks: 100,
+            seeds: 1,
+            first_seed: 0,
+            slime_spawns: 3,
+            sample_every: 1,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Serialize)]
+struct Sample {
+    seed: u64,
+    #[serde(flatten)]
+    stats: WorldStats,
+}
+
+struct Args {
+    config: SimConfig,
+    out: Option<PathBuf>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut config = SimConfig::default();
+    let mut out = None;
+
+    let mut overrides = Vec::new();
+
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {flag}"))?;
+
+        match flag.as_str() {
+            // read first, so flags override it no matter the order
+            "--config" => {
+                let json = fs::read_to_string(&value).map_err(|e| format!("{value}: {e}"))?;
+                config = serde_json::from_str(&json).map_err(|e| format!("{value}: {e}"))?;
+            }
+            "--out" => out = Some(PathBuf::from(value)),
+            _ => overrides.push((flag, value)),
+        }
+    }
+
+    for (flag, value) in overrides {
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|e| format!("{flag} {value}: {e}"))
+        };
+
+        match flag.as_str() {
+            "--map" => config.map = Some(PathBuf::from(&value)),
+            "--ticks" => config.ticks = number()?,
+            "--seeds" => config.seeds = number()?,
+            "--first-seed" => config.first_seed = number()?,
+            _ => return Err(format!("unknown flag {flag}")),
+        }
+    }
+
+    Ok(Args { config, out })
+}
+
+// the starting world for one seed
+fn setup(config: &SimConfig, base: Option<&GridSnapshot>, seed: u64) -> GridState {
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut grid = match base {
+        Some(snapshot) => snapshot.clone().restore(),
+        None => {
+            let mut grid = GridState::new(config.width, config.height, HexTile::Wild);
+            grid.generate_terrain(&mut rng);
+            grid
+        }
+    };
+
+    for _ in 0..config.slime_spawns {
+        let x = rng.random_range(0..grid.width as u32);
+        let y = rng.random_range(0..grid.height as u32);
+
+        grid.set_tile(x, y, HexTile::Slime);
+    }
+
+    grid
+}
+
+fn run(config: &SimConfig, base: Option<&GridSnapshot>, seed: u64) -> Vec<Sample> {
+    let mut grid = setup(config, base, seed);
+    let sample_every = config.sample_every.max(1);
+
+    let mut samples = vec![Sample {
+        seed,
+        stats: WorldStats::collect(&grid),
+    }];
+
+    let mut kills = 0;
+
+    for i in 1..=config.ticks {
+        simulation::tick(&mut grid);
+        kills += grid.turret_kills;
+
+        if i % sample_every == 0 || i == config.ticks {
+            // every kill since the previous sample, not just this tick's
+            let stats = WorldStats {
+                turret_kills: mem::take(&mut kills),
+                ..WorldStats::collect(&grid)
+            };
+
+            samples.push(Sample { seed, stats });
+        }
+    }
+
+    info!("seed {seed} done at tick {}", grid.tick);
+
+    samples
+}
+
+// seeds are independent, so they're spread over all cores; results still come
+// back in seed order
+fn run_all(config: &SimConfig, base: Option<&GridSnapshot>) -> Vec<Sample> {
+    let seeds = (config.first_seed..config.first_seed + config.seeds).collect::<Vec<_>>();
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    let per_worker = seeds.len().div_ceil(workers).max(1);
+
+    thread::scope(|scope| {
+        let handles = seeds
+            .chunks(per_worker)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .flat_map(|&seed| run(config, base, seed))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+fn write_csv(samples: &[Sample], out: &mut impl Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "seed,tick,slime_tiles,slime_coverage,mines,turrets,total_gold,turret_kills"
+    )?;
+
+    for Sample { seed, stats } in samples {
+        writeln!(
+            out,
+            "{seed},{},{},{:.4},{},{},{},{}",
+            stats.tick,
+            stats.slime_tiles,
+            stats.slime_coverage,
+            stats.mines,
+            stats.turrets,
+            stats.total_gold,
+            stats.turret_kills
+        )?;
+    }
+
+    Ok(())
+}
+
+fn write_samples(samples: &[Sample], out: Option<&Path>) -> io::Result<()> {
+    match out {
+        Some(path) if path.extension().is_some_and(|e| e == "json") => {
+            fs::write(path, serde_json::to_string_pretty(samples)?)
+        }
+        Some(path) => write_csv(samples, &mut io::BufWriter::new(fs::File::create(path)?)),
+        None => write_csv(samples, &mut io::stdout().lock()),
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    let Args { config, out } = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+
+    let base = config.map.as_ref().map(|path| match snapshot::load(path) {
+        Ok(Some(grid)) => GridSnapshot::capture(&grid),
+        Ok(None) => panic!("no world found at {}", path.display()),
+        Err(e) => panic!("failed to load world from {}: {e}", path.display()),
+    });
+
+    info!("running {} seeds for {} ticks", config.seeds, config.ticks);
+
+    let samples = run_all(&config, base.as_ref());
+
+    if let Err(e) = write_samples(&samples, out.as_deref()) {
+        eprintln!("failed to write stats: {e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn it_parses_flags_over_defaults() {
+        let Args { config, out } =
+            parse_args(args(&["--ticks", "500", "--seeds", "20", "--out", "x.csv"])).unwrap();
+
+        assert_eq!(config.ticks, 500);
+        assert_eq!(config.seeds, 20);
+        assert_eq!(config.width, SimConfig::default().width);
+        assert_eq!(out, Some(PathBuf::from("x.csv")));
+
+        assert!(parse_args(args(&["--ticks"])).is_err());
+        assert!(parse_args(args(&["--ticks", "lots"])).is_err());
+        assert!(parse_args(args(&["--speed", "3"])).is_err());
+    }
+
+    #[test]
+    fn it_is_deterministic_per_seed() {
+        let config = SimConfig {
+            width: 12,
+            height: 12,
+            ticks: 10,
+            seeds: 3,
+            sample_every: 5,
+            ..SimConfig::default()
+        };
+
+        let first = run_all(&config, None);
+        let second = run_all(&config, None);
+
+        assert_eq!(first, second);
+
+        // start, tick 5 and tick 10 for each seed, in seed order
+        assert_eq!(
+            first
+                .iter()
+                .map(|s| (s.seed, s.stats.tick))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, 0),
+                (0, 5),
+                (0, 10),
+                (1, 0),
+                (1, 5),
+                (1, 10),
+                (2, 0),
+                (2, 5),
+                (2, 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn it_writes_one_csv_row_per_sample() {
+        let config = SimConfig {
+            ticks: 3,
+            ..SimConfig::default()
+        };
+
+        let samples = run(&config, None, 7);
+
+        let mut out = Vec::new();
+        write_csv(&samples, &mut out).unwrap();
+
+        let csv = String::from_utf8(out).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 1 + 4);
+        assert!(lines[0].starts_with("seed,tick,"));
+        assert!(lines[0].ends_with(",turret_kills"));
+        assert!(lines[4].starts_with("7,3,"));
+    }
+}
@@ -0,0 +1,9 @@
+use std::sync::Arc;
+
+use crate::network::history::UpdateChannel;
+
+pub mod api;
+pub mod network;
+pub mod types;
+
+pub type UpdateBroadcast = Arc<UpdateChannel>;
@@ -1,12 +1,26 @@
-use std::{sync::Arc, time::Duration};
-
-use log::debug;
-use tokio::sync::{RwLock, broadcast};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::{
-    api::grid_api::GridState,
-    network::ws::WebSocketServer,
-    types::{HexTile, MineData, TileState},
+use log::{error, info, warn};
+use rand::{SeedableRng, rngs::StdRng};
+use tokio::sync::{RwLock, watch};
+
+use backend::{
+    api::{
+        grid_api::GridState,
+        journal::{self, Journal},
+        replay::Replay,
+        scripts::ScriptStore,
+        simulation::{self, SimControl},
+        snapshot,
+    },
+    network::{
+        history::UpdateChannel,
+        ws::{ShutdownNotice, WebSocketServer},
+    },
+    types::HexTile,
 };
 
 const MAP_WIDTH: usize = 20;
@@ -15,69 +29,48 @@ const STARTER_TILE: HexTile = HexTile::Wild;
 
 const SERVER_URL: &str = "0.0.0.0:9001";
 
-pub mod api;
-pub mod network;
-pub mod types;
-
-pub type UpdateBroadcast = broadcast::Sender<Vec<TileState>>;
-
-async fn game_loop(state: Arc<RwLock<GridState>>, tx: UpdateBroadcast) {
-    let mut interval = tokio::time::interval(Duration::from_secs(5));
-
-    loop {
-        interval.tick().await;
-
-        {
-            debug!("sending update...");
-
-            let tiles_to_modify = {
-                let grid = state.read().await;
-
-                grid.slime_tiles
-                    .iter()
-                    .flat_map(|t| {
-                        let (x, y) = grid.get_coords(*t);
-
-                        grid.get_neighbors(x, y)
-                            .map(|n| grid.get_coords(n))
-                            .collect::<Vec<_>>()
-                    })
-                    .collect::<Vec<_>>()
-            };
-
-            {
-                let mut grid = state.write().await;
-
-                for (nx, ny) in tiles_to_modify {
-                    let tile = grid.get_tile(nx, ny).unwrap();
-
-                    if !matches!(tile, HexTile::Mine(_)) {
-                        grid.set_tile(
-                            nx,
-                            ny,
-                            HexTile::Mine(MineData {
-                                count: 1,
-                                level: 1,
-                                capacity: 1,
-                                trade_value: 1,
-                                state: "".to_string(),
-                            }),
-                        );
-                    }
-                }
-            }
-
-            // 3 updates to happen (maybe not in this order)
-            // 1. miner update
-            // 2. logistics update
-            // 3. defense update
-            // this will be handled via wasm, but for now it'll be implementations of the
-            // rust traits
-        }
-
-        // if !updates.is_empty() {
-        //     let _ = tx.send(updates);
-        // }
+// token clients authenticate with to pause/step/speed up the simulation
+const ADMIN_TOKEN_ENV: &str = "PLU_ADMIN_TOKEN";
+
+// where the world is loaded from on startup and saved to on shutdown
+const SAVE_PATH: &str = "world.json";
+// every change since that save, replayed on top of it after a crash
+const JOURNAL_PATH: &str = "world.jsonl";
+// uploaded player scripts, one <id>.wasm each
+const SCRIPTS_DIR: &str = "scripts";
+// set to record every player edit and script upload/assignment this session
+// into a replay file, written out on shutdown
+const REPLAY_PATH_ENV: &str = "PLU_REPLAY_PATH";
+// seconds clients are asked to wait before reconnecting after a shutdown
+const RECONNECT_AFTER: u32 = 10;
+
+// per connection buffer before it counts as lagging, and how many updates we
+// keep around to catch lagging/reconnecting clients up without a full resend
+const BROADCAST_CAPACITY: usize = 100;
+const HISTORY_LEN: usize = 256;
+
+// resolves on ctrl-c, or SIGTERM on unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
 }
 
@@ -85,24 +78,117 @@ async fn game_loop(state: Arc<RwLock<GridState>>, tx: UpdateBroadcast) {
 async fn main() {
     env_logger::init();
 
-    let state = Arc::new(RwLock::new(GridState::new(
-        MAP_WIDTH,
-        MAP_HEIGHT,
-        STARTER_TILE,
-    )));
+    let save_path = Path::new(SAVE_PATH);
+
+    // only known for freshly generated worlds
+    let mut seed = None;
+
+    let mut grid = match snapshot::load(save_path) {
+        Ok(Some(grid)) => grid,
+        Ok(None) => {
+            let terrain_seed = rand::random();
+            seed = Some(terrain_seed);
+
+            let mut grid = GridState::new(MAP_WIDTH, MAP_HEIGHT, STARTER_TILE);
+            grid.generate_terrain(&mut StdRng::seed_from_u64(terrain_seed));
+            grid
+        }
+        Err(e) => panic!("failed to load world from {SAVE_PATH}: {e}"),
+    };
+
+    match ScriptStore::open(Path::new(SCRIPTS_DIR)) {
+        Ok(scripts) => grid.scripts = scripts,
+        Err(e) => panic!("failed to load scripts from {SCRIPTS_DIR}: {e}"),
+    }
+
+    let journal_path = Path::new(JOURNAL_PATH);
+
+    match journal::read(journal_path) {
+        Ok(records) => grid.apply_journal(records),
+        Err(e) => panic!("failed to read journal {JOURNAL_PATH}: {e}"),
+    }
+
+    match Journal::open(journal_path, save_path) {
+        Ok(journal) => grid.journal = Some(journal),
+        Err(e) => panic!("failed to open journal {JOURNAL_PATH}: {e}"),
+    }
+
+    // so the journal only ever holds changes on top of the save we just
+    // started from
+    if let Err(e) = grid.checkpoint() {
+        panic!("failed to save world to {SAVE_PATH}: {e}");
+    }
+
+    let replay_path = std::env::var(REPLAY_PATH_ENV).ok().map(PathBuf::from);
+
+    if replay_path.is_some() {
+        grid.replay = Some(Replay::start(&grid, seed));
+    }
+
+    let state = Arc::new(RwLock::new(grid));
 
-    let (tx, _) = broadcast::channel::<Vec<TileState>>(100);
+    let tx = Arc::new(UpdateChannel::new(BROADCAST_CAPACITY, HISTORY_LEN));
+
+    let sim = Arc::new(SimControl::default());
+
+    let (stop_tx, stop_rx) = watch::channel(false);
 
     let state_clone = state.clone();
 
     let tx_clone = tx.clone();
 
-    tokio::spawn(async move {
-        game_loop(state_clone, tx_clone).await;
+    let sim_clone = sim.clone();
+
+    let game = tokio::spawn(async move {
+        simulation::game_loop(state_clone, tx_clone, sim_clone, stop_rx).await;
+    });
+
+    let admin_token = std::env::var(ADMIN_TOKEN_ENV).ok();
+
+    if admin_token.is_none() {
+        warn!("{ADMIN_TOKEN_ENV} is not set, admin commands are disabled");
+    }
+
+    let ws_handler =
+        Arc::new(WebSocketServer::new("/ws".to_string()).with_admin_token(admin_token));
+
+    let server = {
+        let ws_handler = ws_handler.clone();
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            ws_handler
+                .start_server(SERVER_URL.parse().unwrap(), state, tx, sim)
+                .await;
+        })
+    };
+
+    shutdown_signal().await;
+    info!("shutdown requested");
+
+    // let the current tick finish and get everyone off the server first, so
+    // nothing can change the world after it's been saved
+    let _ = stop_tx.send(true);
+    let _ = game.await;
+
+    ws_handler.shutdown(ShutdownNotice {
+        reason: "server is shutting down".to_string(),
+        reconnect_after: RECONNECT_AFTER,
     });
 
-    let ws_handler = WebSocketServer::new("/ws".to_string());
-    ws_handler
-        .start_server(SERVER_URL.parse().unwrap(), state, tx)
-        .await;
+    let _ = server.await;
+
+    let mut grid = state.write().await;
+
+    if let Err(e) = grid.checkpoint() {
+        error!("failed to save world to {SAVE_PATH}: {e}");
+    }
+
+    if let (Some(mut replay), Some(path)) = (grid.replay.take(), replay_path) {
+        replay.finish(&grid);
+
+        if let Err(e) = replay.save(&path) {
+            error!("failed to save replay to {}: {e}", path.display());
+        }
+    }
 }
@@ -0,0 +1,331 @@
+use axum::extract::ws::Message;
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::types::{ClientMessage, HexTile, ServerMessage, TileState};
+
+// wire format picked by the client when it connects, e.g. `/ws?encoding=msgpack`.
+// json stays the default since it's a lot nicer to read in the devtools
+#[derive(PartialEq, Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+// query parameters accepted on the websocket upgrade request
+#[derive(Debug, Default, Deserialize)]
+pub struct ConnectParams {
+    #[serde(default)]
+    pub encoding: Encoding,
+    // has to match the server's admin token for admin commands to be
+    // accepted. taken from the x-admin-token header, never the query string,
+    // which ends up in access logs
+    #[serde(skip)]
+    pub admin_token: Option<String>,
+}
+
+impl Encoding {
+    pub fn encode(&self, message: &ServerMessage) -> Option<Message> {
+        match self {
+            Self::Json => match serde_json::to_string(message) {
+                Ok(json) => Some(Message::Text(json.into())),
+                Err(e) => {
+                    warn!("failed to encode message as json: {e}");
+                    None
+                }
+            },
+            // named so the decoded objects look exactly like the json ones
+            Self::Msgpack => match rmp_serde::to_vec_named(message) {
+                Ok(bytes) => Some(Message::Binary(bytes.into())),
+                Err(e) => {
+                    warn!("failed to encode message as msgpack: {e}");
+                    None
+                }
+            },
+        }
+    }
+}
+
+// maps are mostly long stretches of the same tile, and in a plain list of
+// tiles the field names make up most of the bytes. so msgpack (which isn't
+// human readable as far as serde is concerned) gets lists of tiles as runs
+// of [count, tile], and lists of TileState as [positions, runs]: every col
+// and row one after the other, then the tiles themselves as runs. json keeps
+// the plain lists
+pub mod runs {
+    use super::*;
+
+    pub fn serialize<S: Serializer, T: PartialEq + Serialize>(
+        values: &[T],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            values.serialize(serializer)
+        } else {
+            to_runs(values).serialize(serializer)
+        }
+    }
+
+    // serde buffers internally tagged enums (like ServerMessage) without
+    // passing on whether the format is human readable, so reading takes
+    // either shape
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Either<T> {
+        Plain(Vec<T>),
+        Runs(Vec<(u32, T)>),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: Clone + Deserialize<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<T>, D::Error> {
+        Ok(match Either::deserialize(deserializer)? {
+            Either::Plain(values) => values,
+            Either::Runs(runs) => from_runs(runs),
+        })
+    }
+
+    pub(super) fn to_runs<T: PartialEq>(values: &[T]) -> Vec<(u32, &T)> {
+        let mut runs: Vec<(u32, &T)> = Vec::new();
+
+        for value in values {
+            match runs.last_mut() {
+                Some((count, last)) if *last == value => *count += 1,
+                _ => runs.push((1, value)),
+            }
+        }
+
+        runs
+    }
+
+    pub(super) fn from_runs<T: Clone>(runs: Vec<(u32, T)>) -> Vec<T> {
+        runs.into_iter()
+            .flat_map(|(count, value)| std::iter::repeat_n(value, count as usize))
+            .collect()
+    }
+}
+
+pub mod packed_tiles {
+    use super::{runs::*, *};
+
+    pub fn serialize<S: Serializer>(tiles: &[TileState], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return tiles.serialize(serializer);
+        }
+
+        let positions = tiles
+            .iter()
+            .flat_map(|tile| [tile.col, tile.row])
+            .collect::<Vec<_>>();
+        let data = tiles.iter().map(|tile| &tile.data).collect::<Vec<_>>();
+
+        (positions, to_runs(&data)).serialize(serializer)
+    }
+
+    // either shape, see runs::deserialize
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Either {
+        Plain(Vec<TileState>),
+        Packed(Vec<i32>, Vec<(u32, HexTile)>),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<TileState>, D::Error> {
+        let (positions, runs) = match Either::deserialize(deserializer)? {
+            Either::Plain(tiles) => return Ok(tiles),
+            Either::Packed(positions, runs) => (positions, runs),
+        };
+
+        Ok(positions
+            .chunks_exact(2)
+            .zip(from_runs(runs))
+            .map(|(position, data)| TileState {
+                col: position[0],
+                row: position[1],
+                data,
+            })
+            .collect())
+    }
+}
+
+// clients can send either format regardless of what they receive; text
+// frames are json and binary frames are msgpack
+pub fn decode(message: &Message) -> Option<ClientMessage> {
+    match message {
+        Message::Text(text) => serde_json::from_str(text).ok(),
+        Message::Binary(bytes) => rmp_serde::from_slice(bytes).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    use crate::{
+        api::{grid_api::GridState, simulation, tile_store::TileStore},
+        types::{MineData, Terrain, tests::mine},
+    };
+
+    use super::*;
+
+    // what joining a game in progress looks like: generated terrain, a few
+    // buildings and some ticks' worth of slime
+    fn realistic_grid_state() -> ServerMessage {
+        let mut grid = GridState::new(64, 64, HexTile::Wild);
+        grid.generate_terrain(&mut StdRng::seed_from_u64(3));
+
+        for i in 0..12 {
+            grid.set_tile(5 * i, 40, mine(i, 10, 0));
+        }
+        grid.set_tile(20, 20, HexTile::Slime);
+
+        for _ in 0..3 {
+            simulation::tick(&mut grid);
+        }
+
+        let indices = 0..grid.width * grid.height;
+
+        ServerMessage::GridState {
+            width: grid.width,
+            height: grid.height,
+            tick: grid.tick,
+            seq: 3,
+            tiles: indices.clone().map(|i| grid.tile_state(i)).collect(),
+            terrain: indices
+                .map(|i| {
+                    let (x, y) = grid.get_coords(i);
+                    *grid.get_terrain(x, y).unwrap()
+                })
+                .collect(),
+            state_hash: format!("{:016x}", grid.state_hash()),
+        }
+    }
+
+    fn sample_message() -> ServerMessage {
+        ServerMessage::GridState {
+            width: 2,
+            height: 1,
+            tick: 4,
+            seq: 9,
+            tiles: vec![
+                TileState {
+                    col: 0,
+                    row: 0,
+                    data: HexTile::Wild,
+                },
+                TileState {
+                    col: 1,
+                    row: 0,
+                    data: HexTile::Mine(MineData {
+                        level: 1,
+                        count: 2,
+                        capacity: 3,
+                        state: TileStore::default(),
+                        trade_value: 0,
+                    }),
+                },
+            ],
+            terrain: vec![Terrain::Plains, Terrain::Ore { richness: 2 }],
+            state_hash: "00000000deadbeef".to_string(),
+        }
+    }
+
+    #[test]
+    fn it_encodes_json_as_text() {
+        let message = Encoding::Json.encode(&sample_message()).unwrap();
+
+        match message {
+            Message::Text(text) => {
+                let decoded: ServerMessage = serde_json::from_str(&text).unwrap();
+                assert_eq!(decoded, sample_message());
+            }
+            _ => panic!("expected text frame"),
+        }
+    }
+
+    #[test]
+    fn it_encodes_msgpack_as_binary() {
+        let message = Encoding::Msgpack.encode(&sample_message()).unwrap();
+
+        let json = match Encoding::Json.encode(&sample_message()).unwrap() {
+            Message::Text(text) => text.len(),
+            _ => panic!("expected text frame"),
+        };
+
+        match message {
+            Message::Binary(bytes) => {
+                let decoded: ServerMessage = rmp_serde::from_slice(&bytes).unwrap();
+                assert_eq!(decoded, sample_message());
+
+                assert!(bytes.len() < json);
+            }
+            _ => panic!("expected binary frame"),
+        }
+    }
+
+    #[test]
+    fn it_packs_tiles_in_msgpack() {
+        let message = realistic_grid_state();
+
+        let Some(Message::Text(json)) = Encoding::Json.encode(&message) else {
+            panic!("expected text frame");
+        };
+        let Some(Message::Binary(bytes)) = Encoding::Msgpack.encode(&message) else {
+            panic!("expected binary frame");
+        };
+
+        // json looks the same as ever
+        let decoded: ServerMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, message);
+        assert!(json.contains(r#"{"col":0,"row":0,"data":"Wild"}"#));
+
+        let decoded: ServerMessage = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded, message);
+
+        assert!(
+            bytes.len() * 5 < json.len(),
+            "{} bytes of msgpack vs {} of json",
+            bytes.len(),
+            json.len()
+        );
+    }
+
+    #[test]
+    fn it_decodes_both_formats() {
+        let message = ClientMessage::TileUpdate {
+            col: 3,
+            row: 4,
+            data: HexTile::Slime,
+        };
+
+        let text = Message::Text(serde_json::to_string(&message).unwrap().into());
+        let binary = Message::Binary(rmp_serde::to_vec_named(&message).unwrap().into());
+
+        for frame in [text, binary] {
+            match decode(&frame) {
+                Some(ClientMessage::TileUpdate { col, row, data }) => {
+                    assert_eq!((col, row), (3, 4));
+                    assert_eq!(data, HexTile::Slime);
+                }
+                other => panic!("unexpected decode result {other:?}"),
+            }
+        }
+
+        assert!(decode(&Message::Binary(vec![0xc1].into())).is_none());
+    }
+
+    #[test]
+    fn it_defaults_to_json() {
+        let params: ConnectParams = serde_json::from_str("{}").unwrap();
+        assert_eq!(params.encoding, Encoding::Json);
+        assert!(params.admin_token.is_none());
+
+        let params: ConnectParams = serde_json::from_str(r#"{"encoding":"msgpack"}"#).unwrap();
+        assert_eq!(params.encoding, Encoding::Msgpack);
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::broadcast;
+
+use crate::{api::script_host::ScriptLog, types::TileState};
+
+// every tile that changed during one tick (or one player edit), sent out together
+#[derive(Debug, Clone)]
+pub struct TickUpdate {
+    // numbered per published update, without gaps, so a client that knows the
+    // last seq it saw can be told exactly what it missed
+    pub seq: u64,
+    pub tick: u64,
+    // GridState::state_hash right after these changes
+    pub state_hash: u64,
+    pub tiles: Vec<TileState>,
+}
+
+struct History {
+    last_seq: u64,
+    updates: VecDeque<TickUpdate>,
+}
+
+// broadcast channel that numbers every update and keeps the most recent ones
+// around, so connections that lagged behind the channel (or reconnected) can
+// catch up on just the diffs instead of the whole map
+pub struct UpdateChannel {
+    tx: broadcast::Sender<TickUpdate>,
+    history: Mutex<History>,
+    history_len: usize,
+    // script output, one batch per tick. not numbered or kept around, a
+    // client that misses some just doesn't get to see them
+    logs: broadcast::Sender<Arc<[ScriptLog]>>,
+}
+
+impl UpdateChannel {
+    pub fn new(capacity: usize, history_len: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        let (logs, _) = broadcast::channel(capacity);
+
+        UpdateChannel {
+            tx,
+            history: Mutex::new(History {
+                last_seq: 0,
+                updates: VecDeque::with_capacity(history_len),
+            }),
+            history_len,
+            logs,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TickUpdate> {
+        self.tx.subscribe()
+    }
+
+    // callers should still hold the grid write lock, that way a snapshot taken
+    // under the read lock always matches `last_seq`
+    pub fn publish(&self, tick: u64, state_hash: u64, tiles: Vec<TileState>) -> u64 {
+        let mut history = self.history.lock().unwrap();
+
+        history.last_seq += 1;
+
+        let update = TickUpdate {
+            seq: history.last_seq,
+            tick,
+            state_hash,
+            tiles,
+        };
+
+        if history.updates.len() == self.history_len {
+            history.updates.pop_front();
+        }
+        history.updates.push_back(update.clone());
+
+        // sending while locked keeps channel order the same as seq order
+        let _ = self.tx.send(update);
+
+        history.last_seq
+    }
+
+    pub fn subscribe_logs(&self) -> broadcast::Receiver<Arc<[ScriptLog]>> {
+        self.logs.subscribe()
+    }
+
+    pub fn publish_logs(&self, logs: Vec<ScriptLog>) {
+        let _ = self.logs.send(logs.into());
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.history.lock().unwrap().last_seq
+    }
+
+    // every update published after `seq`, or None if some of them have
+    // already fallen out of the history
+    pub fn since(&self, seq: u64) -> Option<Vec<TickUpdate>> {
+        let history = self.history.lock().unwrap();
+
+        if seq > history.last_seq {
+            return None;
+        }
+
+        let missing = (history.last_seq - seq) as usize;
+
+        if missing > history.updates.len() {
+            return None;
+        }
+
+        Some(
+            history
+                .updates
+                .iter()
+                .skip(history.updates.len() - missing)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::HexTile;
+
+    use super::*;
+
+    fn tile(col: i32) -> TileState {
+        TileState {
+            col,
+            row: 0,
+            data: HexTile::Slime,
+        }
+    }
+
+    #[test]
+    fn it_numbers_updates_without_gaps() {
+        let channel = UpdateChannel::new(10, 10);
+        let mut rx = channel.subscribe();
+
+        assert_eq!(channel.last_seq(), 0);
+        assert_eq!(channel.publish(1, 0, vec![tile(0)]), 1);
+        assert_eq!(channel.publish(1, 0, vec![tile(1)]), 2);
+        assert_eq!(channel.publish(3, 0, vec![tile(2)]), 3);
+        assert_eq!(channel.last_seq(), 3);
+
+        let seqs = (0..3)
+            .map(|_| rx.try_recv().unwrap().seq)
+            .collect::<Vec<_>>();
+
+        assert_eq!(seqs, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn it_returns_updates_since_seq() {
+        let channel = UpdateChannel::new(10, 10);
+
+        for i in 0..5 {
+            channel.publish(i, 0, vec![tile(i as i32)]);
+        }
+
+        let missed = channel.since(2).unwrap();
+        assert_eq!(
+            missed.iter().map(|u| u.seq).collect::<Vec<_>>(),
+            vec![3, 4, 5]
+        );
+
+        assert!(channel.since(5).unwrap().is_empty());
+        assert_eq!(channel.since(0).unwrap().len(), 5);
+
+        // from the future, can't help
+        assert!(channel.since(6).is_none());
+    }
+
+    #[test]
+    fn it_forgets_updates_past_history_len() {
+        let channel = UpdateChannel::new(10, 3);
+
+        for i in 0..6 {
+            channel.publish(i, 0, vec![tile(i as i32)]);
+        }
+
+        assert_eq!(channel.since(3).unwrap().len(), 3);
+        assert!(channel.since(2).is_none());
+    }
+}
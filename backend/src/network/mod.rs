@@ -1 +1,3 @@
+pub mod codec;
+pub mod history;
 pub mod ws;
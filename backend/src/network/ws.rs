@@ -1,33 +1,306 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    net::SocketAddr,
+    ops::Range,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
 
 use axum::{
     Router,
     extract::{
-        WebSocketUpgrade,
+        Query, State, WebSocketUpgrade,
         ws::{Message, WebSocket},
     },
+    http::HeaderMap,
     response::IntoResponse,
     routing::get,
 };
 
 use futures::SinkExt;
 use futures::StreamExt;
-use log::{debug, info};
-use tokio::sync::RwLock;
+use futures::stream::SplitSink;
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::{RwLock, broadcast::error::RecvError, watch},
+    time::Instant,
+};
 
 use crate::{
     UpdateBroadcast,
-    api::grid_api::GridState,
-    types::{ClientMessage, HexTile, ServerMessage, TileState},
+    api::{
+        action_log::Actor,
+        chunk_map::CHUNK_SIZE,
+        grid_api::GridState,
+        script_host::{LogLevel, SCRIPT_FUEL, ScriptLog},
+        scripts::{self, ScriptError},
+        simulation::{SimControl, SimSettings},
+        tile_store::TileStore,
+    },
+    network::{
+        codec::{self, ConnectParams, Encoding},
+        history::TickUpdate,
+    },
+    types::{AdminCommand, ClientMessage, ServerMessage, TileState},
 };
 
+// how many tiles past the edge of a client's viewport we still send, so
+// panning a little doesn't immediately show stale tiles
+const VIEWPORT_MARGIN: i32 = 2;
+
+// inclusive tile bounds a client currently has on screen
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Viewport {
+    pub min_col: i32,
+    pub min_row: i32,
+    pub max_col: i32,
+    pub max_row: i32,
+}
+
+impl Viewport {
+    pub fn contains(&self, col: i32, row: i32) -> bool {
+        col >= self.min_col.saturating_sub(VIEWPORT_MARGIN)
+            && col <= self.max_col.saturating_add(VIEWPORT_MARGIN)
+            && row >= self.min_row.saturating_sub(VIEWPORT_MARGIN)
+            && row <= self.max_row.saturating_add(VIEWPORT_MARGIN)
+    }
+
+    // the columns and rows of a width x height map that `contains` says yes
+    // to, so going over them costs as much as the viewport and not the map
+    pub fn clamp(&self, width: usize, height: usize) -> (Range<usize>, Range<usize>) {
+        let clamp = |min: i32, max: i32, len: usize| {
+            let start = min.saturating_sub(VIEWPORT_MARGIN).max(0) as usize;
+            let end = max.saturating_add(VIEWPORT_MARGIN).saturating_add(1).max(0) as usize;
+
+            start.min(len)..end.min(len)
+        };
+
+        (
+            clamp(self.min_col, self.max_col, width),
+            clamp(self.min_row, self.max_row, height),
+        )
+    }
+}
+
+// how often we ping every client, and how long a client may stay silent
+// (not even answering pings) before we give up on it
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: HEARTBEAT_INTERVAL,
+            timeout: IDLE_TIMEOUT,
+        }
+    }
+}
+
+// what every client is told before the server goes down
+#[derive(Debug, Clone)]
+pub struct ShutdownNotice {
+    pub reason: String,
+    // seconds
+    pub reconnect_after: u32,
+}
+
+// who's on the other end of one connection, and what they can see
+#[derive(Debug)]
+struct Session {
+    // edits from this connection are attributed to them: a guest name until
+    // the client authenticates with its secret
+    player: String,
+    is_admin: bool,
+    // until the client tells us what it can see, it gets everything
+    viewport: Option<Viewport>,
+    // seq of the last update this client has been sent (or that was already
+    // part of the state when it connected)
+    last_seq: u64,
+}
+
+impl Session {
+    fn new(player: String, is_admin: bool) -> Self {
+        Session {
+            player,
+            is_admin,
+            viewport: None,
+            last_seq: 0,
+        }
+    }
+}
+
+// script log lines a connection gets at most: a burst of SCRIPT_LOG_BURST,
+// then SCRIPT_LOG_RATE per second
+const SCRIPT_LOG_BURST: f64 = 32.0;
+const SCRIPT_LOG_RATE: f64 = 8.0;
+
+// token bucket keeping one player's noisy scripts from flooding their socket
+struct LogBudget {
+    tokens: f64,
+    refilled: Instant,
+    // lines dropped since the last report, and where the last of them came
+    // from as (col, row, tick)
+    dropped: u32,
+    last_dropped: (u32, u32, u64),
+}
+
+impl LogBudget {
+    fn new(now: Instant) -> Self {
+        LogBudget {
+            tokens: SCRIPT_LOG_BURST,
+            refilled: now,
+            dropped: 0,
+            last_dropped: (0, 0, 0),
+        }
+    }
+
+    // takes a token if there is one
+    fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * SCRIPT_LOG_RATE).min(SCRIPT_LOG_BURST);
+        self.refilled = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+
+        true
+    }
+
+    // the message to send for `log`, or None if it's over budget
+    fn admit(&mut self, log: &ScriptLog, now: Instant) -> Option<ServerMessage> {
+        if !self.take(now) {
+            self.dropped += 1;
+            self.last_dropped = (log.col, log.row, log.tick);
+
+            return None;
+        }
+
+        Some(ServerMessage::ScriptLog {
+            col: log.col as i32,
+            row: log.row as i32,
+            tick: log.tick,
+            level: log.level,
+            message: log.message.clone(),
+        })
+    }
+
+    // a warning with how many lines were dropped since the last one, once
+    // the budget allows for it. checked before every batch and on every
+    // heartbeat, so it still comes when the script went quiet
+    fn report_dropped(&mut self, now: Instant) -> Option<ServerMessage> {
+        if self.dropped == 0 || !self.take(now) {
+            return None;
+        }
+
+        let (col, row, tick) = self.last_dropped;
+
+        Some(ServerMessage::ScriptLog {
+            col: col as i32,
+            row: row as i32,
+            tick,
+            level: LogLevel::Warn,
+            message: format!("{} lines dropped", std::mem::take(&mut self.dropped)),
+        })
+    }
+}
+
+// header for clients that can send one along with the upgrade request;
+// browsers can't, and send an authenticate message instead
+const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+
+// shortest secret a player can authenticate with, anything less could be
+// guessed
+const MIN_SECRET_LEN: usize = 16;
+
+// names handed to clients that don't authenticate with a secret
+static NEXT_GUEST: AtomicU64 = AtomicU64::new(1);
+
+// everything a connection task needs, shared between all of them
+#[derive(Clone)]
+struct SocketState {
+    grid: Arc<RwLock<GridState>>,
+    updates: UpdateBroadcast,
+    heartbeat: Heartbeat,
+    connections: Arc<AtomicUsize>,
+    shutdown: watch::Receiver<Option<ShutdownNotice>>,
+    sim: Arc<SimControl>,
+    admin_token: Option<Arc<str>>,
+}
+
 pub struct WebSocketServer {
     path: String,
+    heartbeat: Heartbeat,
+    // without one nobody gets to send admin commands
+    admin_token: Option<Arc<str>>,
+    // number of connection tasks currently alive
+    connections: Arc<AtomicUsize>,
+    shutdown: watch::Sender<Option<ShutdownNotice>>,
 }
 
 impl WebSocketServer {
     pub fn new(path: String) -> Self {
-        WebSocketServer { path }
+        WebSocketServer {
+            path,
+            heartbeat: Heartbeat::default(),
+            admin_token: None,
+            connections: Arc::new(AtomicUsize::new(0)),
+            shutdown: watch::Sender::new(None),
+        }
+    }
+
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    pub fn with_admin_token(mut self, token: Option<String>) -> Self {
+        self.admin_token = token.map(Into::into);
+        self
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    pub fn router(
+        &self,
+        state: Arc<RwLock<GridState>>,
+        tx: UpdateBroadcast,
+        sim: Arc<SimControl>,
+    ) -> Router {
+        Router::new()
+            .route(&self.path, get(ws_handler))
+            .with_state(SocketState {
+                grid: state,
+                updates: tx,
+                heartbeat: self.heartbeat,
+                connections: self.connections.clone(),
+                shutdown: self.shutdown.subscribe(),
+                sim,
+                admin_token: self.admin_token.clone(),
+            })
+    }
+
+    // notifies and disconnects every client, and makes start_server return
+    // once they're all gone
+    pub fn shutdown(&self, notice: ShutdownNotice) {
+        info!("shutting down websocket server: {}", notice.reason);
+
+        self.shutdown.send_replace(Some(notice));
     }
 
     pub async fn start_server(
@@ -35,71 +308,500 @@ impl WebSocketServer {
         addr: SocketAddr,
         state: Arc<RwLock<GridState>>,
         tx: UpdateBroadcast,
+        sim: Arc<SimControl>,
     ) {
-        let app = Router::new()
-            .route(&self.path, get(ws_handler))
-            .with_state((state, tx));
+        let app = self.router(state, tx, sim);
 
         let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
 
         info!("websocket server listening on {addr}");
 
-        axum::serve(listener, app).await.unwrap();
+        let mut shutdown = self.shutdown.subscribe();
+
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move {
+                let _ = shutdown.wait_for(Option::is_some).await;
+            })
+            .await
+            .unwrap();
+    }
+}
+
+// counts a connection task as alive for as long as it's held
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl ConnectionGuard {
+    fn new(connections: Arc<AtomicUsize>) -> Self {
+        connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(connections)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Err once the socket can't be written to anymore
+async fn send_message(
+    sender: &mut SplitSink<WebSocket, Message>,
+    encoding: Encoding,
+    message: &ServerMessage,
+) -> Result<(), axum::Error> {
+    match encoding.encode(message) {
+        Some(frame) => sender.send(frame).await,
+        None => Ok(()),
+    }
+}
+
+async fn send_messages(
+    sender: &mut SplitSink<WebSocket, Message>,
+    encoding: Encoding,
+    messages: &[ServerMessage],
+) -> Result<(), axum::Error> {
+    for message in messages {
+        send_message(sender, encoding, message).await?;
     }
+
+    Ok(())
 }
 
-async fn handle_socket(
-    socket: WebSocket,
-    state: Arc<RwLock<GridState>>,
-    broadcast_tx: UpdateBroadcast,
-) {
+async fn handle_socket(socket: WebSocket, socket_state: SocketState, params: ConnectParams) {
+    let _guard = ConnectionGuard::new(socket_state.connections.clone());
+
+    let is_admin = is_admin_token(
+        socket_state.admin_token.as_deref(),
+        params.admin_token.as_deref(),
+    );
+
+    let player = format!("guest-{}", NEXT_GUEST.fetch_add(1, Ordering::Relaxed));
+
+    let mut session = Session::new(player, is_admin);
+    session.last_seq = socket_state.updates.last_seq();
+
+    let SocketState {
+        grid: state,
+        updates: broadcast_tx,
+        heartbeat,
+        mut shutdown,
+        sim,
+        admin_token,
+        ..
+    } = socket_state;
+
+    let encoding = params.encoding;
+
     let (mut sender, mut receiver) = socket.split();
     let mut broadcast_rx = broadcast_tx.subscribe();
+    let mut logs_rx = broadcast_tx.subscribe_logs();
+    let mut log_budget = LogBudget::new(Instant::now());
+
+    // anything from the client (including pongs) counts as a sign of life
+    let mut last_seen = Instant::now();
+    let mut ping_interval = tokio::time::interval(heartbeat.interval);
+
+    let mut sim_rx = sim.subscribe();
+    let mut sim_status = sim_status_message(&sim_rx.borrow_and_update());
+
+    let mut first_message = true;
+
+    debug!("New socket connection formed for {session:?}, using {encoding:?} encoding");
 
-    debug!("New socket connection formed");
+    if send_message(&mut sender, encoding, &sim_status)
+        .await
+        .is_err()
+    {
+        return;
+    }
 
     loop {
         // select! documentation (wild):
         // https://tokio.rs/tokio/tutorial/select
         tokio::select! {
-            Ok(updates) = broadcast_rx.recv() => {
-                for tile in updates {
-                    let response = ServerMessage::TileUpdate {
-                        col: tile.col,
-                        row: tile.row,
-                        data: tile.data
+            result = broadcast_rx.recv() => {
+                let response = match result {
+                    // already covered by a resync
+                    Ok(update) if update.seq <= session.last_seq => None,
+                    Ok(update) => {
+                        session.last_seq = update.seq;
+                        batch_message(update, session.viewport.as_ref())
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("connection lagged behind by {skipped} updates, resyncing");
+
+                        let (seq, response) =
+                            resync_message(&state, &broadcast_tx, session.last_seq, session.viewport.as_ref()).await;
+                        session.last_seq = seq;
+
+                        response
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                if let Some(response) = response
+                    && send_message(&mut sender, encoding, &response).await.is_err() {
+                        break;
+                    }
+            },
+            result = logs_rx.recv() => {
+                let logs = match result {
+                    Ok(logs) => logs,
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("connection missed {skipped} ticks of script logs");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                let now = Instant::now();
+                let responses = log_budget
+                    .report_dropped(now)
+                    .into_iter()
+                    .chain(
+                        logs.iter()
+                            .filter(|log| log.owner == session.player)
+                            .filter_map(|log| log_budget.admit(log, now)),
+                    )
+                    .collect::<Vec<_>>();
+
+                if send_messages(&mut sender, encoding, &responses).await.is_err() {
+                    break;
+                }
+            },
+            msg = receiver.next() => {
+                let msg = match msg {
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Err(e)) => {
+                        debug!("socket error, closing connection: {e}");
+                        break;
+                    }
+                    Some(Ok(msg)) => msg,
+                };
+
+                last_seen = Instant::now();
+
+                if let Some(message) = codec::decode(&msg) {
+                    // successfully received client message
+                    let response = match message {
+                        ClientMessage::Authenticate { admin_token: given, secret } => Some(on_authenticate(
+                            admin_token.as_deref(),
+                            &mut session,
+                            given.as_deref(),
+                            secret.as_deref(),
+                            first_message,
+                        )),
+                        ClientMessage::Admin { command } => {
+                            on_admin_command(&state, &broadcast_tx, &sim, &session, command).await
+                        }
+                        message => on_receive_message(&state, &broadcast_tx, &mut session, message).await,
                     };
+                    first_message = false;
 
-                    if let Ok(json) = serde_json::to_string(&response)
-                        && sender.send(Message::Text(json.into())).await.is_err() {
+                    // potential server message
+                    if let Some(response) = response
+                        && send_message(&mut sender, encoding, &response).await.is_err() {
                             break;
-                    }
+                        }
                 }
             },
-            Some(Ok(msg)) = receiver.next() => {
-                if let Message::Text(text) = msg {
-                    let message = serde_json::from_str::<ClientMessage>(&text);
-
-                    if let Ok(message) = message {
-                        // successfully received client message
-                        let response = on_receive_message(&state, &broadcast_tx, message).await;
-
-                        // potential server message
-                        if let Some(response) = response
-                            && let Ok(json) = serde_json::to_string(&response) {
-                                let _ = sender.send(Message::Text(json.into())).await;
-                            }
+            Ok(Some(notice)) = async { shutdown.wait_for(Option::is_some).await.map(|n| n.clone()) } => {
+                let response = ServerMessage::ServerShutdown {
+                    reason: notice.reason,
+                    reconnect_after: notice.reconnect_after,
+                };
+
+                let _ = send_message(&mut sender, encoding, &response).await;
+                break;
+            },
+            Ok(()) = sim_rx.changed() => {
+                // step progress doesn't matter to clients, only pause/speed
+                let status = sim_status_message(&sim_rx.borrow_and_update());
+
+                if status != sim_status {
+                    sim_status = status;
+
+                    if send_message(&mut sender, encoding, &sim_status).await.is_err() {
+                        break;
                     }
+                }
+            },
+            _ = ping_interval.tick() => {
+                if last_seen.elapsed() > heartbeat.timeout {
+                    info!("connection idle for {:?}, closing", last_seen.elapsed());
+                    break;
+                }
 
+                if sender.send(Message::Ping(Default::default())).await.is_err() {
+                    break;
                 }
+
+                if let Some(report) = log_budget.report_dropped(Instant::now())
+                    && send_message(&mut sender, encoding, &report).await.is_err() {
+                        break;
+                    }
+            }
+        }
+    }
+
+    // sends a close frame if the socket is still around
+    let _ = sender.close().await;
+
+    debug!("Socket connection closed");
+}
+
+// whether `given` is the admin token, taking as long for every wrong guess.
+// both get hashed first, so not even the length of the real one leaks
+fn is_admin_token(expected: Option<&str>, given: Option<&str>) -> bool {
+    let (Some(expected), Some(given)) = (expected, given) else {
+        return false;
+    };
+
+    Sha256::digest(expected)
+        .iter()
+        .zip(Sha256::digest(given).iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+// the name a player with `secret` plays under; the same every time they
+// connect, without the secret being kept anywhere
+fn player_id(secret: &str) -> String {
+    let digest = Sha256::digest(secret);
+    let hex = digest[..6]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+
+    format!("player-{hex}")
+}
+
+// only the first message may say who's connecting, it can't change halfway
+// through
+fn on_authenticate(
+    expected: Option<&str>,
+    session: &mut Session,
+    admin_token: Option<&str>,
+    secret: Option<&str>,
+    first_message: bool,
+) -> ServerMessage {
+    if !first_message {
+        return ServerMessage::Error {
+            message: "authenticate has to be the first message".to_string(),
+        };
+    }
+
+    if let Some(secret) = secret {
+        if secret.len() < MIN_SECRET_LEN {
+            return ServerMessage::Error {
+                message: format!("secrets have to be at least {MIN_SECRET_LEN} characters"),
+            };
+        }
+
+        session.player = player_id(secret);
+    }
+
+    session.is_admin |= is_admin_token(expected, admin_token);
+
+    if admin_token.is_some() && !session.is_admin {
+        warn!("{} sent a wrong admin token", session.player);
+    }
+
+    ServerMessage::Authenticated {
+        player: session.player.clone(),
+        is_admin: session.is_admin,
+    }
+}
+
+// one tick's changes as a single message, dropping tiles outside the viewport;
+// None if the client can't see any of them
+fn batch_message(update: TickUpdate, viewport: Option<&Viewport>) -> Option<ServerMessage> {
+    let tiles = update
+        .tiles
+        .into_iter()
+        .filter(|tile| viewport.is_none_or(|v| v.contains(tile.col, tile.row)))
+        .collect::<Vec<_>>();
+
+    if tiles.is_empty() {
+        return None;
+    }
+
+    Some(ServerMessage::TileBatch {
+        seq: update.seq,
+        tick: update.tick,
+        state_hash: format_hash(update.state_hash),
+        tiles,
+    })
+}
+
+// folds several updates into one, later changes to a tile win
+fn merge_updates(updates: Vec<TickUpdate>) -> Option<TickUpdate> {
+    let last = updates.last()?;
+    let (seq, tick, state_hash) = (last.seq, last.tick, last.state_hash);
+
+    let mut tiles: Vec<TileState> = Vec::new();
+    // where each (col, row) ended up in `tiles`
+    let mut positions = HashMap::new();
+
+    for tile in updates.into_iter().flat_map(|u| u.tiles) {
+        match positions.entry((tile.col, tile.row)) {
+            Entry::Occupied(position) => tiles[*position.get()] = tile,
+            Entry::Vacant(position) => {
+                position.insert(tiles.len());
+                tiles.push(tile);
             }
         }
     }
+
+    Some(TickUpdate {
+        seq,
+        tick,
+        state_hash,
+        tiles,
+    })
+}
+
+// brings a client that last saw `since_seq` up to date: the missed updates as
+// one batch if we still have them all, otherwise a full grid state. also
+// returns the seq the client is at afterwards
+async fn resync_message(
+    state: &Arc<RwLock<GridState>>,
+    tx: &UpdateBroadcast,
+    since_seq: u64,
+    viewport: Option<&Viewport>,
+) -> (u64, Option<ServerMessage>) {
+    // grid first, so nothing can be published while we look at the history
+    let grid = state.read().await;
+    let seq = tx.last_seq();
+
+    match tx.since(since_seq) {
+        Some(missed) => {
+            let response = merge_updates(missed).and_then(|u| batch_message(u, viewport));
+
+            (seq, response)
+        }
+        None => (seq, Some(grid_state_message(&grid, seq, viewport))),
+    }
+}
+
+// full grid state, limited to the tiles inside the viewport (if there is one)
+fn grid_state_message(grid: &GridState, seq: u64, viewport: Option<&Viewport>) -> ServerMessage {
+    let mut update = Vec::new();
+    let mut terrain = Vec::new();
+
+    let (cols, rows) = viewport.map_or((0..grid.width, 0..grid.height), |v| {
+        v.clamp(grid.width, grid.height)
+    });
+
+    cols.for_each(|i| {
+        for j in rows.clone() {
+            update.push(TileState {
+                data: grid.tiles.get(i as u32, j as u32).clone(),
+                row: j as i32,
+                col: i as i32,
+            });
+            terrain.push(*grid.terrain.get(i as u32, j as u32));
+        }
+    });
+
+    ServerMessage::GridState {
+        tiles: update,
+        terrain,
+        width: grid.width,
+        height: grid.height,
+        tick: grid.tick,
+        seq,
+        state_hash: format_hash(grid.state_hash()),
+    }
+}
+
+// u64 doesn't fit in a js number, so hashes go out as fixed width hex
+fn format_hash(hash: u64) -> String {
+    format!("{hash:016x}")
+}
+
+fn sim_status_message(settings: &SimSettings) -> ServerMessage {
+    ServerMessage::SimStatus {
+        paused: settings.paused,
+        tick_interval_ms: settings.tick_interval.as_millis() as u32,
+    }
+}
+
+// sim status changes reach every client (this one included) through the sim
+// settings and tile changes through the broadcast, so there's only something
+// to reply on refusal
+async fn on_admin_command(
+    state: &Arc<RwLock<GridState>>,
+    tx: &UpdateBroadcast,
+    sim: &SimControl,
+    session: &Session,
+    command: AdminCommand,
+) -> Option<ServerMessage> {
+    if !session.is_admin {
+        warn!(
+            "refused admin command {command:?} from non-admin {}",
+            session.player
+        );
+
+        return Some(ServerMessage::Error {
+            message: "admin commands need a valid admin_token".to_string(),
+        });
+    }
+
+    info!("[ADMIN] {} sent {command:?}", session.player);
+
+    let mut grid = state.write().await;
+
+    let changed = match command {
+        AdminCommand::Pause => {
+            sim.pause();
+            Vec::new()
+        }
+        AdminCommand::Resume => {
+            sim.resume();
+            Vec::new()
+        }
+        AdminCommand::Step { ticks } => {
+            sim.step(ticks);
+            Vec::new()
+        }
+        AdminCommand::SetTickRate { interval_ms } => {
+            sim.set_tick_interval(Duration::from_millis(interval_ms as u64));
+            Vec::new()
+        }
+        AdminCommand::Undo { player, count } => grid.undo_actions(&player, count as usize),
+        AdminCommand::Redo { player, count } => grid.redo_actions(&player, count as usize),
+        AdminCommand::RevertRegion {
+            min_col,
+            min_row,
+            max_col,
+            max_row,
+            tick,
+        } => grid.revert_region((min_col, min_row), (max_col, max_row), tick),
+        AdminCommand::ScriptProfiles => return Some(script_profiles_message(&grid, None)),
+    };
+
+    grid.sync_journal();
+
+    if !changed.is_empty() {
+        let tiles = changed.into_iter().map(|i| grid.tile_state(i)).collect();
+        tx.publish(grid.tick, grid.state_hash(), tiles);
+    }
+
+    None
+}
+
+fn script_profiles_message(grid: &GridState, owner: Option<&str>) -> ServerMessage {
+    ServerMessage::ScriptProfiles {
+        fuel_budget: SCRIPT_FUEL,
+        profiles: grid.profiler.report(owner),
+    }
 }
 
 async fn on_receive_message(
     state: &Arc<RwLock<GridState>>,
     tx: &UpdateBroadcast,
+    session: &mut Session,
     message: ClientMessage,
 ) -> Option<ServerMessage> {
     match message {
@@ -107,36 +809,184 @@ async fn on_receive_message(
             debug!("[REQUEST] request grid state");
 
             let grid = state.read().await;
-            let tiles: &Vec<HexTile> = &grid.tiles;
 
-            let mut update = Vec::new();
+            Some(grid_state_message(
+                &grid,
+                tx.last_seq(),
+                session.viewport.as_ref(),
+            ))
+        }
+        ClientMessage::SetViewport {
+            min_col,
+            min_row,
+            max_col,
+            max_row,
+        } => {
+            debug!("[REQUEST] set viewport to <{min_col}, {min_row}> - <{max_col}, {max_row}>");
 
-            (0..grid.width).for_each(|i| {
-                for j in 0..grid.height {
-                    update.push(TileState {
-                        data: tiles[grid.get_index(i as u32, j as u32)].clone(),
-                        row: j as i32,
-                        col: i as i32,
-                    });
-                }
-            });
+            let new_viewport = Viewport {
+                min_col,
+                min_row,
+                max_col,
+                max_row,
+            };
+            session.viewport = Some(new_viewport);
 
-            Some(ServerMessage::GridState {
-                tiles: update,
-                width: grid.width,
-                height: grid.height,
+            // the client only has what was inside its old view, so send
+            // everything it can now see
+            let grid = state.read().await;
+
+            Some(grid_state_message(
+                &grid,
+                tx.last_seq(),
+                Some(&new_viewport),
+            ))
+        }
+        ClientMessage::Resync { last_seq } => {
+            debug!("[REQUEST] resync from seq {last_seq}");
+
+            let (seq, response) =
+                resync_message(state, tx, last_seq, session.viewport.as_ref()).await;
+
+            // or the broadcasts it was just sent would come again
+            session.last_seq = seq;
+
+            response
+        }
+        ClientMessage::RequestChunk {
+            chunk_col,
+            chunk_row,
+        } => {
+            debug!("[REQUEST] chunk <{chunk_col}, {chunk_row}>");
+
+            let grid = state.read().await;
+
+            Some(ServerMessage::ChunkState {
+                chunk_col,
+                chunk_row,
+                size: CHUNK_SIZE,
+                tiles: grid.tiles.chunk_values(chunk_col, chunk_row),
+                terrain: grid.terrain.chunk_values(chunk_col, chunk_row),
             })
         }
         ClientMessage::TileUpdate { col, row, data } => {
             debug!("[REQUEST] tile update for <col: {col}, row: {row}>");
+
+            // scripts can only grow state within the quotas, clients have to
+            // be held to them too
+            if let Some(Err(e)) = data.store().map(TileStore::check) {
+                return Some(ServerMessage::Error {
+                    message: format!("tile update for <col: {col}, row: {row}> refused: {e}"),
+                });
+            }
+
+            let grid = &mut *state.write().await;
+
+            if !grid.in_bounds(col as u32, row as u32) {
+                return Some(ServerMessage::Error {
+                    message: format!("tile update for <col: {col}, row: {row}> is off the map"),
+                });
+            }
+
+            // same rule as assigning: a scripted building is its owner's to
+            // change or tear down
+            if let Some(assignment) = grid
+                .assignments
+                .get(&grid.get_index(col as u32, row as u32))
+                && assignment.owner != session.player
             {
-                let mut grid = state.write().await;
+                let e = ScriptError::NotOwner {
+                    owner: assignment.owner.clone(),
+                };
+
+                return Some(ServerMessage::Error {
+                    message: format!("tile update for <col: {col}, row: {row}> refused: {e}"),
+                });
+            }
+
+            grid.edit_tile(
+                col as u32,
+                row as u32,
+                data,
+                Actor::Player(session.player.clone()),
+            );
+            grid.sync_journal();
+
+            // the sender hears about it through the broadcast like everyone
+            // else, along with the seq and map hash that go with it
+            let tile = grid.tile_state(grid.get_index(col as u32, row as u32));
+            tx.publish(grid.tick, grid.state_hash(), vec![tile]);
+
+            None
+        }
+        ClientMessage::UploadScript {
+            role,
+            bytes,
+            language,
+            replaces,
+        } => {
+            let language = language.unwrap_or_default();
+            debug!(
+                "[REQUEST] {role} script upload of {} bytes of {language:?}",
+                bytes.len()
+            );
+
+            // compiling can take a while, don't hold up the game for it
+            let engine = state.read().await.scripts.engine().clone();
+
+            let uploaded = match scripts::compile(&engine, language, role, &bytes) {
+                Ok(script) => state.write().await.upload_script(
+                    script,
+                    &bytes,
+                    &session.player,
+                    replaces.as_deref(),
+                ),
+                Err(e) => Err(e),
+            };
+
+            match uploaded {
+                Ok(script_id) => {
+                    info!("{} uploaded {role} script {script_id}", session.player);
+                    Some(ServerMessage::ScriptUploaded { script_id })
+                }
+                Err(e) => Some(ServerMessage::ScriptRejected {
+                    script_id: scripts::script_id(&bytes),
+                    message: e.to_string(),
+                }),
+            }
+        }
+        ClientMessage::AssignScript {
+            col,
+            row,
+            script_id,
+        } => {
+            debug!("[REQUEST] assign script {script_id} to <col: {col}, row: {row}>");
+
+            let mut grid = state.write().await;
 
-                grid.set_tile(col as u32, row as u32, data.clone())
+            let assigned = match (u32::try_from(col), u32::try_from(row)) {
+                (Ok(x), Ok(y)) => grid.assign_script(x, y, &script_id, &session.player),
+                _ => Err(ScriptError::OutOfBounds),
+            };
+            grid.sync_journal();
+
+            match assigned {
+                Ok(()) => Some(ServerMessage::ScriptAssigned {
+                    col,
+                    row,
+                    script_id,
+                }),
+                Err(e) => Some(ServerMessage::Error {
+                    message: format!("can't assign script to <col: {col}, row: {row}>: {e}"),
+                }),
             }
+        }
+        ClientMessage::RequestScriptProfiles => {
+            debug!("[REQUEST] script profiles of {}", session.player);
 
-            // acknowledged on backend, now update client
-            Some(ServerMessage::TileUpdate { row, col, data })
+            let grid = state.read().await;
+
+            Some(script_profiles_message(&grid, Some(&session.player)))
         }
         _ => None,
     }
@@ -144,22 +994,40 @@ async fn on_receive_message(
 
 async fn ws_handler(
     ws: WebSocketUpgrade,
-    axum::extract::State((state, tx)): axum::extract::State<(
-        Arc<RwLock<GridState>>,
-        UpdateBroadcast,
-    )>,
+    Query(mut params): Query<ConnectParams>,
+    headers: HeaderMap,
+    State(state): State<SocketState>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state, tx))
+    params.admin_token = headers
+        .get(ADMIN_TOKEN_HEADER)
+        .and_then(|token| token.to_str().ok())
+        .map(str::to_string);
+
+    ws.on_upgrade(move |socket| handle_socket(socket, state, params))
 }
 
 #[cfg(test)]
 mod tests {
     use std::cmp::{max, min};
 
-    use tokio::sync::broadcast;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    use crate::{
+        api::{
+            profiler::ScriptCost,
+            replay::{Replay, ReplayAction},
+            scripts::{ScriptRole, tests::module},
+        },
+        network::history::UpdateChannel,
+        types::{HexTile, TurretData, tests::turret},
+    };
 
     use super::*;
 
+    fn guest() -> Session {
+        Session::new("guest".to_string(), false)
+    }
+
     #[test]
     fn it_sets_websocket_path_on_initialization() {
         let path = "ws://somesocketaddr";
@@ -174,38 +1042,31 @@ mod tests {
         let state: Arc<RwLock<GridState>> =
             Arc::new(RwLock::new(GridState::new(10, 10, HexTile::Wild)));
 
-        let (tx, _) = broadcast::channel::<Vec<TileState>>(100);
+        let tx = Arc::new(UpdateChannel::new(100, 100));
 
-        let response = on_receive_message(&state, &tx, ClientMessage::None).await;
+        let response = on_receive_message(&state, &tx, &mut guest(), ClientMessage::None).await;
 
         assert!(response.is_none());
     }
 
     #[tokio::test]
-    async fn it_returns_tile_update_for_tile_request() {
+    async fn it_answers_tile_requests_through_the_broadcast_only() {
         let state: Arc<RwLock<GridState>> =
             Arc::new(RwLock::new(GridState::new(10, 10, HexTile::Wild)));
 
-        let (tx, _) = broadcast::channel::<Vec<TileState>>(100);
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+        let mut rx = tx.subscribe();
 
         let update = ClientMessage::TileUpdate {
             col: 1,
             row: 0,
             data: HexTile::Slime,
         };
-        let expected_response = ServerMessage::TileUpdate {
-            col: 1,
-            row: 0,
-            data: HexTile::Slime,
-        };
 
-        let response = on_receive_message(&state, &tx, update).await;
-
-        assert!(response.is_some());
+        let response = on_receive_message(&state, &tx, &mut guest(), update).await;
 
-        let response = response.unwrap();
-
-        assert_eq!(response, expected_response);
+        assert!(response.is_none());
+        assert_eq!(rx.try_recv().unwrap().tiles[0].data, HexTile::Slime);
     }
 
     #[tokio::test]
@@ -215,11 +1076,11 @@ mod tests {
 
         let grid = state.read().await;
 
-        let (tx, _) = broadcast::channel::<Vec<TileState>>(100);
+        let tx = Arc::new(UpdateChannel::new(100, 100));
 
         let update = ClientMessage::RequestGridState;
 
-        let response = on_receive_message(&state, &tx, update).await;
+        let response = on_receive_message(&state, &tx, &mut guest(), update).await;
 
         assert!(response.is_some());
 
@@ -230,11 +1091,14 @@ mod tests {
                 width,
                 height,
                 tiles,
+                terrain,
+                ..
             } => {
                 assert_eq!(width, 15);
                 assert_eq!(height, 10);
-                assert_eq!(tiles.len(), grid.tiles.len());
+                assert_eq!(tiles.len(), grid.width * grid.height);
                 assert_eq!(tiles.len(), 15 * 10);
+                assert_eq!(terrain.len(), tiles.len());
 
                 let mut min_x = 999999;
                 let mut max_x = -999999;
@@ -261,4 +1125,1108 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn it_includes_margin_in_viewport() {
+        let viewport = Viewport {
+            min_col: 5,
+            min_row: 5,
+            max_col: 10,
+            max_row: 8,
+        };
+
+        assert!(viewport.contains(5, 5));
+        assert!(viewport.contains(10, 8));
+        assert!(viewport.contains(5 - VIEWPORT_MARGIN, 8 + VIEWPORT_MARGIN));
+        assert!(!viewport.contains(4 - VIEWPORT_MARGIN, 6));
+        assert!(!viewport.contains(7, 9 + VIEWPORT_MARGIN));
+    }
+
+    #[test]
+    fn it_clamps_viewport_to_the_map() {
+        let viewport = Viewport {
+            min_col: 5,
+            min_row: -40,
+            max_col: 10,
+            max_row: 8,
+        };
+
+        assert_eq!(
+            viewport.clamp(12, 100),
+            (
+                5 - VIEWPORT_MARGIN as usize..12,
+                0..8 + VIEWPORT_MARGIN as usize + 1
+            )
+        );
+
+        // nowhere near the map, or as far as a client can ask
+        let viewport = Viewport {
+            min_col: -50,
+            min_row: i32::MIN,
+            max_col: -20,
+            max_row: i32::MAX,
+        };
+
+        let (cols, rows) = viewport.clamp(12, 100);
+        assert!(cols.is_empty());
+        assert_eq!(rows, 0..100);
+        assert!(viewport.contains(-20, i32::MAX));
+    }
+
+    #[tokio::test]
+    async fn it_only_returns_tiles_inside_viewport() {
+        let state: Arc<RwLock<GridState>> =
+            Arc::new(RwLock::new(GridState::new(30, 30, HexTile::Wild)));
+
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+
+        let mut session = guest();
+
+        let update = ClientMessage::SetViewport {
+            min_col: 10,
+            min_row: 12,
+            max_col: 14,
+            max_row: 15,
+        };
+
+        let response = on_receive_message(&state, &tx, &mut session, update).await;
+
+        let expected_viewport = Viewport {
+            min_col: 10,
+            min_row: 12,
+            max_col: 14,
+            max_row: 15,
+        };
+
+        assert_eq!(session.viewport, Some(expected_viewport));
+
+        let check_tiles = |response: Option<ServerMessage>| match response {
+            Some(ServerMessage::GridState {
+                width,
+                height,
+                tiles,
+                terrain,
+                ..
+            }) => {
+                // still reports the full map size
+                assert_eq!(width, 30);
+                assert_eq!(height, 30);
+
+                let side = 2 * VIEWPORT_MARGIN as usize;
+                assert_eq!(tiles.len(), (5 + side) * (4 + side));
+                assert_eq!(terrain.len(), tiles.len());
+
+                assert!(
+                    tiles
+                        .iter()
+                        .all(|tile| expected_viewport.contains(tile.col, tile.row))
+                );
+            }
+            _ => panic!("invalid response"),
+        };
+
+        check_tiles(response);
+
+        // later full state requests respect the viewport too
+        let response =
+            on_receive_message(&state, &tx, &mut session, ClientMessage::RequestGridState).await;
+
+        check_tiles(response);
+    }
+
+    #[tokio::test]
+    async fn it_returns_chunk_for_request() {
+        let state: Arc<RwLock<GridState>> =
+            Arc::new(RwLock::new(GridState::new(40, 40, HexTile::Wild)));
+
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+
+        {
+            let mut grid = state.write().await;
+            grid.set_tile(CHUNK_SIZE + 1, 2, HexTile::Slime);
+        }
+
+        let update = ClientMessage::RequestChunk {
+            chunk_col: 1,
+            chunk_row: 0,
+        };
+
+        let response = on_receive_message(&state, &tx, &mut guest(), update).await;
+
+        match response {
+            Some(ServerMessage::ChunkState {
+                chunk_col,
+                chunk_row,
+                size,
+                tiles,
+                terrain,
+            }) => {
+                assert_eq!((chunk_col, chunk_row), (1, 0));
+                assert_eq!(size, CHUNK_SIZE);
+                assert_eq!(tiles.len(), (CHUNK_SIZE * CHUNK_SIZE) as usize);
+                assert_eq!(terrain.len(), tiles.len());
+
+                assert_eq!(tiles[(2 * CHUNK_SIZE + 1) as usize], HexTile::Slime);
+                assert_eq!(tiles.iter().filter(|t| **t == HexTile::Slime).count(), 1);
+            }
+            _ => panic!("invalid response"),
+        }
+    }
+
+    #[test]
+    fn it_batches_visible_tiles_of_a_tick() {
+        let tile = |col, row| TileState {
+            col,
+            row,
+            data: HexTile::Slime,
+        };
+
+        let update = TickUpdate {
+            seq: 3,
+            tick: 7,
+            state_hash: 0xabc,
+            tiles: vec![tile(0, 0), tile(1, 0), tile(20, 20)],
+        };
+
+        // no viewport, everything in one message
+        match batch_message(update.clone(), None) {
+            Some(ServerMessage::TileBatch {
+                seq,
+                tick,
+                state_hash,
+                tiles,
+            }) => {
+                assert_eq!(seq, 3);
+                assert_eq!(tick, 7);
+                assert_eq!(state_hash, "0000000000000abc");
+                assert_eq!(tiles.len(), 3);
+            }
+            _ => panic!("invalid response"),
+        }
+
+        let viewport = Viewport {
+            min_col: 0,
+            min_row: 0,
+            max_col: 5,
+            max_row: 5,
+        };
+
+        match batch_message(update.clone(), Some(&viewport)) {
+            Some(ServerMessage::TileBatch {
+                seq, tick, tiles, ..
+            }) => {
+                assert_eq!(seq, 3);
+                assert_eq!(tick, 7);
+                assert_eq!(tiles, vec![tile(0, 0), tile(1, 0)]);
+            }
+            _ => panic!("invalid response"),
+        }
+
+        // nothing visible, nothing sent
+        let far_away = Viewport {
+            min_col: 50,
+            min_row: 50,
+            max_col: 60,
+            max_row: 60,
+        };
+
+        assert!(batch_message(update, Some(&far_away)).is_none());
+    }
+
+    #[tokio::test]
+    async fn it_publishes_player_tile_updates() {
+        let state: Arc<RwLock<GridState>> =
+            Arc::new(RwLock::new(GridState::new(10, 10, HexTile::Wild)));
+
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+        let mut rx = tx.subscribe();
+
+        let update = ClientMessage::TileUpdate {
+            col: 2,
+            row: 3,
+            data: HexTile::Slime,
+        };
+
+        on_receive_message(&state, &tx, &mut guest(), update).await;
+
+        let published = rx.try_recv().unwrap();
+
+        assert_eq!(published.seq, 1);
+        assert_eq!(
+            published.state_hash,
+            state.read().await.compute_state_hash()
+        );
+        assert_eq!(
+            published.tiles,
+            vec![TileState {
+                col: 2,
+                row: 3,
+                data: HexTile::Slime
+            }]
+        );
+
+        // out of bounds edits are refused instead of published
+        let update = ClientMessage::TileUpdate {
+            col: 20,
+            row: 3,
+            data: HexTile::Slime,
+        };
+
+        let response = on_receive_message(&state, &tx, &mut guest(), update).await;
+
+        assert!(matches!(response, Some(ServerMessage::Error { .. })));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn it_uploads_and_assigns_scripts() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+        grid.set_tile(
+            4,
+            4,
+            HexTile::Turret(TurretData {
+                level: 1,
+                state: TileStore::default(),
+            }),
+        );
+
+        let state = Arc::new(RwLock::new(grid));
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+        let mut session = guest();
+
+        let bytes = module(&["mine_tick"], &[]);
+        let upload = ClientMessage::UploadScript {
+            role: ScriptRole::Defender,
+            bytes: bytes.clone(),
+            language: None,
+            replaces: None,
+        };
+
+        let Some(ServerMessage::ScriptRejected { script_id, message }) =
+            on_receive_message(&state, &tx, &mut session, upload).await
+        else {
+            panic!("upload without defender_tick should be rejected");
+        };
+        assert!(message.contains("defender_tick"));
+        assert_eq!(script_id, scripts::script_id(&bytes));
+
+        let upload = ClientMessage::UploadScript {
+            role: ScriptRole::Defender,
+            bytes: module(&["defender_tick"], &[]),
+            language: None,
+            replaces: None,
+        };
+
+        let Some(ServerMessage::ScriptUploaded { script_id }) =
+            on_receive_message(&state, &tx, &mut session, upload).await
+        else {
+            panic!("valid upload should be accepted");
+        };
+
+        let assign = |col| ClientMessage::AssignScript {
+            col,
+            row: 4,
+            script_id: script_id.clone(),
+        };
+
+        assert!(matches!(
+            on_receive_message(&state, &tx, &mut session, assign(5)).await,
+            Some(ServerMessage::Error { .. })
+        ));
+        assert!(matches!(
+            on_receive_message(&state, &tx, &mut session, assign(4)).await,
+            Some(ServerMessage::ScriptAssigned { col: 4, row: 4, .. })
+        ));
+
+        let grid = state.read().await;
+        assert_eq!(grid.assignments[&grid.get_index(4, 4)].owner, "guest");
+        drop(grid);
+
+        // a new version only replaces what its uploader runs
+        let update = ClientMessage::UploadScript {
+            role: ScriptRole::Defender,
+            bytes: module(&["defender_tick", "mine_tick"], &[]),
+            language: None,
+            replaces: Some(script_id.clone()),
+        };
+
+        let mut bob = Session::new("bob".to_string(), false);
+        assert!(matches!(
+            on_receive_message(&state, &tx, &mut bob, update.clone()).await,
+            Some(ServerMessage::ScriptRejected { .. })
+        ));
+        assert!(state.write().await.scripts.take_reloads().is_empty());
+
+        assert!(matches!(
+            on_receive_message(&state, &tx, &mut session, update).await,
+            Some(ServerMessage::ScriptUploaded { .. })
+        ));
+        assert_eq!(state.write().await.scripts.take_reloads().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_keeps_players_off_each_others_scripted_tiles() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+        grid.set_tile(4, 4, turret());
+
+        let state = Arc::new(RwLock::new(grid));
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+        let mut ada = Session::new("ada".to_string(), false);
+
+        let upload = ClientMessage::UploadScript {
+            role: ScriptRole::Defender,
+            bytes: module(&["defender_tick"], &[]),
+            language: None,
+            replaces: None,
+        };
+        let Some(ServerMessage::ScriptUploaded { script_id }) =
+            on_receive_message(&state, &tx, &mut ada, upload).await
+        else {
+            panic!("valid upload should be accepted");
+        };
+
+        let assign = ClientMessage::AssignScript {
+            col: 4,
+            row: 4,
+            script_id,
+        };
+        on_receive_message(&state, &tx, &mut ada, assign).await;
+
+        let mut rx = tx.subscribe();
+
+        let demolish = ClientMessage::TileUpdate {
+            col: 4,
+            row: 4,
+            data: HexTile::Wild,
+        };
+
+        let mut bob = Session::new("bob".to_string(), false);
+        let response = on_receive_message(&state, &tx, &mut bob, demolish.clone()).await;
+
+        assert!(
+            matches!(response, Some(ServerMessage::Error { message }) if message.contains("ada"))
+        );
+        assert!(rx.try_recv().is_err());
+        {
+            let grid = state.read().await;
+            assert_eq!(grid.get_tile(4, 4), Some(&turret()));
+            assert_eq!(grid.assignments[&grid.get_index(4, 4)].owner, "ada");
+        }
+
+        // the owner still can
+        assert!(
+            on_receive_message(&state, &tx, &mut ada, demolish)
+                .await
+                .is_none()
+        );
+        assert_eq!(state.read().await.get_tile(4, 4), Some(&HexTile::Wild));
+    }
+
+    #[tokio::test]
+    async fn it_refuses_tile_state_over_quota() {
+        let state: Arc<RwLock<GridState>> =
+            Arc::new(RwLock::new(GridState::new(10, 10, HexTile::Wild)));
+
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+        let mut rx = tx.subscribe();
+
+        // a client can send whatever it likes, without going through set
+        let state_json = format!(
+            r#"{{"version":0,"entries":{{"blob":"{}"}}}}"#,
+            "x".repeat(5000)
+        );
+
+        let update = ClientMessage::TileUpdate {
+            col: 2,
+            row: 3,
+            data: HexTile::Turret(TurretData {
+                level: 1,
+                state: serde_json::from_str(&state_json).unwrap(),
+            }),
+        };
+
+        let response = on_receive_message(&state, &tx, &mut guest(), update).await;
+
+        assert!(matches!(response, Some(ServerMessage::Error { .. })));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(state.read().await.get_tile(2, 3), Some(&HexTile::Wild));
+    }
+
+    #[tokio::test]
+    async fn it_records_player_edits_into_the_replay() {
+        let mut grid = GridState::new(10, 10, HexTile::Wild);
+        grid.tick = 4;
+        grid.replay = Some(Replay::start(&grid, Some(1)));
+
+        let state = Arc::new(RwLock::new(grid));
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+
+        let update = ClientMessage::TileUpdate {
+            col: 2,
+            row: 3,
+            data: HexTile::Slime,
+        };
+
+        on_receive_message(&state, &tx, &mut guest(), update).await;
+
+        let grid = state.read().await;
+
+        assert_eq!(
+            grid.replay.as_ref().unwrap().actions,
+            vec![ReplayAction::Tile {
+                tick: 4,
+                col: 2,
+                row: 3,
+                tile: HexTile::Slime,
+            }]
+        );
+    }
+
+    #[test]
+    fn it_merges_missed_updates() {
+        let tile = |col, data| TileState { col, row: 0, data };
+
+        let updates = vec![
+            TickUpdate {
+                seq: 4,
+                tick: 2,
+                state_hash: 1,
+                tiles: vec![tile(0, HexTile::Slime), tile(1, HexTile::Slime)],
+            },
+            TickUpdate {
+                seq: 5,
+                tick: 3,
+                state_hash: 2,
+                tiles: vec![tile(0, HexTile::Wild)],
+            },
+        ];
+
+        let merged = merge_updates(updates).unwrap();
+
+        assert_eq!(merged.seq, 5);
+        assert_eq!(merged.tick, 3);
+        assert_eq!(merged.state_hash, 2);
+        assert_eq!(
+            merged.tiles,
+            vec![tile(0, HexTile::Wild), tile(1, HexTile::Slime)]
+        );
+
+        assert!(merge_updates(Vec::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn it_resyncs_with_missed_diffs() {
+        let state: Arc<RwLock<GridState>> =
+            Arc::new(RwLock::new(GridState::new(10, 10, HexTile::Wild)));
+
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+
+        for col in 0..3 {
+            let update = ClientMessage::TileUpdate {
+                col,
+                row: 0,
+                data: HexTile::Slime,
+            };
+
+            on_receive_message(&state, &tx, &mut guest(), update).await;
+        }
+
+        let mut session = guest();
+        let response = on_receive_message(
+            &state,
+            &tx,
+            &mut session,
+            ClientMessage::Resync { last_seq: 1 },
+        )
+        .await;
+
+        match response {
+            Some(ServerMessage::TileBatch { seq, tiles, .. }) => {
+                assert_eq!(seq, 3);
+                assert_eq!(tiles.iter().map(|t| t.col).collect::<Vec<_>>(), vec![1, 2]);
+            }
+            _ => panic!("invalid response"),
+        }
+
+        // so the socket skips those updates when they're broadcast
+        assert_eq!(session.last_seq, 3);
+
+        // already up to date, nothing to send
+        let response = on_receive_message(
+            &state,
+            &tx,
+            &mut guest(),
+            ClientMessage::Resync { last_seq: 3 },
+        )
+        .await;
+
+        assert!(response.is_none());
+    }
+
+    #[tokio::test]
+    async fn it_resyncs_with_full_state_when_history_is_gone() {
+        let state: Arc<RwLock<GridState>> =
+            Arc::new(RwLock::new(GridState::new(10, 10, HexTile::Wild)));
+
+        let tx = Arc::new(UpdateChannel::new(100, 2));
+
+        for col in 0..5 {
+            let update = ClientMessage::TileUpdate {
+                col,
+                row: 0,
+                data: HexTile::Slime,
+            };
+
+            on_receive_message(&state, &tx, &mut guest(), update).await;
+        }
+
+        let response = on_receive_message(
+            &state,
+            &tx,
+            &mut guest(),
+            ClientMessage::Resync { last_seq: 1 },
+        )
+        .await;
+
+        match response {
+            Some(ServerMessage::GridState { seq, tiles, .. }) => {
+                assert_eq!(seq, 5);
+                assert_eq!(tiles.len(), 100);
+                assert_eq!(tiles.iter().filter(|t| t.data == HexTile::Slime).count(), 5);
+            }
+            _ => panic!("invalid response"),
+        }
+    }
+
+    async fn spawn_server(server: &WebSocketServer) -> SocketAddr {
+        spawn_server_with_sim(server, Arc::new(SimControl::default())).await
+    }
+
+    async fn spawn_server_with_sim(server: &WebSocketServer, sim: Arc<SimControl>) -> SocketAddr {
+        let state: Arc<RwLock<GridState>> =
+            Arc::new(RwLock::new(GridState::new(10, 10, HexTile::Wild)));
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+
+        let app = server.router(state, tx, sim);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        addr
+    }
+
+    // polls until the server has exactly `expected` live connection tasks
+    async fn wait_for_connections(server: &WebSocketServer, expected: usize) -> bool {
+        for _ in 0..200 {
+            if server.active_connections() == expected {
+                return true;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        false
+    }
+
+    #[tokio::test]
+    async fn it_ends_connection_tasks_when_clients_leave() {
+        let server = WebSocketServer::new("/ws".to_string());
+        let addr = spawn_server(&server).await;
+
+        let mut clients = Vec::new();
+
+        for _ in 0..50 {
+            let (client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+                .await
+                .unwrap();
+            clients.push(client);
+        }
+
+        assert!(wait_for_connections(&server, 50).await);
+
+        // half of them say goodbye properly, the other half just vanish
+        for (i, mut client) in clients.into_iter().enumerate() {
+            if i % 2 == 0 {
+                client.close(None).await.unwrap();
+            } else {
+                drop(client);
+            }
+        }
+
+        assert!(wait_for_connections(&server, 0).await);
+    }
+
+    #[tokio::test]
+    async fn it_closes_idle_connections() {
+        let server = WebSocketServer::new("/ws".to_string()).with_heartbeat(Heartbeat {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(100),
+        });
+        let addr = spawn_server(&server).await;
+
+        // never read from, so pings are never answered
+        let (_silent, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+
+        // reads (and so answers pings) the whole time
+        let (mut responsive, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+        tokio::spawn(async move { while let Some(Ok(_)) = responsive.next().await {} });
+
+        assert!(wait_for_connections(&server, 2).await);
+        assert!(wait_for_connections(&server, 1).await);
+
+        // well past the timeout, the responsive one is still around
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(server.active_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_notifies_clients_on_shutdown() {
+        let server = WebSocketServer::new("/ws".to_string());
+        let addr = spawn_server(&server).await;
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+
+        assert!(wait_for_connections(&server, 1).await);
+
+        server.shutdown(ShutdownNotice {
+            reason: "maintenance".to_string(),
+            reconnect_after: 30,
+        });
+
+        let mut notified = false;
+        let mut closed = false;
+
+        while let Some(Ok(msg)) = client.next().await {
+            match msg {
+                tokio_tungstenite::tungstenite::Message::Text(text) => {
+                    let message: ServerMessage = serde_json::from_str(&text).unwrap();
+
+                    if matches!(message, ServerMessage::SimStatus { .. }) {
+                        continue;
+                    }
+
+                    assert_eq!(
+                        message,
+                        ServerMessage::ServerShutdown {
+                            reason: "maintenance".to_string(),
+                            reconnect_after: 30,
+                        }
+                    );
+                    notified = true;
+                }
+                tokio_tungstenite::tungstenite::Message::Close(_) => closed = true,
+                _ => {}
+            }
+        }
+
+        assert!(notified);
+        assert!(closed);
+        assert!(wait_for_connections(&server, 0).await);
+    }
+
+    #[tokio::test]
+    async fn it_only_accepts_admin_commands_from_admins() {
+        let state: Arc<RwLock<GridState>> =
+            Arc::new(RwLock::new(GridState::new(10, 10, HexTile::Wild)));
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+        let sim = SimControl::default();
+
+        let admin = Session::new("admin".to_string(), true);
+
+        let response = on_admin_command(&state, &tx, &sim, &guest(), AdminCommand::Pause).await;
+
+        assert!(matches!(response, Some(ServerMessage::Error { .. })));
+        assert!(!sim.settings().paused);
+
+        let response =
+            on_admin_command(&state, &tx, &sim, &admin, AdminCommand::Step { ticks: 2 }).await;
+
+        assert!(response.is_none());
+        assert!(sim.settings().paused);
+        assert_eq!(sim.settings().pending_steps, 2);
+
+        on_admin_command(
+            &state,
+            &tx,
+            &sim,
+            &admin,
+            AdminCommand::SetTickRate { interval_ms: 200 },
+        )
+        .await;
+        on_admin_command(&state, &tx, &sim, &admin, AdminCommand::Resume).await;
+
+        assert!(!sim.settings().paused);
+        assert_eq!(sim.settings().tick_interval, Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn it_shows_script_costs_to_their_owner_and_admins() {
+        let state: Arc<RwLock<GridState>> =
+            Arc::new(RwLock::new(GridState::new(10, 10, HexTile::Wild)));
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+        let sim = SimControl::default();
+
+        {
+            let mut grid = state.write().await;
+            let cost = ScriptCost {
+                fuel: 10,
+                wall_us: 1,
+                memory: None,
+            };
+
+            grid.profiler.record(1, (1, 0), "a", "ada", 1, cost);
+            grid.profiler.record(2, (2, 0), "b", "bob", 1, cost);
+        }
+
+        let owners = |response: Option<ServerMessage>| match response {
+            Some(ServerMessage::ScriptProfiles {
+                fuel_budget,
+                profiles,
+            }) => {
+                assert_eq!(fuel_budget, SCRIPT_FUEL);
+                profiles.into_iter().map(|p| p.owner).collect::<Vec<_>>()
+            }
+            response => panic!("expected script profiles, got {response:?}"),
+        };
+
+        let mut ada = Session::new("ada".to_string(), false);
+        let response =
+            on_receive_message(&state, &tx, &mut ada, ClientMessage::RequestScriptProfiles).await;
+        assert_eq!(owners(response), ["ada"]);
+
+        let response =
+            on_admin_command(&state, &tx, &sim, &ada, AdminCommand::ScriptProfiles).await;
+        assert!(matches!(response, Some(ServerMessage::Error { .. })));
+
+        let admin = Session::new("admin".to_string(), true);
+        let response =
+            on_admin_command(&state, &tx, &sim, &admin, AdminCommand::ScriptProfiles).await;
+        assert_eq!(owners(response).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_undoes_player_edits_on_admin_request() {
+        let state: Arc<RwLock<GridState>> =
+            Arc::new(RwLock::new(GridState::new(10, 10, HexTile::Wild)));
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+        let sim = SimControl::default();
+
+        let mut griefer = Session::new("griefer".to_string(), false);
+        let admin = Session::new("admin".to_string(), true);
+
+        for col in 0..3 {
+            let update = ClientMessage::TileUpdate {
+                col,
+                row: 0,
+                data: HexTile::Slime,
+            };
+
+            on_receive_message(&state, &tx, &mut griefer, update).await;
+        }
+
+        let mut rx = tx.subscribe();
+
+        let undo = AdminCommand::Undo {
+            player: "griefer".to_string(),
+            count: 2,
+        };
+
+        on_admin_command(&state, &tx, &sim, &admin, undo).await;
+
+        {
+            let grid = state.read().await;
+
+            assert_eq!(grid.get_tile(0, 0), Some(&HexTile::Slime));
+            assert_eq!(grid.get_tile(1, 0), Some(&HexTile::Wild));
+            assert_eq!(grid.get_tile(2, 0), Some(&HexTile::Wild));
+        }
+
+        // and everyone gets told
+        let published = rx.try_recv().unwrap();
+
+        assert_eq!(
+            published.tiles.iter().map(|t| t.col).collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+        assert!(published.tiles.iter().all(|t| t.data == HexTile::Wild));
+    }
+
+    // next json message from the client that isn't a ping/pong
+    async fn next_message<S>(client: &mut S) -> ServerMessage
+    where
+        S: futures::Stream<
+                Item = Result<
+                    tokio_tungstenite::tungstenite::Message,
+                    tokio_tungstenite::tungstenite::Error,
+                >,
+            > + Unpin,
+    {
+        loop {
+            if let tokio_tungstenite::tungstenite::Message::Text(text) =
+                client.next().await.unwrap().unwrap()
+            {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn it_broadcasts_sim_status_changes() {
+        let sim = Arc::new(SimControl::new(Duration::from_secs(5)));
+        let server =
+            WebSocketServer::new("/ws".to_string()).with_admin_token(Some("hunter2".to_string()));
+        let addr = spawn_server_with_sim(&server, sim.clone()).await;
+
+        let mut request = format!("ws://{addr}/ws").into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert(ADMIN_TOKEN_HEADER, "hunter2".parse().unwrap());
+
+        let (mut admin, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        // the token doesn't count in the url
+        let (mut viewer, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/ws?admin_token=hunter2"))
+                .await
+                .unwrap();
+
+        let running = ServerMessage::SimStatus {
+            paused: false,
+            tick_interval_ms: 5000,
+        };
+
+        // everyone is told the current status when they connect
+        assert_eq!(next_message(&mut admin).await, running);
+        assert_eq!(next_message(&mut viewer).await, running);
+
+        let text = |message: &ClientMessage| {
+            tokio_tungstenite::tungstenite::Message::Text(
+                serde_json::to_string(message).unwrap().into(),
+            )
+        };
+        let admin_message = |command| text(&ClientMessage::Admin { command });
+
+        viewer
+            .send(text(&ClientMessage::Authenticate {
+                admin_token: Some("wrong".to_string()),
+                secret: None,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_message(&mut viewer).await,
+            ServerMessage::Authenticated {
+                is_admin: false,
+                ..
+            }
+        ));
+
+        viewer
+            .send(admin_message(AdminCommand::Pause))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_message(&mut viewer).await,
+            ServerMessage::Error { .. }
+        ));
+        assert!(!sim.settings().paused);
+
+        admin
+            .send(admin_message(AdminCommand::Pause))
+            .await
+            .unwrap();
+
+        let paused = ServerMessage::SimStatus {
+            paused: true,
+            tick_interval_ms: 5000,
+        };
+
+        assert_eq!(next_message(&mut admin).await, paused);
+        assert_eq!(next_message(&mut viewer).await, paused);
+    }
+
+    #[test]
+    fn it_checks_the_admin_token() {
+        assert!(is_admin_token(Some("hunter2"), Some("hunter2")));
+        assert!(!is_admin_token(Some("hunter2"), Some("hunter3")));
+        assert!(!is_admin_token(Some("hunter2"), Some("hunter")));
+        assert!(!is_admin_token(Some("hunter2"), None));
+        // no token set, nobody is admin
+        assert!(!is_admin_token(None, Some("")));
+    }
+
+    #[test]
+    fn it_only_authenticates_on_the_first_message() {
+        let mut session = guest();
+
+        assert_eq!(
+            on_authenticate(Some("hunter2"), &mut session, Some("hunter2"), None, true),
+            ServerMessage::Authenticated {
+                player: "guest".to_string(),
+                is_admin: true,
+            }
+        );
+
+        let mut session = guest();
+        let response = on_authenticate(
+            Some("hunter2"),
+            &mut session,
+            Some("hunter2"),
+            Some("correct horse battery staple"),
+            false,
+        );
+
+        assert!(matches!(response, ServerMessage::Error { .. }));
+        assert!(!session.is_admin);
+        assert_eq!(session.player, "guest");
+    }
+
+    #[test]
+    fn it_names_players_after_their_secret() {
+        let secret = "correct horse battery staple";
+        let mut session = guest();
+
+        on_authenticate(None, &mut session, None, Some(secret), true);
+
+        // the same player whenever they come back, and nothing like the secret
+        assert_eq!(session.player, player_id(secret));
+        assert!(session.player.starts_with("player-"));
+        assert!(!session.player.contains("horse"));
+        assert_ne!(
+            player_id(secret),
+            player_id("correct horse battery stapler")
+        );
+
+        let mut session = guest();
+        let response = on_authenticate(None, &mut session, None, Some("hunter2"), true);
+
+        assert!(matches!(response, ServerMessage::Error { .. }));
+        assert_eq!(session.player, "guest");
+    }
+
+    fn script_log(owner: &str, message: &str) -> ScriptLog {
+        ScriptLog {
+            owner: owner.to_string(),
+            col: 3,
+            row: 4,
+            tick: 10,
+            level: LogLevel::Info,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn it_rate_limits_script_logs() {
+        let start = Instant::now();
+        let mut budget = LogBudget::new(start);
+        let log = script_log("ada", "hi");
+
+        let burst = (0..100).filter_map(|_| budget.admit(&log, start)).count();
+
+        assert_eq!(burst, SCRIPT_LOG_BURST as usize);
+
+        // nothing left to even say so with
+        assert!(budget.report_dropped(start).is_none());
+
+        // half a second buys back a few lines, the first of which says what
+        // was lost in between
+        let later = start + Duration::from_millis(500);
+
+        assert_eq!(
+            budget.report_dropped(later),
+            Some(ServerMessage::ScriptLog {
+                col: 3,
+                row: 4,
+                tick: 10,
+                level: LogLevel::Warn,
+                message: format!("{} lines dropped", 100 - burst),
+            })
+        );
+        assert!(budget.report_dropped(later).is_none());
+        assert!(matches!(
+            budget.admit(&log, later),
+            Some(ServerMessage::ScriptLog { message, .. }) if message == "hi"
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_sends_script_logs_to_their_owner_only() {
+        let server = WebSocketServer::new("/ws".to_string());
+        let state = Arc::new(RwLock::new(GridState::new(10, 10, HexTile::Wild)));
+        let tx = Arc::new(UpdateChannel::new(100, 100));
+
+        let app = server.router(state, tx.clone(), Arc::new(SimControl::default()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let secret = "ada's very own secret";
+        let owner = player_id(secret);
+
+        // someone who only knows ada's name
+        let (mut ada, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws"))
+            .await
+            .unwrap();
+        let (mut eve, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/ws?player={owner}"))
+                .await
+                .unwrap();
+
+        let mut players = Vec::new();
+
+        for (client, secret) in [(&mut ada, Some(secret)), (&mut eve, None)] {
+            // sent once the connection is subscribed
+            assert!(matches!(
+                next_message(client).await,
+                ServerMessage::SimStatus { .. }
+            ));
+
+            let authenticate = ClientMessage::Authenticate {
+                admin_token: None,
+                secret: secret.map(str::to_string),
+            };
+
+            client
+                .send(tokio_tungstenite::tungstenite::Message::Text(
+                    serde_json::to_string(&authenticate).unwrap().into(),
+                ))
+                .await
+                .unwrap();
+
+            let ServerMessage::Authenticated { player, .. } = next_message(client).await else {
+                panic!("expected to be told who we are");
+            };
+            players.push(player);
+        }
+
+        assert_eq!(players[0], owner);
+        assert_ne!(players[1], owner);
+
+        tx.publish_logs(vec![
+            script_log("bob", "not yours"),
+            script_log(&owner, "yours"),
+        ]);
+        tx.publish_logs(vec![script_log(&owner, "also yours")]);
+        tx.publish_logs(vec![script_log(&players[1], "eve's")]);
+
+        for expected in ["yours", "also yours"] {
+            assert!(matches!(
+                next_message(&mut ada).await,
+                ServerMessage::ScriptLog { message, .. } if message == expected
+            ));
+        }
+
+        // nothing of ada's came before
+        assert!(matches!(
+            next_message(&mut eve).await,
+            ServerMessage::ScriptLog { message, .. } if message == "eve's"
+        ));
+    }
 }
@@ -1,23 +1,33 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Display};
+use std::fmt::Display;
 use ts_rs::TS;
 
+use crate::{
+    api::{
+        profiler::ScriptProfile,
+        script_host::LogLevel,
+        scripts::{ScriptLanguage, ScriptRole},
+        tile_store::TileStore,
+    },
+    network::codec::{packed_tiles, runs},
+};
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export, export_to = "../../frontend/types/types.ts")]
 pub struct TurretData {
     pub level: u32,
     // in the future it could be neat to require ammo
-    pub state: String,
+    pub state: TileStore,
 }
 
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export, export_to = "../../frontend/types/types.ts")]
 pub struct MineData {
     pub level: u32,
-    pub count: u32,    // how much gold i currently have
-    pub capacity: u32, // how much gold i can have at max (might be dynamic in future)
-    pub state: String, // we'll have a string that you can arbitarily put data into for "persistence"
-    pub trade_value: u32, // bad name for this, but >0 is me offering gold to the networking, <0 is me
+    pub count: u32,       // how much gold i currently have
+    pub capacity: u32,    // how much gold i can have at max (might be dynamic in future)
+    pub state: TileStore, // whatever the mine's script wants to keep between ticks
+    pub trade_value: i32, // bad name for this, but >0 is me offering gold to the networking, <0 is me
                           // requesting gold from the network
 }
 
@@ -30,6 +40,25 @@ pub enum HexTile {
     Slime,
 }
 
+impl HexTile {
+    // the script state of tiles that have one
+    pub fn store(&self) -> Option<&TileStore> {
+        match self {
+            Self::Mine(mine) => Some(&mine.state),
+            Self::Turret(turret) => Some(&turret.state),
+            Self::Wild | Self::Slime => None,
+        }
+    }
+
+    pub fn store_mut(&mut self) -> Option<&mut TileStore> {
+        match self {
+            Self::Mine(mine) => Some(&mut mine.state),
+            Self::Turret(turret) => Some(&mut turret.state),
+            Self::Wild | Self::Slime => None,
+        }
+    }
+}
+
 impl Display for HexTile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -41,6 +70,39 @@ impl Display for HexTile {
     }
 }
 
+// what sits underneath a built tile; unlike HexTile this is never overwritten by
+// building, so demolishing a mine/turret reveals the original ground again
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/types/types.ts")]
+pub enum Terrain {
+    Plains,
+    Rock,
+    Water,
+    Ore { richness: u32 },
+}
+
+impl Terrain {
+    // how much gold a mine sitting on this terrain produces per tick
+    pub fn mine_yield(&self) -> u32 {
+        match self {
+            Self::Plains | Self::Water => 0,
+            Self::Rock => 1,
+            Self::Ore { richness } => *richness,
+        }
+    }
+}
+
+impl Display for Terrain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Plains => write!(f, "Plains"),
+            Self::Rock => write!(f, "Rock"),
+            Self::Water => write!(f, "Water"),
+            Self::Ore { richness } => write!(f, "Ore ({richness})"),
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export, export_to = "../../frontend/types/types.ts")]
 pub struct TileState {
@@ -53,6 +115,20 @@ pub struct TileState {
 #[ts(export, export_to = "../../frontend/types/types.ts")]
 #[serde(tag = "type")]
 pub enum ClientMessage {
+    // who's connecting, as the first message; browsers can't send headers
+    // along with a websocket
+    #[serde(rename = "authenticate")]
+    Authenticate {
+        #[serde(default)]
+        #[ts(optional)]
+        admin_token: Option<String>,
+        // random string the client keeps to itself; the player's name is
+        // derived from it, so nobody can pass for anyone else without it.
+        // guests get a new name every connection
+        #[serde(default)]
+        #[ts(optional)]
+        secret: Option<String>,
+    },
     #[serde(rename = "request_grid_state")]
     RequestGridState,
     #[serde(rename = "tile_update")]
@@ -61,9 +137,95 @@ pub enum ClientMessage {
         row: i32,
         data: HexTile,
     },
+    // inclusive bounds of the tiles the client has on screen
+    #[serde(rename = "set_viewport")]
+    SetViewport {
+        min_col: i32,
+        min_row: i32,
+        max_col: i32,
+        max_row: i32,
+    },
+    // sent after reconnecting, with the seq of the last update the client applied
+    #[serde(rename = "resync")]
+    Resync {
+        #[ts(type = "number")]
+        last_seq: u64,
+    },
+    // streams a CHUNK_SIZE x CHUNK_SIZE block of the map instead of the whole grid
+    #[serde(rename = "request_chunk")]
+    RequestChunk {
+        chunk_col: u32,
+        chunk_row: u32,
+    },
+    // simulation controls, only honoured on connections opened with the admin token
+    #[serde(rename = "admin")]
+    Admin {
+        command: AdminCommand,
+    },
+    // a compiled wasm module (or rhai source, as utf-8) implementing (at
+    // least) `role`. with `replaces`, every tile of this player running that
+    // script switches to the new one on the next tick; rejected if there are
+    // none. counts against the player's upload quota until it's neither
+    // assigned anywhere nor freshly uploaded
+    #[serde(rename = "upload_script")]
+    UploadScript {
+        role: ScriptRole,
+        bytes: Vec<u8>,
+        // wasm if left out
+        #[serde(default)]
+        #[ts(optional)]
+        language: Option<ScriptLanguage>,
+        #[serde(default)]
+        #[ts(optional)]
+        replaces: Option<String>,
+    },
+    // runs an uploaded script on a mine/turret, on behalf of this player
+    #[serde(rename = "assign_script")]
+    AssignScript {
+        col: i32,
+        row: i32,
+        script_id: String,
+    },
+    // what this player's scripts have cost so far
+    #[serde(rename = "request_script_profiles")]
+    RequestScriptProfiles,
     None,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export, export_to = "../../frontend/types/types.ts")]
+#[serde(tag = "type")]
+pub enum AdminCommand {
+    #[serde(rename = "pause")]
+    Pause,
+    #[serde(rename = "resume")]
+    Resume,
+    // pauses, then runs exactly `ticks` ticks
+    #[serde(rename = "step")]
+    Step { ticks: u32 },
+    #[serde(rename = "set_tick_rate")]
+    SetTickRate { interval_ms: u32 },
+    // takes back the last `count` tile edits of a player
+    #[serde(rename = "undo")]
+    Undo { player: String, count: u32 },
+    // re-applies the last `count` of a player's undone edits
+    #[serde(rename = "redo")]
+    Redo { player: String, count: u32 },
+    // undoes every player edit inside the (inclusive) region made after `tick`
+    #[serde(rename = "revert_region")]
+    RevertRegion {
+        min_col: u32,
+        min_row: u32,
+        max_col: u32,
+        max_row: u32,
+        #[ts(type = "number")]
+        tick: u64,
+    },
+    // what every tile's script has cost so far
+    #[serde(rename = "script_profiles")]
+    ScriptProfiles,
+}
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export, export_to = "../../frontend/types/types.ts")]
 #[serde(tag = "type")]
@@ -72,8 +234,121 @@ pub enum ServerMessage {
     GridState {
         width: usize,
         height: usize,
+        #[ts(type = "number")]
+        tick: u64,
+        // seq of the last update already included in this state
+        #[ts(type = "number")]
+        seq: u64,
+        #[serde(with = "packed_tiles")]
+        #[ts(as = "Vec<TileState>")]
         tiles: Vec<TileState>,
+        // terrain[i] is the ground underneath tiles[i]
+        #[serde(with = "runs")]
+        #[ts(as = "Vec<Terrain>")]
+        terrain: Vec<Terrain>,
+        // hash of the whole map (not just the viewport), as 16 hex digits
+        state_hash: String,
     },
     #[serde(rename = "tile_update")]
     TileUpdate { col: i32, row: i32, data: HexTile },
+    // everything that changed during one simulation tick, to be applied at once
+    #[serde(rename = "tile_batch")]
+    TileBatch {
+        #[ts(type = "number")]
+        seq: u64,
+        #[ts(type = "number")]
+        tick: u64,
+        // hash of the whole map once this batch is applied; clients holding
+        // the full map can compare it against their own to detect a desync
+        state_hash: String,
+        #[serde(with = "packed_tiles")]
+        #[ts(as = "Vec<TileState>")]
+        tiles: Vec<TileState>,
+    },
+    // the server is going away; clients should wait `reconnect_after` seconds
+    // before trying to connect again
+    #[serde(rename = "server_shutdown")]
+    ServerShutdown {
+        reason: String,
+        reconnect_after: u32,
+    },
+    // tiles and terrain of one chunk, row-major starting at
+    // (chunk_col * size, chunk_row * size); entries past the map edge are filler
+    #[serde(rename = "chunk_state")]
+    ChunkState {
+        chunk_col: u32,
+        chunk_row: u32,
+        size: u32,
+        #[serde(with = "runs")]
+        #[ts(as = "Vec<HexTile>")]
+        tiles: Vec<HexTile>,
+        #[serde(with = "runs")]
+        #[ts(as = "Vec<Terrain>")]
+        terrain: Vec<Terrain>,
+    },
+    // sent on connect and whenever an admin pauses/resumes or changes the speed
+    #[serde(rename = "sim_status")]
+    SimStatus { paused: bool, tick_interval_ms: u32 },
+    // id to assign an uploaded script by
+    #[serde(rename = "script_uploaded")]
+    ScriptUploaded { script_id: String },
+    // why an upload didn't compile or validate, with the id it would've had
+    #[serde(rename = "script_rejected")]
+    ScriptRejected { script_id: String, message: String },
+    #[serde(rename = "script_assigned")]
+    ScriptAssigned {
+        col: i32,
+        row: i32,
+        script_id: String,
+    },
+    // something a script assigned by this player logged, or why it failed;
+    // rate-limited per connection, with a warning saying how many lines were
+    // dropped once it can be sent
+    #[serde(rename = "script_log")]
+    ScriptLog {
+        col: i32,
+        row: i32,
+        #[ts(type = "number")]
+        tick: u64,
+        level: LogLevel,
+        message: String,
+    },
+    // per-tile script costs, most expensive first, with the fuel every call
+    // gets to compare them against
+    #[serde(rename = "script_profiles")]
+    ScriptProfiles {
+        #[ts(type = "number")]
+        fuel_budget: u64,
+        profiles: Vec<ScriptProfile>,
+    },
+    // who the server takes this connection for, in reply to authenticate
+    #[serde(rename = "authenticated")]
+    Authenticated { player: String, is_admin: bool },
+    // a request was understood but refused
+    #[serde(rename = "error")]
+    Error { message: String },
+}
+
+// tiles the tests keep building
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // a level 1 mine with an empty script state
+    pub(crate) fn mine(count: u32, capacity: u32, trade_value: i32) -> HexTile {
+        HexTile::Mine(MineData {
+            level: 1,
+            count,
+            capacity,
+            state: TileStore::default(),
+            trade_value,
+        })
+    }
+
+    pub(crate) fn turret() -> HexTile {
+        HexTile::Turret(TurretData {
+            level: 1,
+            state: TileStore::default(),
+        })
+    }
 }
@@ -1,5 +1,5 @@
 import * as PIXI from "pixi.js";
-import { HexTile } from "../../../types/types";
+import { HexTile, Terrain } from "../../../types/types";
 import { hexPoints, hexToPixel } from "../utils/mathUtils";
 
 const terrainColors: Record<string, number> = {
@@ -9,8 +9,17 @@ const terrainColors: Record<string, number> = {
   Turret: 0x2196f3,
 };
 
+// ground colors, only visible while nothing is built on the tile
+const groundColors: Record<string, number> = {
+  Plains: 0x4caf50,
+  Rock: 0x8d8d8d,
+  Water: 0x2f6fb5,
+  Ore: 0xc9a227,
+};
+
 export class HexagonView extends PIXI.Graphics {
   public data: HexTile;
+  public terrain: Terrain = "Plains";
 
   constructor(data: HexTile, row: number, col: number) {
     super();
@@ -31,7 +40,9 @@ export class HexagonView extends PIXI.Graphics {
   public draw() {
     let color = 0xff0000;
 
-    if (typeof this.data === "string") {
+    if (this.data === "Wild") {
+      color = groundColors[terrainName(this.terrain)];
+    } else if (typeof this.data === "string") {
       color = terrainColors[this.data as string];
     } else {
       color = terrainColors[Object.keys(this.data)[0]];
@@ -41,3 +52,7 @@ export class HexagonView extends PIXI.Graphics {
     this.poly(hexPoints).stroke({ width: 2, color: 0x2b2b2b }).fill(color);
   }
 }
+
+export function terrainName(terrain: Terrain): string {
+  return typeof terrain === "string" ? terrain : Object.keys(terrain)[0];
+}
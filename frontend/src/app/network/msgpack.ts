@@ -0,0 +1,164 @@
+// minimal msgpack decoder for server messages; the backend encodes structs as
+// maps (rmp_serde::to_vec_named), so once the packed tile lists are expanded
+// again (see codec.rs) decoded values have the exact same shape as the json
+// messages described in types.ts
+
+export function decodeMsgpack(buffer: ArrayBuffer): unknown {
+  const reader = new MsgpackReader(new DataView(buffer));
+  return unpack(reader.read() as Record<string, unknown>);
+}
+
+type Runs = Array<[number, unknown]>;
+
+// [count, value] pairs back into a plain list
+function fromRuns(runs: Runs): unknown[] {
+  return runs.flatMap(([count, value]) => Array(count).fill(value));
+}
+
+// [positions, runs] back into a list of { col, row, data }
+function fromPackedTiles([positions, runs]: [number[], Runs]) {
+  return fromRuns(runs).map((data, i) => ({
+    col: positions[2 * i],
+    row: positions[2 * i + 1],
+    data,
+  }));
+}
+
+function unpack(message: Record<string, unknown>) {
+  switch (message.type) {
+    case "grid_state":
+      message.tiles = fromPackedTiles(message.tiles as [number[], Runs]);
+      message.terrain = fromRuns(message.terrain as Runs);
+      break;
+    case "tile_batch":
+      message.tiles = fromPackedTiles(message.tiles as [number[], Runs]);
+      break;
+    case "chunk_state":
+      message.tiles = fromRuns(message.tiles as Runs);
+      message.terrain = fromRuns(message.terrain as Runs);
+      break;
+  }
+
+  return message;
+}
+
+class MsgpackReader {
+  private view: DataView;
+  private offset = 0;
+  private decoder = new TextDecoder();
+
+  constructor(view: DataView) {
+    this.view = view;
+  }
+
+  public read(): unknown {
+    const byte = this.u8();
+
+    if (byte <= 0x7f) return byte;
+    if (byte >= 0xe0) return byte - 0x100;
+    if ((byte & 0xf0) === 0x80) return this.map(byte & 0x0f);
+    if ((byte & 0xf0) === 0x90) return this.array(byte & 0x0f);
+    if ((byte & 0xe0) === 0xa0) return this.str(byte & 0x1f);
+
+    switch (byte) {
+      case 0xc0:
+        return null;
+      case 0xc2:
+        return false;
+      case 0xc3:
+        return true;
+      case 0xc4:
+        return this.bin(this.u8());
+      case 0xc5:
+        return this.bin(this.u16());
+      case 0xc6:
+        return this.bin(this.u32());
+      case 0xca:
+        return this.step(4, (o) => this.view.getFloat32(o));
+      case 0xcb:
+        return this.step(8, (o) => this.view.getFloat64(o));
+      case 0xcc:
+        return this.u8();
+      case 0xcd:
+        return this.u16();
+      case 0xce:
+        return this.u32();
+      case 0xcf:
+        return Number(this.step(8, (o) => this.view.getBigUint64(o)));
+      case 0xd0:
+        return this.step(1, (o) => this.view.getInt8(o));
+      case 0xd1:
+        return this.step(2, (o) => this.view.getInt16(o));
+      case 0xd2:
+        return this.step(4, (o) => this.view.getInt32(o));
+      case 0xd3:
+        return Number(this.step(8, (o) => this.view.getBigInt64(o)));
+      case 0xd9:
+        return this.str(this.u8());
+      case 0xda:
+        return this.str(this.u16());
+      case 0xdb:
+        return this.str(this.u32());
+      case 0xdc:
+        return this.array(this.u16());
+      case 0xdd:
+        return this.array(this.u32());
+      case 0xde:
+        return this.map(this.u16());
+      case 0xdf:
+        return this.map(this.u32());
+      default:
+        throw new Error(`unsupported msgpack type 0x${byte.toString(16)}`);
+    }
+  }
+
+  private step<T>(size: number, get: (offset: number) => T): T {
+    const value = get(this.offset);
+    this.offset += size;
+    return value;
+  }
+
+  private u8() {
+    return this.step(1, (o) => this.view.getUint8(o));
+  }
+
+  private u16() {
+    return this.step(2, (o) => this.view.getUint16(o));
+  }
+
+  private u32() {
+    return this.step(4, (o) => this.view.getUint32(o));
+  }
+
+  private str(length: number) {
+    const bytes = this.bin(length);
+    return this.decoder.decode(bytes);
+  }
+
+  private bin(length: number) {
+    const bytes = new Uint8Array(
+      this.view.buffer,
+      this.view.byteOffset + this.offset,
+      length,
+    );
+    this.offset += length;
+    return bytes;
+  }
+
+  private array(length: number) {
+    const result = [];
+    for (let i = 0; i < length; i++) {
+      result.push(this.read());
+    }
+    return result;
+  }
+
+  private map(length: number) {
+    const result: Record<string, unknown> = {};
+    for (let i = 0; i < length; i++) {
+      const key = String(this.read());
+      result[key] = this.read();
+    }
+    return result;
+  }
+}
@@ -1,21 +1,56 @@
 // socket logic for connecting to game backend
 
+import { storage } from "../../engine/utils/storage";
 import { logDebug, logError, logInfo } from "../utils/logger";
+import { decodeMsgpack } from "./msgpack";
 
 const BACKEND_URL = "ws://localhost:9001/ws";
+const DEFAULT_RECONNECT_DELAY = 3065;
+
+// msgpack by default, open the game with ?encoding=json to get readable
+// frames in the devtools
+const ENCODING =
+  new URLSearchParams(window.location.search).get("encoding") ?? "msgpack";
+
+// open the game with #admin_token=... to be allowed to pause/step the sim;
+// the fragment never leaves the browser, unlike a query parameter
+const ADMIN_TOKEN = new URLSearchParams(window.location.hash.slice(1)).get(
+  "admin_token",
+);
+
+// the server names us after this, so the same browser keeps playing as the
+// same player; made up the first time around
+const SECRET_KEY = "player_secret";
+
+function playerSecret() {
+  let secret = storage.getString(SECRET_KEY);
+
+  if (!secret) {
+    secret = crypto.randomUUID();
+    storage.setString(SECRET_KEY, secret);
+  }
+
+  return secret;
+}
 
 export type WebSocketMessageHandler = (message: any) => void;
 
 export class WebSocketManager {
   private socket?: WebSocket;
   private handler: WebSocketMessageHandler;
+  // seq of the last update we applied, used to catch up after reconnecting
+  private lastSeq?: number;
+  private reconnectDelay = DEFAULT_RECONNECT_DELAY;
 
   constructor(handler: WebSocketMessageHandler) {
     this.handler = handler;
   }
 
   public connect() {
-    this.socket = new WebSocket(BACKEND_URL);
+    const params = new URLSearchParams({ encoding: ENCODING });
+
+    this.socket = new WebSocket(`${BACKEND_URL}?${params}`);
+    this.socket.binaryType = "arraybuffer";
     this.registerEvents();
   }
 
@@ -30,13 +65,42 @@ export class WebSocketManager {
 
     this.socket.onopen = () => {
       logInfo("WebSocket connected");
-      this.socket?.send(JSON.stringify({ type: "request_grid_state" }));
+      this.reconnectDelay = DEFAULT_RECONNECT_DELAY;
+
+      // has to come before anything else
+      this.socket?.send(
+        JSON.stringify({
+          type: "authenticate",
+          secret: playerSecret(),
+          admin_token: ADMIN_TOKEN ?? undefined,
+        }),
+      );
+
+      const request =
+        this.lastSeq === undefined
+          ? { type: "request_grid_state" }
+          : { type: "resync", last_seq: this.lastSeq };
+
+      this.socket?.send(JSON.stringify(request));
     };
 
     this.socket.onmessage = (event) => {
       try {
         logDebug(`socket message: ${event}`);
-        const message = JSON.parse(event.data);
+        const message =
+          event.data instanceof ArrayBuffer
+            ? decodeMsgpack(event.data)
+            : JSON.parse(event.data);
+        if (typeof message.seq === "number") {
+          this.lastSeq = message.seq;
+        }
+
+        // the server tells us how long it'll be gone for
+        if (message.type === "server_shutdown") {
+          logInfo(`Server shutting down: ${message.reason}`);
+          this.reconnectDelay = message.reconnect_after * 1000;
+        }
+
         this.handleWebSocketMessage(message);
       } catch (error) {
         logError(`Failed to parse WebSocket message: ${error}`);
@@ -49,10 +113,14 @@ export class WebSocketManager {
 
     this.socket.onclose = () => {
       logInfo("WebSocket closed");
-      setTimeout(() => this.connect(), 3065);
+      setTimeout(() => this.connect(), this.reconnectDelay);
     };
   }
 
+  public isAdmin() {
+    return ADMIN_TOKEN !== null;
+  }
+
   public sendMessage(message: string) {
     if (this.socket && this.socket.readyState == WebSocket.OPEN) {
       this.socket.send(message);
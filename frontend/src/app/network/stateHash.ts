@@ -0,0 +1,30 @@
+// mirror of the backend's map hash (GridState::state_hash): every tile that
+// isn't Wild contributes FNV-1a 64 of `${col},${row}:${JSON.stringify(tile)}`,
+// all XORed together
+
+import { HexTile } from "../../../types/types";
+
+const FNV_OFFSET = 0xcbf29ce484222325n;
+const FNV_PRIME = 0x100000001b3n;
+const MASK = 0xffffffffffffffffn;
+
+const encoder = new TextEncoder();
+
+export function tileHash(col: number, row: number, tile: HexTile): bigint {
+  if (tile === "Wild") {
+    return 0n;
+  }
+
+  let hash = FNV_OFFSET;
+
+  for (const byte of encoder.encode(`${col},${row}:${JSON.stringify(tile)}`)) {
+    hash = ((hash ^ BigInt(byte)) * FNV_PRIME) & MASK;
+  }
+
+  return hash;
+}
+
+// same format the server sends, 16 hex digits
+export function formatHash(hash: bigint): string {
+  return hash.toString(16).padStart(16, "0");
+}
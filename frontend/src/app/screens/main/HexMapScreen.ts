@@ -1,7 +1,15 @@
 import * as PIXI from "pixi.js";
-import { HexTile } from "../../../../types/types";
+import {
+  AdminCommand,
+  HexTile,
+  ScriptProfile,
+  ScriptRole,
+  Terrain,
+  TileState,
+} from "../../../../types/types";
 import { WebSocketManager } from "../../network/socket";
-import { HexagonView } from "../../components/HexagonView";
+import { HexagonView, terrainName } from "../../components/HexagonView";
+import { formatHash, tileHash } from "../../network/stateHash";
 
 const TOOLTIP_STYLE: PIXI.TextStyleOptions = {
   fontFamily: "monospace",
@@ -12,6 +20,16 @@ const TOOLTIP_STYLE: PIXI.TextStyleOptions = {
 
 const DEFAULT_TERRAIN: HexTile = "Slime";
 
+// what the server will call an upload of `bytes` (scripts::script_id): hex
+// sha256 of them
+async function scriptId(bytes: Uint8Array): Promise<string> {
+  const digest = await crypto.subtle.digest("SHA-256", bytes);
+
+  return Array.from(new Uint8Array(digest), (b) =>
+    b.toString(16).padStart(2, "0"),
+  ).join("");
+}
+
 export class HexMapScreen extends PIXI.Container {
   private tooltip!: PIXI.Text;
   private tooltipBackground!: PIXI.Graphics;
@@ -28,6 +46,25 @@ export class HexMapScreen extends PIXI.Container {
 
   private map_width = 0;
 
+  private simPaused = false;
+
+  // the tile under the pointer, and by script id, the tiles pasted scripts
+  // get assigned to once the server accepted them
+  private hovered: { col: number; row: number } | null = null;
+  private pendingAssigns = new Map<
+    string,
+    Array<{ col: number; row: number }>
+  >();
+
+  // what scripts cost on each tile (by index), as of the last profiles we
+  // asked for, and the fuel every call gets
+  private profiles = new Map<number, ScriptProfile>();
+  private fuelBudget = 0;
+
+  // our own copy of the server's map hash, updated along with every tile
+  private stateHash = 0n;
+  private tileHashes: bigint[] = [];
+
   constructor() {
     super();
     this.interactive = true;
@@ -42,6 +79,85 @@ export class HexMapScreen extends PIXI.Container {
       this.handleWebSocketMessage.bind(this),
     );
     this.wsClient.connect();
+
+    window.addEventListener("keydown", this.onKey.bind(this));
+
+    window.addEventListener("paste", this.onPaste.bind(this));
+  }
+
+  // pasting rhai source while hovering a mine/turret uploads it and runs it
+  // there; the role is whichever entry point comes first
+  private onPaste(event: ClipboardEvent) {
+    const source = event.clipboardData?.getData("text") ?? "";
+    const entry = source.match(
+      /fn\s+(mine|logistics|defender)_tick\s*\(\s*\)/,
+    );
+
+    if (!entry || !this.hovered) {
+      return;
+    }
+
+    this.uploadScript(entry[1] as ScriptRole, source, this.hovered);
+  }
+
+  // also runs it on `assignTo` once the server accepted it
+  public async uploadScript(
+    role: ScriptRole,
+    source: string,
+    assignTo?: { col: number; row: number },
+  ) {
+    const bytes = new TextEncoder().encode(source);
+
+    // noted before sending, so the reply always finds it
+    if (assignTo) {
+      const id = await scriptId(bytes);
+      const tiles = this.pendingAssigns.get(id) ?? [];
+
+      this.pendingAssigns.set(id, [...tiles, assignTo]);
+    }
+
+    this.wsClient.sendMessage(
+      JSON.stringify({
+        type: "upload_script",
+        role,
+        language: "rhai",
+        bytes: Array.from(bytes),
+      }),
+    );
+  }
+
+  // what our scripts have cost so far, per tile
+  public requestScriptProfiles() {
+    this.wsClient.sendMessage(
+      JSON.stringify({ type: "request_script_profiles" }),
+    );
+  }
+
+  // s: our script costs, shown in the tile tooltips; everything else is for
+  // admins only
+  private onKey(event: KeyboardEvent) {
+    if (event.key === "s") {
+      this.requestScriptProfiles();
+    } else if (this.wsClient.isAdmin()) {
+      this.onAdminKey(event);
+    }
+  }
+
+  // p: pause/resume, n: step one tick, +/-: faster/slower, c: script costs
+  private onAdminKey(event: KeyboardEvent) {
+    const commands: Record<string, AdminCommand> = {
+      p: { type: this.simPaused ? "resume" : "pause" },
+      n: { type: "step", ticks: 1 },
+      "+": { type: "set_tick_rate", interval_ms: 500 },
+      "-": { type: "set_tick_rate", interval_ms: 5000 },
+      c: { type: "script_profiles" },
+    };
+
+    const command = commands[event.key];
+
+    if (command) {
+      this.wsClient.sendMessage(JSON.stringify({ type: "admin", command }));
+    }
   }
 
   private initTooltip() {
@@ -73,7 +189,13 @@ export class HexMapScreen extends PIXI.Container {
           this.map_width = message.width;
         }
 
+        this.updateTerrain(message.tiles, message.terrain);
         this.updateGridState(message.tiles);
+
+        // asking again wouldn't help, the hashing itself must disagree
+        if (!this.matchesServerHash(message.state_hash)) {
+          console.warn("map hash differs right after a full grid state");
+        }
         break;
       case "tile_update":
         this.updateTile({
@@ -82,14 +204,123 @@ export class HexMapScreen extends PIXI.Container {
           row: message.row,
         });
         break;
-      case "tiles_update":
-        message.tiles.forEach((tile: any) => this.updateTile(tile));
+      case "chunk_state":
+        this.updateChunk(message);
+        break;
+      case "tile_batch":
+        // everything from one tick, applied together
+        message.tiles.forEach((tile: TileState) => this.updateTile(tile));
+
+        if (!this.matchesServerHash(message.state_hash)) {
+          console.warn("map out of sync with the server, requesting full state");
+          this.wsClient.sendMessage(
+            JSON.stringify({ type: "request_grid_state" }),
+          );
+        }
+        break;
+      case "sim_status":
+        this.simPaused = message.paused;
+        console.log(
+          `simulation ${message.paused ? "paused" : "running"}, ${message.tick_interval_ms}ms per tick`,
+        );
+        break;
+      case "server_shutdown":
+        // reconnecting is handled by the socket
+        break;
+      case "script_uploaded":
+        console.log("script uploaded:", message.script_id);
+
+        this.pendingAssigns.get(message.script_id)?.forEach((tile) =>
+          this.wsClient.sendMessage(
+            JSON.stringify({
+              type: "assign_script",
+              ...tile,
+              script_id: message.script_id,
+            }),
+          ),
+        );
+        this.pendingAssigns.delete(message.script_id);
+        break;
+      case "script_rejected":
+        console.warn("script rejected:", message.message);
+        this.pendingAssigns.delete(message.script_id);
+        break;
+      case "script_assigned":
+        console.log(
+          `script ${message.script_id} now runs on <${message.col}, ${message.row}>`,
+        );
+        break;
+      case "script_log": {
+        const line = `[tick ${message.tick}] <${message.col}, ${message.row}> ${message.message}`;
+
+        if (message.level === "error" || message.level === "warn") {
+          console.warn(line);
+        } else {
+          console.log(line);
+        }
+        break;
+      }
+      case "script_profiles":
+        this.fuelBudget = message.fuel_budget;
+        this.profiles = new Map(
+          message.profiles.map((profile: ScriptProfile) => [
+            this.map_width * profile.row + profile.col,
+            profile,
+          ]),
+        );
+
+        if (this.hovered) {
+          this.updateTooltipText(this.hovered.col, this.hovered.row);
+        }
+        break;
+      case "error":
+        console.warn("Server refused request:", message.message);
         break;
       default:
         console.warn("Unknown message type:", message.type);
     }
   }
 
+  // terrain[i] is the ground underneath tiles[i]
+  private updateTerrain(
+    tiles: Array<{ col: number; row: number }>,
+    terrain: Array<Terrain>,
+  ) {
+    tiles.forEach(({ col, row }, i) => {
+      const hex = this.hexes[this.map_width * row + col];
+
+      if (hex) {
+        hex.terrain = terrain[i];
+      }
+    });
+  }
+
+  // chunks are row-major and may run past the edge of the map
+  private updateChunk(chunk: {
+    chunk_col: number;
+    chunk_row: number;
+    size: number;
+    tiles: Array<HexTile>;
+    terrain: Array<Terrain>;
+  }) {
+    chunk.tiles.forEach((data, i) => {
+      const col = chunk.chunk_col * chunk.size + (i % chunk.size);
+      const row = chunk.chunk_row * chunk.size + Math.floor(i / chunk.size);
+
+      if (col >= this.map_width) {
+        return;
+      }
+
+      const hex = this.hexes[this.map_width * row + col];
+
+      if (hex) {
+        hex.terrain = chunk.terrain[i];
+      }
+
+      this.updateTile({ col, row, data });
+    });
+  }
+
   private updateGridState(
     tiles: Array<{ col: number; row: number; data: Partial<HexTile> }>,
   ) {
@@ -113,6 +344,10 @@ export class HexMapScreen extends PIXI.Container {
     const hex = this.hexes[index];
 
     if (hex) {
+      const hash = tileHash(col, row, data as HexTile);
+      this.stateHash ^= (this.tileHashes[index] ?? 0n) ^ hash;
+      this.tileHashes[index] = hash;
+
       // rust type gen compatibility is quite terrible, it can be either string or object for enum
       // also js strings are primitives, not objects? :(
       if (typeof data === "string") {
@@ -129,6 +364,11 @@ export class HexMapScreen extends PIXI.Container {
     console.log("done");
   }
 
+  // only meaningful while we hold the whole map, i.e. without a viewport
+  private matchesServerHash(serverHash: string) {
+    return formatHash(this.stateHash) === serverHash;
+  }
+
   public sendTileUpdate(col: number, row: number, data: Partial<HexTile>) {
     this.wsClient.sendMessage(
       JSON.stringify({
@@ -165,15 +405,42 @@ export class HexMapScreen extends PIXI.Container {
     this.mapContainer.y = this.mapStart.y + dy;
   }
 
+  // the hovered tile, plus what its script costs if we've asked (with s)
+  private updateTooltipText(col: number, row: number) {
+    const index = this.map_width * row + col;
+    const hex = this.hexes[index];
+    const profile = this.profiles.get(index);
+
+    let text = `x: ${col}, y: ${row}, tile: ${hex.data}, ground: ${terrainName(hex.terrain)}`;
+
+    if (profile) {
+      const avgFuel = Math.round(profile.total_fuel / profile.ticks);
+      const avgUs = Math.round(profile.total_wall_us / profile.ticks);
+
+      text += `\nscript: ${profile.script_id.slice(0, 8)} (${profile.owner})`;
+      text += `\nfuel: ${avgFuel} avg, ${profile.peak_fuel} peak / ${this.fuelBudget}`;
+      text += `\ntime: ${avgUs}us avg, ${profile.peak_wall_us}us peak`;
+
+      if (profile.peak_memory !== null) {
+        text += `\nmemory: ${profile.peak_memory} bytes`;
+      }
+    }
+
+    this.tooltip.text = text;
+  }
+
   private registerHexEvent(g: HexagonView, row: number, col: number) {
     g.on("pointerout", () => {
+      this.hovered = null;
       this.tooltip.visible = false;
       this.tooltipBackground.visible = false;
     });
 
     g.on("pointerover", (_) => {
+      this.hovered = { col, row };
+
       if (!this.isDragging) {
-        this.tooltip.text = `x: ${col}, y: ${row}, terrain: ${g.data}`;
+        this.updateTooltipText(col, row);
         this.tooltip.visible = true;
         this.tooltipBackground.visible = true;
       }
@@ -1,8 +1,45 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
+export type AdminCommand =
+  | { type: "pause" }
+  | { type: "resume" }
+  | { type: "step"; ticks: number }
+  | { type: "set_tick_rate"; interval_ms: number }
+  | { type: "undo"; player: string; count: number }
+  | { type: "redo"; player: string; count: number }
+  | {
+      type: "revert_region";
+      min_col: number;
+      min_row: number;
+      max_col: number;
+      max_row: number;
+      tick: number;
+    }
+  | { type: "script_profiles" };
+
 export type ClientMessage =
+  | { type: "authenticate"; admin_token?: string; secret?: string }
   | { type: "request_grid_state" }
   | { type: "tile_update"; col: number; row: number; data: HexTile }
+  | {
+      type: "set_viewport";
+      min_col: number;
+      min_row: number;
+      max_col: number;
+      max_row: number;
+    }
+  | { type: "resync"; last_seq: number }
+  | { type: "request_chunk"; chunk_col: number; chunk_row: number }
+  | { type: "admin"; command: AdminCommand }
+  | {
+      type: "upload_script";
+      role: ScriptRole;
+      bytes: Array<number>;
+      language?: ScriptLanguage;
+      replaces?: string;
+    }
+  | { type: "assign_script"; col: number; row: number; script_id: string }
+  | { type: "request_script_profiles" }
   | { type: "None" };
 
 export type HexTile =
@@ -11,23 +48,95 @@ export type HexTile =
   | { Turret: TurretData }
   | "Slime";
 
+export type LogLevel = "debug" | "info" | "warn" | "error";
+
 export type MineData = {
   level: number;
   count: number;
   capacity: number;
-  state: string;
+  state: TileStore;
   trade_value: number;
 };
 
+export type ScriptLanguage = "wasm" | "rhai";
+
+export type ScriptProfile = {
+  col: number;
+  row: number;
+  script_id: string;
+  owner: string;
+  ticks: number;
+  last_tick: number;
+  last_fuel: number;
+  total_fuel: number;
+  peak_fuel: number;
+  last_wall_us: number;
+  total_wall_us: number;
+  peak_wall_us: number;
+  peak_memory: number | null;
+};
+
+export type ScriptRole = "mine" | "defender" | "logistics";
+
 export type ServerMessage =
   | {
       type: "grid_state";
       width: number;
       height: number;
+      tick: number;
+      seq: number;
       tiles: Array<TileState>;
+      terrain: Array<Terrain>;
+      state_hash: string;
     }
-  | { type: "tile_update"; col: number; row: number; data: HexTile };
+  | { type: "tile_update"; col: number; row: number; data: HexTile }
+  | {
+      type: "tile_batch";
+      seq: number;
+      tick: number;
+      state_hash: string;
+      tiles: Array<TileState>;
+    }
+  | { type: "server_shutdown"; reason: string; reconnect_after: number }
+  | {
+      type: "chunk_state";
+      chunk_col: number;
+      chunk_row: number;
+      size: number;
+      tiles: Array<HexTile>;
+      terrain: Array<Terrain>;
+    }
+  | { type: "sim_status"; paused: boolean; tick_interval_ms: number }
+  | { type: "script_uploaded"; script_id: string }
+  | { type: "script_rejected"; script_id: string; message: string }
+  | { type: "script_assigned"; col: number; row: number; script_id: string }
+  | {
+      type: "script_log";
+      col: number;
+      row: number;
+      tick: number;
+      level: LogLevel;
+      message: string;
+    }
+  | {
+      type: "script_profiles";
+      fuel_budget: number;
+      profiles: Array<ScriptProfile>;
+    }
+  | { type: "authenticated"; player: string; is_admin: boolean }
+  | { type: "error"; message: string };
+
+export type Terrain =
+  | "Plains"
+  | "Rock"
+  | "Water"
+  | { Ore: { richness: number } };
 
 export type TileState = { col: number; row: number; data: HexTile };
 
-export type TurretData = { level: number; state: string };
+export type TileStore = {
+  version: number;
+  entries: { [key in string]?: string };
+};
+
+export type TurretData = { level: number; state: TileStore };